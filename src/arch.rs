@@ -0,0 +1,143 @@
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+
+/// A target architecture.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    ValueEnum,
+    Serialize,
+    Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Arch {
+    /// 64-bit x86.
+    Amd64,
+    /// 32-bit x86.
+    I386,
+    /// 64-bit ARM.
+    Aarch64,
+    /// 64-bit SPARC.
+    Sparcv9,
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arch::Amd64 => write!(f, "amd64"),
+            Arch::I386 => write!(f, "i386"),
+            Arch::Aarch64 => write!(f, "aarch64"),
+            Arch::Sparcv9 => write!(f, "sparcv9"),
+        }
+    }
+}
+
+impl Arch {
+    /// Machine specific compiler flags used when compiling kernel objects.
+    pub fn kernel_cflags(&self) -> Vec<&'static str> {
+        match self {
+            Arch::Amd64 => vec![
+                "-m64",
+                "-mcmodel=kernel",
+                "-mindirect-branch-register",
+                "-mindirect-branch=thunk-extern",
+                "-mno-mmx",
+                "-mno-red-zone",
+                "-mno-sse",
+                "-msave-args",
+                "-U__i386",
+                "-Ui386",
+            ],
+            Arch::I386 => vec![
+                "-m32",
+                "-march=pentiumpro",
+                "-mno-mmx",
+                "-mno-sse",
+                "-U__amd64",
+            ],
+            Arch::Aarch64 => vec![
+                "-march=armv8-a",
+                "-mbranch-protection=none",
+                "-mcmodel=large",
+                "-mgeneral-regs-only",
+                "-mno-outline-atomics",
+                "-mstrict-align",
+            ],
+            Arch::Sparcv9 => vec![
+                "-m64",
+                "-mcpu=v9",
+                "-mcmodel=medlow",
+                "-mno-fpu",
+                "-fno-pic",
+                "-ffixed-g3",
+                "-ffixed-g4",
+                "-ffixed-g7",
+            ],
+        }
+    }
+
+    /// Machine specific preprocessor definitions used when compiling kernel
+    /// objects.
+    pub fn kernel_defines(&self) -> Vec<&'static str> {
+        match self {
+            Arch::Amd64 => {
+                vec!["-D_ELF64", "-D_SYSCALL32", "-D_SYSCALL32_IMPL"]
+            }
+            Arch::I386 => vec![],
+            Arch::Aarch64 => vec!["-D_ELF64"],
+            Arch::Sparcv9 => {
+                vec!["-D_ELF64", "-D_SYSCALL32", "-D_SYSCALL32_IMPL"]
+            }
+        }
+    }
+
+    /// The directory, relative to the source root, of the headers shared by
+    /// all platforms of this architecture's instruction set. These take
+    /// precedence over the common kernel headers.
+    pub fn isa_dir(&self) -> &'static str {
+        match self {
+            Arch::Amd64 | Arch::I386 => "uts/intel",
+            Arch::Aarch64 => "uts/aarch64",
+            Arch::Sparcv9 => "uts/sparc",
+        }
+    }
+
+    /// The subdirectory of each kernel directory that modules built for this
+    /// architecture are installed in, e.g. `kernel/drv/amd64`. 32-bit modules
+    /// are installed in the kernel directory itself.
+    pub fn kernel_subdir(&self) -> Option<&'static str> {
+        match self {
+            Arch::Amd64 => Some("amd64"),
+            Arch::I386 => None,
+            Arch::Aarch64 => Some("aarch64"),
+            Arch::Sparcv9 => Some("sparcv9"),
+        }
+    }
+
+    /// The directory, relative to the source root, of the headers of this
+    /// architecture's platform.
+    pub fn platform_dir(&self) -> &'static str {
+        match self {
+            Arch::Amd64 | Arch::I386 => "uts/i86pc",
+            Arch::Aarch64 => "uts/armv8",
+            Arch::Sparcv9 => "uts/sun4u",
+        }
+    }
+
+    /// The emulation lld links objects for this architecture with.
+    pub fn lld_emulation(&self) -> &'static str {
+        match self {
+            Arch::Amd64 => "elf_x86_64",
+            Arch::I386 => "elf_i386",
+            Arch::Aarch64 => "aarch64elf",
+            Arch::Sparcv9 => "elf64_sparc",
+        }
+    }
+}
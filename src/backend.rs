@@ -0,0 +1,36 @@
+use crate::error::Result;
+use crate::util;
+use std::io::Write;
+use std::path::Path;
+use tracing::info;
+
+/// An output format for a build specification, e.g. a ninja build file.
+pub trait Backend {
+    /// Write this build specification to `w`.
+    fn emit(&self, w: &mut dyn Write) -> Result<()>;
+}
+
+/// Emit `backend` to the file at `path`, leaving the file untouched if its
+/// content would not change. A path of `-` writes to stdout instead.
+pub fn emit_file(backend: &dyn Backend, path: &Path) -> Result<()> {
+    write(path, &render(backend)?)
+}
+
+/// Emit `backend` to memory.
+pub fn render(backend: &dyn Backend) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    backend.emit(&mut out)?;
+    Ok(out)
+}
+
+/// Write `data` to the file at `path`, leaving the file untouched if its
+/// content would not change. A path of `-` writes to stdout instead.
+pub fn write(path: &Path, data: &[u8]) -> Result<()> {
+    if path == Path::new("-") {
+        Ok(std::io::stdout().write_all(data)?)
+    } else {
+        util::write_if_changed(path, data)?;
+        info!("wrote {}", path.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use crate::error::Result;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// How many differing lines of each kind a summary shows.
+const SHOWN: usize = 10;
+
+/// Compare the file at `path` to what eos would write there, `expected`.
+/// Returns a summary of the differences if there are any. The header eos
+/// writes is ignored as it records how eos was invoked.
+pub fn compare(path: &Path, expected: &[u8]) -> Result<Option<String>> {
+    let actual = match std::fs::read(path) {
+        Ok(actual) => actual,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Ok(Some(format!("{}: missing", path.display())))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let actual = String::from_utf8_lossy(&actual);
+    let expected = String::from_utf8_lossy(expected);
+    let actual = content(&actual);
+    let expected = content(&expected);
+    if actual == expected {
+        return Ok(None);
+    }
+
+    let removed = difference(&actual, &expected);
+    let added = difference(&expected, &actual);
+    let mut summary = format!(
+        "{}: out of date, {} lines added and {} removed",
+        path.display(),
+        added.len(),
+        removed.len()
+    );
+    for line in removed.iter().take(SHOWN) {
+        summary.push_str(&format!("\n  - {}", line));
+    }
+    for line in added.iter().take(SHOWN) {
+        summary.push_str(&format!("\n  + {}", line));
+    }
+    if removed.len() > SHOWN || added.len() > SHOWN {
+        summary.push_str("\n  ...");
+    }
+    if removed.is_empty() && added.is_empty() {
+        summary.push_str("\n  (lines reordered)");
+    }
+    Ok(Some(summary))
+}
+
+/// The lines of a generated file, without the header.
+fn content(text: &str) -> Vec<&str> {
+    text.lines()
+        .enumerate()
+        .filter(|(i, line)| {
+            *i >= 2
+                || !(line.starts_with("# This file was generated by eos ")
+                    || line.starts_with("# invocation: "))
+        })
+        .map(|(_, line)| line)
+        .collect()
+}
+
+/// The lines of `a` that are not in `b`, counting repeated lines.
+pub fn difference<'a>(a: &[&'a str], b: &[&str]) -> Vec<&'a str> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for line in b {
+        *counts.entry(line).or_default() += 1;
+    }
+    let mut result = Vec::new();
+    for line in a {
+        match counts.get_mut(line) {
+            Some(n) if *n > 0 => *n -= 1,
+            _ => result.push(*line),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difference_counts_repeated_lines() {
+        assert_eq!(difference(&["a", "b", "a", "c"], &["a", "c"]), ["b", "a"]);
+        assert_eq!(difference(&["a"], &["a", "a"]), Vec::<&str>::new());
+    }
+}
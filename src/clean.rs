@@ -0,0 +1,57 @@
+use crate::error::Result;
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// Ninja's own state files, kept next to the build file.
+const NINJA_STATE: &[&str] = &[".ninja_log", ".ninja_deps"];
+
+/// The files and directories in the current directory that a build with eos
+/// produces: the output directories `bld`, the proto areas under `proto`,
+/// ninja's state and any build files eos generated. Build files are recognized
+/// by the header eos writes, so hand written ones with the same names are left
+/// alone.
+pub fn outputs(bld: &[PathBuf], proto: &Path) -> Result<Vec<PathBuf>> {
+    let mut result = Vec::new();
+    for dir in bld.iter().map(|x| x.as_path()).chain([proto]) {
+        if dir.exists() {
+            result.push(dir.to_owned());
+        }
+    }
+    for name in NINJA_STATE {
+        if Path::new(name).exists() {
+            result.push(name.into());
+        }
+    }
+
+    let mut generated = Vec::new();
+    for entry in std::fs::read_dir(".")? {
+        let path = PathBuf::from(entry?.file_name());
+        let name = path.to_string_lossy();
+        let build_file = name == "Makefile"
+            || (name.starts_with("build") && name.ends_with(".ninja"));
+        if build_file && path.is_file() && generated_by_eos(&path)? {
+            generated.push(path);
+        }
+    }
+    generated.sort();
+    result.extend(generated);
+    Ok(result)
+}
+
+/// Whether the file at `path` starts with the header eos puts at the top of
+/// generated files.
+fn generated_by_eos(path: &Path) -> Result<bool> {
+    let mut line = String::new();
+    BufReader::new(std::fs::File::open(path)?).read_line(&mut line)?;
+    Ok(line.starts_with("# This file was generated by eos "))
+}
+
+/// Remove `path`, whether it is a file or a directory.
+pub fn remove(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)?;
+    } else {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,590 @@
+use crate::arch::Arch;
+use crate::deps::Format;
+use crate::scan::HeaderDeps;
+use crate::util;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::{Path, PathBuf};
+
+/// Eos locates the build.toml files in an illumos source tree and generates a
+/// build specification for the whole tree from them. Without a command it
+/// generates build.ninja.
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Architectures to generate build statements for. When more than one is
+    /// given, each gets its own ninja file and output directory under `bld/`,
+    /// and the output file includes them all. Defaults to `arches` from
+    /// eos.toml, or amd64.
+    #[arg(long = "arch", global = true, value_enum)]
+    pub arches: Vec<Arch>,
+
+    /// Log more about what eos is doing. Give twice for even more.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Log less, only warnings and errors. Give twice for errors only.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    pub quiet: u8,
+
+    /// How to write errors and warnings. JSON diagnostics are written to
+    /// stderr one per line, with logging off unless asked for.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub message_format: MessageFormat,
+
+    /// When to color errors, warnings and log lines. `auto` colors them
+    /// when stderr is a terminal, unless NO_COLOR is set.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub color: ColorChoice,
+
+    /// The source tree to build, i.e. `usr/src` of an illumos checkout.
+    /// Defaults to `source_root` from eos.toml, or `usr/src`.
+    #[arg(long, global = true)]
+    pub source_root: Option<PathBuf>,
+
+    /// The directory build outputs are placed under. Defaults to
+    /// `output_dir` from eos.toml, or `bld`.
+    #[arg(long, global = true)]
+    pub output_dir: Option<PathBuf>,
+
+    /// The directory the proto areas `eos install` populates are placed
+    /// under. Defaults to `proto_dir` from eos.toml, or `proto`.
+    #[arg(long, global = true)]
+    pub proto_dir: Option<PathBuf>,
+
+    /// The C compiler to build with and to scan sources for headers with.
+    /// Defaults to `toolchain.cc` from eos.toml, or gcc-10.
+    #[arg(long, global = true)]
+    pub compiler: Option<String>,
+
+    /// The profile whose flags to add to the built-in ones: `debug`,
+    /// `release` or one from eos.toml. Builds with a profile other than
+    /// `release` go in a directory of its name under the output directory.
+    /// Defaults to `profile` from eos.toml.
+    #[arg(long, global = true)]
+    pub profile: Option<String>,
+
+    /// Build a DEBUG kernel, the same as `--profile debug`: compiled with
+    /// `-O0 -DDEBUG`, keeping symbols, in `debug` under the output
+    /// directory.
+    #[arg(long, global = true, conflicts_with = "profile")]
+    pub debug: bool,
+
+    /// Build a kernel that collects coverage data, the same as `--profile
+    /// coverage`: compiled with `--coverage`, in `coverage` under the output
+    /// directory. `eos test` writes an lcov report after the tests run.
+    #[arg(
+        long,
+        global = true,
+        conflicts_with_all = ["profile", "debug"]
+    )]
+    pub coverage: bool,
+
+    /// Leave out CTF data, and stripping, for faster rebuilds. Defaults to
+    /// `ctf.enabled` from eos.toml, or the profile.
+    #[arg(long, global = true)]
+    pub no_ctf: bool,
+
+    /// Build objects that are bit-identical from one build of the same
+    /// sources to the next. Defaults to `reproducible` from eos.toml.
+    #[arg(long, global = true)]
+    pub reproducible: bool,
+
+    /// Treat compiler warnings as errors, except in the modules and sources
+    /// build specs exempt. Defaults to `werror` from eos.toml.
+    #[arg(long, global = true)]
+    pub werror: bool,
+
+    /// Check that modules only reference symbols genunix and the modules
+    /// they depend on define, failing the build otherwise. Defaults to
+    /// `check_symbols` from eos.toml.
+    #[arg(long, global = true)]
+    pub check_symbols: bool,
+
+    /// Which headers objects depend on: only those in the workspace, or all
+    /// of them, including the system's. Defaults to `header_deps` from
+    /// eos.toml, or `workspace`.
+    #[arg(long, global = true, value_name = "HEADERS")]
+    pub header_deps: Option<HeaderDeps>,
+
+    /// Fail if a directory under the source tree cannot be read, instead of
+    /// skipping it with a warning. Defaults to `walk.strict` from eos.toml.
+    #[arg(long, global = true)]
+    pub strict_walk: bool,
+
+    /// Follow symbolic links in the source tree when searching it for build
+    /// specs, instead of skipping them. Defaults to `walk.follow_symlinks`
+    /// from eos.toml.
+    #[arg(long, global = true)]
+    pub follow_symlinks: bool,
+
+    /// Only generate build statements for these modules, and the modules they
+    /// depend on.
+    #[arg(long, global = true, value_delimiter = ',')]
+    pub only: Vec<String>,
+
+    /// Only generate build statements for the build specs under this
+    /// directory, and the modules they depend on.
+    #[arg(long = "subtree", global = true)]
+    pub subtrees: Vec<PathBuf>,
+
+    /// Set a top level ninja variable, e.g. `kernel_cflags='$kernel_cflags
+    /// -DDEBUG'`. Definitions come after the built-in variables, so they
+    /// override them.
+    #[arg(
+        long = "define",
+        global = true,
+        value_name = "NAME=VALUE",
+        value_parser = parse_define
+    )]
+    pub defines: Vec<(String, String)>,
+
+    /// How many compilers to run at once when scanning sources for the
+    /// headers they include. Defaults to the number of CPUs.
+    #[arg(long, global = true, value_name = "N")]
+    pub scan_jobs: Option<std::num::NonZeroUsize>,
+}
+
+impl Args {
+    /// Make the relative paths given on the command line, which are relative
+    /// to `dir` in the workspace, relative to the root of the workspace
+    /// instead.
+    pub fn rebase(&mut self, dir: &Path) {
+        let rebase = |x: &mut PathBuf| {
+            if x != Path::new("-") {
+                *x = util::rebase(dir, x);
+            }
+        };
+        for x in [
+            &mut self.source_root,
+            &mut self.output_dir,
+            &mut self.proto_dir,
+        ] {
+            x.iter_mut().for_each(rebase);
+        }
+        self.subtrees.iter_mut().for_each(rebase);
+        match &mut self.command {
+            Some(Command::Generate(x)) | Some(Command::Check(x)) => {
+                x.output.iter_mut().for_each(rebase)
+            }
+            Some(Command::Diff { output })
+            | Some(Command::Compdb { output }) => {
+                output.iter_mut().for_each(rebase)
+            }
+            Some(Command::Package {
+                publish: Some(repo),
+                ..
+            }) if !repo.contains("://") => {
+                *repo = dir.join(&*repo).to_string_lossy().into_owned()
+            }
+            Some(Command::Fmt { paths, .. })
+            | Some(Command::UpgradeSpec { paths, .. }) => {
+                paths.iter_mut().for_each(rebase)
+            }
+            Some(Command::Graph { output, .. }) => rebase(output),
+            Some(Command::Migrate {
+                makefile,
+                fragments,
+                rules,
+                output,
+                ..
+            }) => {
+                rebase(makefile);
+                fragments.iter_mut().chain(rules).for_each(rebase);
+                output.iter_mut().for_each(rebase);
+            }
+            Some(Command::New {
+                kind: NewKind::Module { dir: x, .. },
+            }) => rebase(x),
+            _ => (),
+        }
+    }
+}
+
+/// Parse a `--define` argument.
+fn parse_define(s: &str) -> Result<(String, String), String> {
+    let Some((name, value)) = s.split_once('=') else {
+        return Err("expected NAME=VALUE".into());
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || "_-.".contains(c);
+    if name.is_empty() || !name.chars().all(valid) {
+        return Err(format!("`{}` is not a valid ninja variable name", name));
+    }
+    Ok((name.into(), value.into()))
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Generate the build file for the tree.
+    Generate(GenerateArgs),
+    /// Check that the generated build file is up to date, without writing
+    /// anything. Exits with an error if it is not.
+    Check(GenerateArgs),
+    /// Show how the generated build file would change, by build statement,
+    /// rule and variable, grouped by the build spec they come from.
+    Diff {
+        /// The build file to compare with. Defaults to build.ninja at the
+        /// root of the workspace.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Regenerate build.ninja if it is out of date and run ninja.
+    Build(BuildArgs),
+    /// Regenerate build.ninja if it is out of date and run ninja to install
+    /// built modules, genunix, conf files and headers into the proto area,
+    /// `proto/root_<arch>`.
+    Install(BuildArgs),
+    /// Build the outputs the tests of the build specs need and run the
+    /// tests, failing if any of them fail.
+    Test(TestArgs),
+    /// Write an IPS package manifest for each package the build specs
+    /// describe, to `pkg` in the output directory, and optionally publish
+    /// the installed packages to a repository.
+    Package {
+        /// The version of the packages.
+        #[arg(long, default_value = "0.5.11")]
+        version: String,
+        /// Publish the packages to this repository with pkgsend, creating it
+        /// with pkgrepo if it is a directory that does not exist. The files
+        /// must have been installed with `eos install` first.
+        #[arg(long)]
+        publish: Option<String>,
+        /// The publisher of a repository created for `--publish`.
+        #[arg(long, default_value = "on-nightly")]
+        publisher: String,
+    },
+    /// List the build specs in the tree.
+    List {
+        /// Write the list as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check that the compiler, link editor, CTF tools, strip and ninja can
+    /// be run and are recent enough, saying how to fix any that are not.
+    Doctor {
+        /// The ninja program to check.
+        #[arg(long, default_value = "ninja")]
+        ninja: String,
+    },
+    /// Show the configuration in effect, after merging eos.toml and the
+    /// command line.
+    Env {
+        /// Write the configuration as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Rewrite build specs in the canonical style, with tables and keys in a
+    /// fixed order and source lists sorted.
+    Fmt {
+        /// Build specs, or directories to format the build specs under.
+        /// Defaults to the whole tree.
+        paths: Vec<PathBuf>,
+        /// Only check that the build specs are formatted, listing those that
+        /// are not.
+        #[arg(long)]
+        check: bool,
+    },
+    /// Rewrite build specs written for an older version of the build spec
+    /// format in the current one.
+    UpgradeSpec {
+        /// Build specs, or directories to upgrade the build specs under.
+        /// Defaults to the whole tree.
+        paths: Vec<PathBuf>,
+        /// Only check that the build specs are the current version, listing
+        /// those that are not.
+        #[arg(long)]
+        check: bool,
+    },
+    /// Show statistics about the build specs and the build generated from
+    /// them, such as the number of edges and the largest targets.
+    Stats {
+        /// How many of the largest targets to show.
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+        /// Write the statistics as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show which build spec and rule produce a file, from which inputs, and
+    /// what needs it.
+    Why {
+        /// An output or source, as named in the build file.
+        path: String,
+    },
+    /// Show the compile command of a source, or the link command of a
+    /// module, with where each flag comes from: the kernel, the
+    /// architecture, the profile, the build spec or `--define`.
+    ExplainFlags {
+        /// A source, as named in the build file, or the name of a module.
+        path: String,
+    },
+    /// Write a graph of the dependencies between modules.
+    Graph {
+        /// The graph language to write.
+        #[arg(long, value_enum, default_value_t = Format::Dot)]
+        format: Format,
+        /// Where to write the graph, or `-` for stdout.
+        #[arg(short, long, default_value = "-")]
+        output: PathBuf,
+    },
+    /// Show the modules a module needs, directly or through other modules,
+    /// and genunix.
+    Deps {
+        /// The name of the module.
+        module: String,
+        /// Also show the headers the objects of the module are compiled
+        /// from. This scans sources for their headers.
+        #[arg(long)]
+        headers: bool,
+        /// Write the dependencies as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// Check the build specs in the tree for mistakes without generating
+    /// anything. Exits with an error if any are found.
+    Lint,
+    /// Create a build spec for something new.
+    New {
+        #[command(subcommand)]
+        kind: NewKind,
+    },
+    /// Convert an illumos module Makefile to a build spec, warning about
+    /// anything that could not be translated.
+    Migrate {
+        /// The module's Makefile, e.g. `usr/src/uts/intel/foo/Makefile`.
+        makefile: PathBuf,
+        /// A Makefile.files fragment defining the module's objects. Defaults
+        /// to those of uts/common and the module's parent directory.
+        #[arg(long = "fragment")]
+        fragments: Vec<PathBuf>,
+        /// A Makefile.rules giving where objects are built from. Defaults to
+        /// those of uts/common and the module's parent directory.
+        #[arg(long)]
+        rules: Vec<PathBuf>,
+        /// Where to write the build spec, or `-` for stdout. Defaults to
+        /// build.toml in the directory of the module's sources.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        /// Replace an existing build spec.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Regenerate build.ninja whenever a build spec changes.
+    Watch(WatchArgs),
+    /// Remove build outputs, proto areas, ninja's state and the build files
+    /// eos generated.
+    Clean {
+        /// Only print what would be removed.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Do not ask before cleaning a directory that does not look like an
+        /// eos workspace.
+        #[arg(short, long)]
+        yes: bool,
+    },
+    /// Write a shell completion script to stdout. Run in a workspace, the
+    /// names of its modules are offered for `--only`.
+    Completions {
+        /// The shell to write the script for.
+        shell: clap_complete::Shell,
+    },
+    /// Write a compile_commands.json for clang tooling.
+    Compdb {
+        /// Where to write the compilation database. Defaults to
+        /// compile_commands.json at the root of the workspace.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write the build graph to stdout for consumption by other tools.
+    Dump {
+        /// The format to write the graph in.
+        #[arg(long, value_enum, default_value_t = GraphFormat::Json)]
+        graph: GraphFormat,
+    },
+    /// Write a dyndep file with the header dependencies of objects. This is
+    /// run by the build for objects whose headers are generated.
+    #[command(hide = true)]
+    Dyndep {
+        /// Where to write the dyndep file.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// An object and its source, as `object=source`.
+        #[arg(long = "object")]
+        objects: Vec<String>,
+        /// A directory outside the workspace whose headers are part of the
+        /// build.
+        #[arg(long = "root")]
+        roots: Vec<PathBuf>,
+        /// Flags the objects are compiled with.
+        #[arg(last = true)]
+        cflags: Vec<String>,
+    },
+    /// Check that a module only references symbols genunix and the modules
+    /// it depends on define, and if so write an empty file recording that
+    /// it does. This is run by the build with `--check-symbols`.
+    #[command(hide = true)]
+    CheckSymbols {
+        /// The program that lists symbols.
+        #[arg(long, default_value = "nm")]
+        nm: String,
+        /// Where to write the file recording the module passed.
+        #[arg(short, long)]
+        output: PathBuf,
+        /// The module to check.
+        module: PathBuf,
+        /// genunix and the modules the module depends on.
+        providers: Vec<PathBuf>,
+    },
+}
+
+/// Options for watching the tree.
+#[derive(clap::Args, Debug)]
+pub struct WatchArgs {
+    /// Also regenerate when the sources named by build specs change, as their
+    /// header dependencies may have.
+    #[arg(long)]
+    pub sources: bool,
+
+    /// Run ninja after each regeneration.
+    #[arg(long)]
+    pub build: bool,
+
+    /// How long to wait for more changes after one, in milliseconds, or how
+    /// often to check for them where the system cannot say when files change.
+    #[arg(long, default_value_t = 500)]
+    pub interval: u64,
+
+    /// How many jobs ninja runs in parallel. Defaults to the number of CPUs.
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// The ninja program to run.
+    #[arg(long, default_value = "ninja")]
+    pub ninja: String,
+}
+
+/// The kinds of build spec `eos new` can create.
+#[derive(Subcommand, Debug)]
+pub enum NewKind {
+    /// Create a build spec for a kernel module from the c sources in a
+    /// directory.
+    Module {
+        /// The directory of the module.
+        dir: PathBuf,
+        /// The name of the module. Defaults to the name of the directory.
+        #[arg(long)]
+        name: Option<String>,
+        /// A module this module depends on, e.g. `misc/mac`.
+        #[arg(long = "dependency")]
+        dependencies: Vec<String>,
+        /// Create a stub source file if the directory has no c sources.
+        #[arg(long)]
+        stub: bool,
+        /// Replace an existing build spec.
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+/// Options for generating a build file.
+#[derive(clap::Args, Debug, Default)]
+pub struct GenerateArgs {
+    /// Where to write the generated build file, or `-` for stdout. Defaults to
+    /// build.ninja for the ninja backend and Makefile for the make backend.
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// The kind of build file to generate.
+    #[arg(short, long, value_enum, default_value_t)]
+    pub backend: BackendKind,
+
+    /// Also write a compile_commands.json for clang tooling next to the
+    /// generated build file.
+    #[arg(long)]
+    pub compdb: bool,
+}
+
+/// Options for building the tree with ninja.
+#[derive(clap::Args, Debug)]
+pub struct BuildArgs {
+    /// Targets to build. Defaults to everything.
+    pub targets: Vec<String>,
+
+    /// How many jobs to run in parallel. Defaults to the number of CPUs.
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// Regenerate build.ninja even if it is up to date.
+    #[arg(long)]
+    pub regenerate: bool,
+
+    /// The ninja program to run.
+    #[arg(long, default_value = "ninja")]
+    pub ninja: String,
+
+    /// Extra arguments passed to ninja as is.
+    #[arg(last = true)]
+    pub ninja_args: Vec<String>,
+}
+
+/// Options for running tests.
+#[derive(clap::Args, Debug)]
+pub struct TestArgs {
+    /// Only run tests whose names contain one of these.
+    pub filters: Vec<String>,
+
+    /// Write a JSON report of the results to stdout instead of text.
+    #[arg(long)]
+    pub json: bool,
+
+    /// How many jobs to build with in parallel. Defaults to the number of
+    /// CPUs.
+    #[arg(short, long)]
+    pub jobs: Option<usize>,
+
+    /// The ninja program to build with.
+    #[arg(long, default_value = "ninja")]
+    pub ninja: String,
+}
+
+/// The available build graph formats.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum GraphFormat {
+    /// Every build statement with its inputs, outputs, rule, variables and
+    /// originating build spec, as JSON.
+    Json,
+}
+
+/// The ways errors and warnings can be written.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum MessageFormat {
+    /// Text for people.
+    #[default]
+    Human,
+    /// A JSON object per diagnostic, with severity, file, span and message.
+    Json,
+}
+
+/// When to color output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Color output to a terminal, unless NO_COLOR is set.
+    #[default]
+    Auto,
+    /// Always color output.
+    Always,
+    /// Never color output.
+    Never,
+}
+
+/// The available output formats.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum BackendKind {
+    /// A ninja build file.
+    #[default]
+    Ninja,
+    /// A GNU make makefile.
+    Make,
+}
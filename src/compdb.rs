@@ -0,0 +1,50 @@
+use crate::backend::Backend;
+use crate::error::Result;
+use crate::ninja;
+use serde_derive::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// A clang compilation database with one entry per compile edge of a set of
+/// ninja build specifications. See
+/// https://clang.llvm.org/docs/JSONCompilationDatabase.html.
+pub struct CompilationDatabase<'a> {
+    /// The ninja build specifications to take compile edges from.
+    pub specs: Vec<&'a ninja::Spec>,
+    /// The directory the build runs in.
+    pub directory: PathBuf,
+}
+
+/// A single compilation database entry.
+#[derive(Serialize)]
+struct Entry<'a> {
+    directory: &'a PathBuf,
+    file: String,
+    output: &'a str,
+    command: String,
+}
+
+impl Backend for CompilationDatabase<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        let compile = ninja::Rules::ModCompile.to_string();
+        let entries: Vec<Entry> = self
+            .specs
+            .iter()
+            .flat_map(|spec| {
+                spec.statements
+                    .iter()
+                    .filter(|stmt| stmt.rule == compile)
+                    .filter_map(|stmt| {
+                        Some(Entry {
+                            directory: &self.directory,
+                            file: stmt.inputs.join(" "),
+                            output: &stmt.output,
+                            command: spec.command(stmt)?,
+                        })
+                    })
+            })
+            .collect();
+        serde_json::to_writer_pretty(&mut *w, &entries)?;
+        Ok(writeln!(w)?)
+    }
+}
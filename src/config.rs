@@ -0,0 +1,870 @@
+use crate::arch::Arch;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::error::{Error, Result};
+use crate::scan::{self, HeaderDeps};
+use crate::target::{Reproducible, Symbols, Target};
+use crate::toolchain::{
+    Distributed, Family, Linker, Sanitizer, Toolchain, Versions,
+};
+use crate::util;
+use crate::walk::{self, Walk};
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+/// The name of the workspace configuration file.
+pub const FILE_NAME: &str = "eos.toml";
+
+/// The root of the workspace `dir` is in: the nearest directory, starting
+/// with `dir` itself, that has an eos.toml or a `usr/src` directory in it.
+pub fn workspace_root(dir: &Path) -> Option<&Path> {
+    dir.ancestors()
+        .find(|x| x.join(FILE_NAME).is_file() || x.join("usr/src").is_dir())
+}
+
+/// The settings that can be overridden from the environment or the command
+/// line.
+pub const SETTINGS: &[&str] = &[
+    "source_root",
+    "output_dir",
+    "named_output_dirs",
+    "proto_dir",
+    "arches",
+    "profile",
+    "toolchain.cc",
+    "toolchain.family",
+    "toolchain.triple",
+    "toolchain.sysroot",
+    "toolchain.ld",
+    "toolchain.linker",
+    "toolchain.emulation",
+    "toolchain.ctfconvert",
+    "toolchain.ctfmerge",
+    "toolchain.strip",
+    "toolchain.objcopy",
+    "toolchain.nm",
+    "toolchain.launcher",
+    "ctf.enabled",
+    "reproducible",
+    "werror",
+    "check_symbols",
+    "header_deps",
+    "walk.strict",
+    "walk.follow_symlinks",
+];
+
+/// Where the value of a setting came from, in increasing precedence.
+#[derive(Clone, Copy, Debug, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Source {
+    /// Built into eos.
+    #[default]
+    Default,
+    /// eos.toml.
+    File,
+    /// An `EOS_*` environment variable.
+    Env,
+    /// A command line flag.
+    Cli,
+}
+
+/// Workspace configuration, read from eos.toml at the root of the workspace.
+/// `EOS_*` environment variables take precedence over the file, and
+/// command line flags over both.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The source tree to build, i.e. `usr/src` of an illumos checkout.
+    pub source_root: Option<PathBuf>,
+    /// The directory build outputs are placed under.
+    pub output_dir: Option<PathBuf>,
+    /// Whether each architecture and profile is built in a directory of its
+    /// own next to the output directory, e.g. `bld-amd64-debug`, instead of
+    /// under it.
+    pub named_output_dirs: Option<bool>,
+    /// The directory the proto areas built files are installed into are
+    /// placed under.
+    pub proto_dir: Option<PathBuf>,
+    /// The architectures to build for.
+    #[serde(default)]
+    pub arches: Vec<Arch>,
+    /// The name of the profile to build with, if any.
+    pub profile: Option<String>,
+    /// Whether two builds of the same sources should produce identical
+    /// objects.
+    pub reproducible: Option<bool>,
+    /// Whether compiler warnings are errors, except where build specs
+    /// exempt their modules or sources.
+    pub werror: Option<bool>,
+    /// Whether to check that modules only reference symbols genunix or their
+    /// dependencies define.
+    pub check_symbols: Option<bool>,
+    /// Which headers objects depend on.
+    pub header_deps: Option<HeaderDeps>,
+    /// Modules that modules may depend on but that are built some other way,
+    /// and so have no build spec, e.g. `misc/scsi`.
+    #[serde(default)]
+    pub external_modules: Vec<String>,
+    /// Whether a source more than one build spec compiles is an error, or
+    /// only a warning, the default.
+    pub duplicate_sources: Option<Severity>,
+    /// Programs to build with instead of the defaults.
+    #[serde(default)]
+    pub toolchain: ToolchainConfig,
+    /// Flag profiles, by name.
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+    /// How CTF data is converted and merged.
+    #[serde(default)]
+    pub ctf: CtfConfig,
+    /// How compiling is spread over other machines, if it is.
+    pub distributed: Option<Distributed>,
+    /// How the source tree is searched for build specs.
+    #[serde(default)]
+    pub walk: WalkConfig,
+    /// Where each setting that is not a default came from.
+    #[serde(skip)]
+    pub sources: BTreeMap<&'static str, Source>,
+}
+
+/// Settings that take precedence over eos.toml, from the environment or the
+/// command line.
+#[derive(Default)]
+pub struct Overrides {
+    pub source_root: Option<PathBuf>,
+    pub output_dir: Option<PathBuf>,
+    pub named_output_dirs: Option<bool>,
+    pub proto_dir: Option<PathBuf>,
+    pub arches: Vec<Arch>,
+    pub profile: Option<String>,
+    pub cc: Option<String>,
+    pub family: Option<Family>,
+    pub triple: Option<String>,
+    pub sysroot: Option<PathBuf>,
+    pub ld: Option<String>,
+    pub linker: Option<Linker>,
+    pub emulation: Option<String>,
+    pub ctfconvert: Option<String>,
+    pub ctfmerge: Option<String>,
+    pub strip: Option<String>,
+    pub objcopy: Option<String>,
+    pub nm: Option<String>,
+    pub launcher: Option<String>,
+    pub ctf: Option<bool>,
+    pub reproducible: Option<bool>,
+    pub werror: Option<bool>,
+    pub check_symbols: Option<bool>,
+    pub header_deps: Option<HeaderDeps>,
+    pub strict_walk: Option<bool>,
+    pub follow_symlinks: Option<bool>,
+}
+
+impl Overrides {
+    /// Make the relative paths to directories, which are relative to `dir`
+    /// in the workspace, relative to the root of the workspace instead, as
+    /// those given on the command line are.
+    pub fn rebase(&mut self, dir: &Path) {
+        for x in [
+            &mut self.source_root,
+            &mut self.output_dir,
+            &mut self.proto_dir,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *x = util::rebase(dir, x);
+        }
+    }
+
+    /// Read overrides from `EOS_SOURCE_ROOT`, `EOS_OUTPUT_DIR`,
+    /// `EOS_PROTO_DIR`, `EOS_ARCH` (comma separated), `EOS_PROFILE`, `EOS_CC`,
+    /// `EOS_CC_FAMILY`, `EOS_TRIPLE`, `EOS_SYSROOT`, `EOS_LD`, `EOS_LD_FAMILY`,
+    /// `EOS_LD_EMULATION`, `EOS_CTFCONVERT`, `EOS_CTFMERGE`, `EOS_STRIP`,
+    /// `EOS_OBJCOPY`, `EOS_NM`, `EOS_CC_LAUNCHER`, and `EOS_CTF`,
+    /// `EOS_REPRODUCIBLE`, `EOS_NAMED_OUTPUT_DIRS`, `EOS_WERROR`,
+    /// `EOS_CHECK_SYMBOLS`, `EOS_STRICT_WALK` and `EOS_FOLLOW_SYMLINKS` (`0` or
+    /// `1`), and `EOS_HEADER_DEPS`. Empty variables are ignored.
+    pub fn from_env() -> Result<Overrides> {
+        let var =
+            |name: &str| std::env::var(name).ok().filter(|x| !x.is_empty());
+        let flag = |name: &str| match var(name).as_deref() {
+            Some("1") => Ok(Some(true)),
+            Some("0") => Ok(Some(false)),
+            Some(x) => Err(Error::Usage(format!(
+                "{}: expected 0 or 1, not `{}`",
+                name, x
+            ))),
+            None => Ok(None),
+        };
+        let mut arches = Vec::new();
+        for x in var("EOS_ARCH").iter().flat_map(|x| x.split(',')) {
+            match Arch::from_str(x.trim(), true) {
+                Ok(arch) => arches.push(arch),
+                Err(_) => {
+                    return Err(Error::Usage(format!(
+                        "EOS_ARCH: unknown architecture `{}`",
+                        x
+                    )))
+                }
+            }
+        }
+        let family = match var("EOS_CC_FAMILY") {
+            Some(x) => Some(Family::from_str(&x, true).map_err(|_| {
+                Error::Usage(format!("EOS_CC_FAMILY: unknown compiler `{}`", x))
+            })?),
+            None => None,
+        };
+        let linker = match var("EOS_LD_FAMILY") {
+            Some(x) => Some(Linker::from_str(&x, true).map_err(|_| {
+                Error::Usage(format!(
+                    "EOS_LD_FAMILY: unknown link editor `{}`",
+                    x
+                ))
+            })?),
+            None => None,
+        };
+        let header_deps = match var("EOS_HEADER_DEPS") {
+            Some(x) => Some(HeaderDeps::from_str(&x, true).map_err(|_| {
+                Error::Usage(format!(
+                    "EOS_HEADER_DEPS: unknown kind of headers `{}`",
+                    x
+                ))
+            })?),
+            None => None,
+        };
+        Ok(Overrides {
+            source_root: var("EOS_SOURCE_ROOT").map(PathBuf::from),
+            output_dir: var("EOS_OUTPUT_DIR").map(PathBuf::from),
+            named_output_dirs: flag("EOS_NAMED_OUTPUT_DIRS")?,
+            proto_dir: var("EOS_PROTO_DIR").map(PathBuf::from),
+            arches,
+            profile: var("EOS_PROFILE"),
+            cc: var("EOS_CC"),
+            family,
+            triple: var("EOS_TRIPLE"),
+            sysroot: var("EOS_SYSROOT").map(PathBuf::from),
+            ld: var("EOS_LD"),
+            linker,
+            emulation: var("EOS_LD_EMULATION"),
+            ctfconvert: var("EOS_CTFCONVERT"),
+            ctfmerge: var("EOS_CTFMERGE"),
+            strip: var("EOS_STRIP"),
+            objcopy: var("EOS_OBJCOPY"),
+            nm: var("EOS_NM"),
+            launcher: var("EOS_CC_LAUNCHER"),
+            ctf: flag("EOS_CTF")?,
+            reproducible: flag("EOS_REPRODUCIBLE")?,
+            werror: flag("EOS_WERROR")?,
+            check_symbols: flag("EOS_CHECK_SYMBOLS")?,
+            header_deps,
+            strict_walk: flag("EOS_STRICT_WALK")?,
+            follow_symlinks: flag("EOS_FOLLOW_SYMLINKS")?,
+        })
+    }
+}
+
+/// The programs to build with. Unset programs keep their defaults.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ToolchainConfig {
+    /// The C compiler, also used to scan sources for headers.
+    pub cc: Option<String>,
+    /// The kind of compiler `cc` is. Defaults to clang if its name says so
+    /// and gcc otherwise.
+    pub family: Option<Family>,
+    /// The target triple to cross compile for. Programs that are not set
+    /// default to those named after it, e.g. `x86_64-unknown-illumos-gcc`,
+    /// and clang is passed it with `--target`.
+    pub triple: Option<String>,
+    /// The illumos system root to cross compile against.
+    pub sysroot: Option<PathBuf>,
+    /// The link editor.
+    pub ld: Option<String>,
+    /// The kind of link editor `ld` is. Defaults to lld if its name says so
+    /// and the illumos link editor otherwise.
+    pub linker: Option<Linker>,
+    /// The emulation lld links for, e.g. `elf_x86_64`. Defaults to the one
+    /// for the architecture; the illumos link editor takes the machine from
+    /// the objects it links.
+    pub emulation: Option<String>,
+    /// The CTF converter.
+    pub ctfconvert: Option<String>,
+    /// The CTF merger.
+    pub ctfmerge: Option<String>,
+    /// The symbol stripper.
+    pub strip: Option<String>,
+    /// Copies debug information out of objects when it is split from them.
+    pub objcopy: Option<String>,
+    /// Lists the symbols of objects, to check those modules reference.
+    pub nm: Option<String>,
+    /// A compiler cache that compile commands are run through, e.g.
+    /// `ccache` or `sccache`. Sources are still scanned with `cc` alone.
+    pub launcher: Option<String>,
+    /// The versions the programs must be, which generating a build file
+    /// checks.
+    #[serde(default)]
+    pub versions: Versions,
+}
+
+/// How CTF data is converted and merged, in eos.toml or a build spec, whose
+/// settings take precedence for its own objects.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct CtfConfig {
+    /// Whether objects get CTF data, and are stripped, at all. Leaving it
+    /// out makes small rebuilds much faster, but the kernel cannot be
+    /// debugged with mdb or traced with DTrace by type. Only eos.toml may
+    /// set it.
+    pub enabled: Option<bool>,
+    /// The label CTF data is given, by default the release, `5.11`.
+    pub label: Option<String>,
+    /// Flags to run ctfconvert with, by default `-X`.
+    pub convert_flags: Option<Vec<String>>,
+    /// Flags to run ctfmerge with.
+    pub merge_flags: Option<Vec<String>>,
+}
+
+/// How the source tree is searched for build specs.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct WalkConfig {
+    /// Whether a directory that cannot be read fails the search, instead of
+    /// being skipped with a warning.
+    pub strict: Option<bool>,
+    /// Whether symbolic links are followed, e.g. to directories of shared
+    /// code, instead of skipped.
+    pub follow_symlinks: Option<bool>,
+    /// Directories that are not searched, e.g. `usr/src/cmd` while only the
+    /// kernel builds with eos.
+    #[serde(default)]
+    pub prune: Vec<PathBuf>,
+    /// How many directories down the search goes, by default all the way.
+    pub max_depth: Option<usize>,
+}
+
+/// Flags added after the built-in ones, e.g. `-O0` for a debug build.
+#[derive(Clone, Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Flags to compile kernel objects with.
+    #[serde(default)]
+    pub cflags: Vec<String>,
+    /// Flags to link kernel components with.
+    #[serde(default)]
+    pub ldflags: Vec<String>,
+    /// What to do with the symbols of kernel objects: `keep`, `strip` or
+    /// `split`, or `true` or `false` for strip and keep. Defaults to
+    /// `strip`.
+    #[serde(default, deserialize_with = "symbols")]
+    pub strip: Option<Symbols>,
+    /// The sanitizer to instrument kernel objects with, e.g. `address` for
+    /// KASAN.
+    pub sanitize: Option<Sanitizer>,
+    /// Whether objects get CTF data and are stripped. Defaults to true.
+    pub ctf: Option<bool>,
+    /// Whether objects collect coverage data, for a report written after
+    /// the tests run. The flags that instrument them are up to the profile.
+    #[serde(default)]
+    pub coverage: bool,
+}
+
+/// Read the `strip` setting of a profile, which is either what to do with
+/// symbols or whether to strip them.
+fn symbols<'de, D>(
+    deserializer: D,
+) -> std::result::Result<Option<Symbols>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Setting {
+        Strip(bool),
+        Name(String),
+    }
+    let setting = serde::Deserialize::deserialize(deserializer).ok();
+    Ok(Some(match setting {
+        Some(Setting::Strip(true)) => Symbols::Strip,
+        Some(Setting::Strip(false)) => Symbols::Keep,
+        Some(Setting::Name(x)) if x == "keep" => Symbols::Keep,
+        Some(Setting::Name(x)) if x == "strip" => Symbols::Strip,
+        Some(Setting::Name(x)) if x == "split" => Symbols::Split,
+        _ => {
+            return Err(serde::de::Error::custom(
+                "expected keep, strip, split, true or false",
+            ))
+        }
+    }))
+}
+
+impl Profile {
+    /// The profiles built into eos, which eos.toml may redefine. `release`
+    /// is the same as no profile, `dev` leaves out CTF data for fast
+    /// rebuilds, `kasan` and `kmsan` build a DEBUG kernel instrumented with
+    /// a sanitizer, and `coverage` one instrumented with gcov.
+    fn builtin(name: &str) -> Option<Profile> {
+        let sanitized = |sanitizer| Profile {
+            cflags: vec!["-O1".into(), "-DDEBUG".into()],
+            strip: Some(Symbols::Keep),
+            sanitize: Some(sanitizer),
+            ..Default::default()
+        };
+        match name {
+            "debug" => Some(Profile {
+                cflags: vec!["-O0".into(), "-DDEBUG".into()],
+                strip: Some(Symbols::Keep),
+                ..Default::default()
+            }),
+            "dev" => Some(Profile {
+                strip: Some(Symbols::Keep),
+                ctf: Some(false),
+                ..Default::default()
+            }),
+            "coverage" => Some(Profile {
+                cflags: vec![
+                    "-O0".into(),
+                    "-DDEBUG".into(),
+                    "--coverage".into(),
+                ],
+                strip: Some(Symbols::Keep),
+                coverage: true,
+                ..Default::default()
+            }),
+            "kasan" => Some(sanitized(Sanitizer::Address)),
+            "kmsan" => Some(sanitized(Sanitizer::Memory)),
+            "release" => Some(Profile::default()),
+            _ => None,
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration at `path`. A missing file is the same as an
+    /// empty one.
+    pub fn load(path: &Path) -> Result<Config> {
+        let data = match std::fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                return Ok(Config::default())
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let mut config: Config = toml::from_str(&data)
+            .map_err(|e| Error::Parse(Diagnostic::toml(path, &data, &e)))?;
+        let set = [
+            config.source_root.is_some(),
+            config.output_dir.is_some(),
+            config.named_output_dirs.is_some(),
+            config.proto_dir.is_some(),
+            !config.arches.is_empty(),
+            config.profile.is_some(),
+            config.toolchain.cc.is_some(),
+            config.toolchain.family.is_some(),
+            config.toolchain.triple.is_some(),
+            config.toolchain.sysroot.is_some(),
+            config.toolchain.ld.is_some(),
+            config.toolchain.linker.is_some(),
+            config.toolchain.emulation.is_some(),
+            config.toolchain.ctfconvert.is_some(),
+            config.toolchain.ctfmerge.is_some(),
+            config.toolchain.strip.is_some(),
+            config.toolchain.objcopy.is_some(),
+            config.toolchain.nm.is_some(),
+            config.toolchain.launcher.is_some(),
+            config.ctf.enabled.is_some(),
+            config.reproducible.is_some(),
+            config.werror.is_some(),
+            config.check_symbols.is_some(),
+            config.header_deps.is_some(),
+            config.walk.strict.is_some(),
+            config.walk.follow_symlinks.is_some(),
+        ];
+        for (name, set) in SETTINGS.iter().zip(set) {
+            if set {
+                config.sources.insert(name, Source::File);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Apply `overrides`, which come from `source`.
+    pub fn apply(&mut self, overrides: Overrides, source: Source) {
+        let mut set = Vec::new();
+        if let Some(x) = overrides.source_root {
+            self.source_root = Some(x);
+            set.push("source_root");
+        }
+        if let Some(x) = overrides.output_dir {
+            self.output_dir = Some(x);
+            set.push("output_dir");
+        }
+        if let Some(x) = overrides.proto_dir {
+            self.proto_dir = Some(x);
+            set.push("proto_dir");
+        }
+        if !overrides.arches.is_empty() {
+            self.arches = overrides.arches;
+            set.push("arches");
+        }
+        if let Some(x) = overrides.profile {
+            self.profile = Some(x);
+            set.push("profile");
+        }
+        if let Some(x) = overrides.family {
+            self.toolchain.family = Some(x);
+            set.push("toolchain.family");
+        }
+        if let Some(x) = overrides.linker {
+            self.toolchain.linker = Some(x);
+            set.push("toolchain.linker");
+        }
+        if let Some(x) = overrides.triple {
+            self.toolchain.triple = Some(x);
+            set.push("toolchain.triple");
+        }
+        if let Some(x) = overrides.sysroot {
+            self.toolchain.sysroot = Some(x);
+            set.push("toolchain.sysroot");
+        }
+        if let Some(x) = overrides.ctf {
+            self.ctf.enabled = Some(x);
+            set.push("ctf.enabled");
+        }
+        if let Some(x) = overrides.reproducible {
+            self.reproducible = Some(x);
+            set.push("reproducible");
+        }
+        if let Some(x) = overrides.werror {
+            self.werror = Some(x);
+            set.push("werror");
+        }
+        if let Some(x) = overrides.named_output_dirs {
+            self.named_output_dirs = Some(x);
+            set.push("named_output_dirs");
+        }
+        if let Some(x) = overrides.check_symbols {
+            self.check_symbols = Some(x);
+            set.push("check_symbols");
+        }
+        if let Some(x) = overrides.header_deps {
+            self.header_deps = Some(x);
+            set.push("header_deps");
+        }
+        if let Some(x) = overrides.strict_walk {
+            self.walk.strict = Some(x);
+            set.push("walk.strict");
+        }
+        if let Some(x) = overrides.follow_symlinks {
+            self.walk.follow_symlinks = Some(x);
+            set.push("walk.follow_symlinks");
+        }
+        for (name, value, setting) in [
+            ("toolchain.cc", &mut self.toolchain.cc, overrides.cc),
+            ("toolchain.ld", &mut self.toolchain.ld, overrides.ld),
+            (
+                "toolchain.emulation",
+                &mut self.toolchain.emulation,
+                overrides.emulation,
+            ),
+            (
+                "toolchain.ctfconvert",
+                &mut self.toolchain.ctfconvert,
+                overrides.ctfconvert,
+            ),
+            (
+                "toolchain.ctfmerge",
+                &mut self.toolchain.ctfmerge,
+                overrides.ctfmerge,
+            ),
+            (
+                "toolchain.strip",
+                &mut self.toolchain.strip,
+                overrides.strip,
+            ),
+            (
+                "toolchain.objcopy",
+                &mut self.toolchain.objcopy,
+                overrides.objcopy,
+            ),
+            ("toolchain.nm", &mut self.toolchain.nm, overrides.nm),
+            (
+                "toolchain.launcher",
+                &mut self.toolchain.launcher,
+                overrides.launcher,
+            ),
+        ] {
+            if setting.is_some() {
+                *value = setting;
+                set.push(name);
+            }
+        }
+        for name in set {
+            self.sources.insert(name, source);
+        }
+    }
+
+    /// Where the value of `setting` came from.
+    pub fn source(&self, setting: &str) -> Source {
+        self.sources.get(setting).copied().unwrap_or_default()
+    }
+
+    /// The source tree to build.
+    pub fn source_root(&self) -> &Path {
+        self.source_root.as_deref().unwrap_or(Path::new("usr/src"))
+    }
+
+    /// The directory build outputs are placed under.
+    pub fn output_dir(&self) -> &Path {
+        self.output_dir.as_deref().unwrap_or(Path::new("bld"))
+    }
+
+    /// The directory caches are kept in, under the output directory, shared
+    /// by every architecture and profile.
+    pub fn cache_dir(&self) -> PathBuf {
+        self.output_dir().join(scan::CACHE_DIR)
+    }
+
+    /// The directory proto areas are placed under.
+    pub fn proto_dir(&self) -> &Path {
+        self.proto_dir.as_deref().unwrap_or(Path::new("proto"))
+    }
+
+    /// The architectures to build for, sorted and without duplicates.
+    pub fn arches(&self) -> Vec<Arch> {
+        let mut arches = match self.arches.is_empty() {
+            true => vec![Arch::Amd64],
+            false => self.arches.clone(),
+        };
+        arches.sort();
+        arches.dedup();
+        arches
+    }
+
+    /// The profile to build with, from eos.toml or built in. Without one,
+    /// only the built-in flags are used.
+    pub fn profile(&self) -> Result<Option<Profile>> {
+        let Some(name) = &self.profile else {
+            return Ok(None);
+        };
+        match self.profiles.get(name).cloned() {
+            Some(profile) => Ok(Some(profile)),
+            None => match Profile::builtin(name) {
+                Some(profile) => Ok(Some(profile)),
+                None => Err(Error::Usage(format!(
+                    "no profile named {} in {}",
+                    name, FILE_NAME
+                ))),
+            },
+        }
+    }
+
+    /// The directory builds with the selected profile are placed under: a
+    /// directory named after the profile in the output directory, or the
+    /// output directory itself for `release` or no profile.
+    pub fn profile_dir(&self) -> PathBuf {
+        match self.profile.as_deref() {
+            None | Some("release") => self.output_dir().to_owned(),
+            Some(name) => self.output_dir().join(name),
+        }
+    }
+
+    /// The directory `arch`, one of `multiarch` architectures if set, is
+    /// built in with the selected profile: one named after both next to the
+    /// output directory with `named_output_dirs`, or otherwise the profile's
+    /// directory, with a directory for each architecture in it if there are
+    /// several.
+    pub fn build_dir(&self, arch: Arch, multiarch: bool) -> PathBuf {
+        if self.named_output_dirs == Some(true) {
+            let profile = self.profile.as_deref().unwrap_or("release");
+            let mut name = self.output_dir().as_os_str().to_owned();
+            name.push(format!("-{}-{}", arch, profile));
+            return name.into();
+        }
+        let mut dir = self.profile_dir();
+        if multiarch {
+            dir.push(arch.to_string());
+        }
+        dir
+    }
+
+    /// The output directories builds may have left: the output directory, or
+    /// with `named_output_dirs` those of any architecture and profile next
+    /// to it.
+    pub fn output_dirs(&self) -> Result<Vec<PathBuf>> {
+        let bld = self.output_dir();
+        if self.named_output_dirs != Some(true) {
+            return Ok(vec![bld.to_owned()]);
+        }
+        let parent = match bld.parent() {
+            Some(x) if !x.as_os_str().is_empty() => x,
+            _ => Path::new("."),
+        };
+        let prefixes: Vec<String> = Arch::value_variants()
+            .iter()
+            .map(|x| format!("{}-{}-", bld.display(), x))
+            .collect();
+        let mut dirs = Vec::new();
+        if !parent.is_dir() {
+            return Ok(dirs);
+        }
+        for entry in std::fs::read_dir(parent)? {
+            let path = parent.join(entry?.file_name());
+            let path = path.strip_prefix(".").unwrap_or(&path).to_owned();
+            let name = path.to_string_lossy();
+            if prefixes.iter().any(|x| name.starts_with(x)) && path.is_dir() {
+                dirs.push(path);
+            }
+        }
+        dirs.sort();
+        Ok(dirs)
+    }
+
+    /// How to search the source tree for build specs: skipping the output
+    /// and proto directories, those pruned, and what the ignore file at its
+    /// root says to.
+    pub fn walk(&self) -> Result<Walk> {
+        let absolute = |x: &Path| -> Result<PathBuf> {
+            Ok(util::normalize(&std::path::absolute(x)?))
+        };
+        let mut walk = Walk {
+            strict: self.walk.strict == Some(true),
+            follow_symlinks: self.walk.follow_symlinks == Some(true),
+            root: absolute(self.source_root())?,
+            skip: Vec::new(),
+            max_depth: self.walk.max_depth,
+            ignore: Vec::new(),
+        };
+        for dir in self.output_dirs()?.iter().map(PathBuf::as_path) {
+            walk.skip.push(absolute(dir)?);
+        }
+        walk.skip.push(absolute(self.proto_dir())?);
+        for dir in &self.walk.prune {
+            walk.skip.push(absolute(dir)?);
+        }
+        walk.read_ignore(&self.source_root().join(walk::IGNORE_FILE))?;
+        Ok(walk)
+    }
+
+    /// The target for building `arch`, one of `multiarch` architectures
+    /// if set.
+    pub fn target(&self, arch: Arch, multiarch: bool) -> Result<Target> {
+        let mut target = Target::new(
+            arch,
+            self.source_root(),
+            &self.build_dir(arch, multiarch),
+            self.proto_dir(),
+        );
+        // Every path in the build is under one of these, so checking them
+        // once lets the target name its own files without failing.
+        for (name, dir) in [
+            ("source_root", &target.src),
+            ("output_dir", &target.bld),
+            ("proto_dir", &target.proto),
+        ] {
+            if dir.to_str().is_none() {
+                return Err(Error::Usage(format!(
+                    "{}: {} is not valid UTF-8, which paths in a ninja build \
+                     file must be",
+                    name,
+                    dir.display()
+                )));
+            }
+        }
+        let toolchain = self.toolchain();
+        target.family = toolchain.family;
+        target.triple = toolchain.triple;
+        target.sysroot = toolchain.sysroot;
+        target.linker = toolchain.linker;
+        target.emulation =
+            match (toolchain.linker, toolchain.emulation) {
+                (Linker::Lld, x) => {
+                    Some(x.unwrap_or_else(|| arch.lld_emulation().into()))
+                }
+                (Linker::Illumos, None) => None,
+                (Linker::Illumos, Some(_)) => return Err(Error::Usage(
+                    "toolchain.emulation, or EOS_LD_EMULATION, is only used \
+                     with lld: the illumos link editor takes the machine from \
+                     the objects it links"
+                        .into(),
+                )),
+            };
+        if let Some(profile) = self.profile()? {
+            target.cflags = profile.cflags;
+            target.ldflags = profile.ldflags;
+            target.symbols = profile.strip.unwrap_or_default();
+            target.sanitize = profile.sanitize;
+            target.coverage = profile.coverage;
+            target.ctf.enabled = profile.ctf.unwrap_or(true);
+        }
+        if let Some(x) = self.ctf.enabled {
+            target.ctf.enabled = x;
+        }
+        if self.reproducible == Some(true) {
+            target.reproducible = Some(Reproducible::new()?);
+        }
+        target.werror = self.werror == Some(true);
+        target.check_symbols = self.check_symbols == Some(true);
+        target.header_deps = self.header_deps.unwrap_or_default();
+        if let Some(x) = &self.ctf.label {
+            target.ctf.label.clone_from(x);
+        }
+        if let Some(x) = &self.ctf.convert_flags {
+            target.ctf.convert_flags.clone_from(x);
+        }
+        if let Some(x) = &self.ctf.merge_flags {
+            target.ctf.merge_flags.clone_from(x);
+        }
+        if let Some(sanitizer) = target.sanitize {
+            if sanitizer.cflags(target.family).is_none() {
+                return Err(Error::Usage(format!(
+                    "{} cannot instrument objects with the {} sanitizer",
+                    toolchain.cc,
+                    sanitizer.to_possible_value().unwrap().get_name()
+                )));
+            }
+        }
+        Ok(target)
+    }
+
+    /// The programs used to build the tree.
+    pub fn toolchain(&self) -> Toolchain {
+        let mut toolchain = Toolchain::default();
+        if let Some(triple) = &self.toolchain.triple {
+            toolchain.cc = format!("{}-gcc", triple);
+            toolchain.ld = format!("{}-ld", triple);
+            toolchain.strip = format!("{}-strip", triple);
+            toolchain.objcopy = format!("{}-objcopy", triple);
+            toolchain.nm = format!("{}-nm", triple);
+            toolchain.triple = Some(triple.clone());
+        }
+        toolchain.sysroot = self.toolchain.sysroot.clone();
+        toolchain.launcher = self.toolchain.launcher.clone();
+        toolchain.distributed = self.distributed.clone();
+        toolchain.versions = self.toolchain.versions.clone();
+        for (value, setting) in [
+            (&mut toolchain.cc, &self.toolchain.cc),
+            (&mut toolchain.ld, &self.toolchain.ld),
+            (&mut toolchain.ctfconvert, &self.toolchain.ctfconvert),
+            (&mut toolchain.ctfmerge, &self.toolchain.ctfmerge),
+            (&mut toolchain.strip, &self.toolchain.strip),
+            (&mut toolchain.objcopy, &self.toolchain.objcopy),
+            (&mut toolchain.nm, &self.toolchain.nm),
+        ] {
+            if let Some(setting) = setting {
+                *value = setting.clone();
+            }
+        }
+        toolchain.family = self
+            .toolchain
+            .family
+            .unwrap_or_else(|| Family::detect(&toolchain.cc));
+        toolchain.linker = self
+            .toolchain
+            .linker
+            .unwrap_or_else(|| Linker::detect(&toolchain.ld));
+        toolchain.emulation = self.toolchain.emulation.clone();
+        toolchain
+    }
+}
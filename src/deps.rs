@@ -0,0 +1,228 @@
+use crate::arch::Arch;
+use crate::backend::Backend;
+use crate::error::{Error, Result};
+use crate::ninja;
+use crate::spec::Spec;
+use serde_derive::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The dependencies between kernel modules, and of every module on genunix,
+/// as a Graphviz or Mermaid graph. Dependencies on modules that have no build
+/// spec yet are drawn dashed.
+pub struct ModuleGraph<'a> {
+    /// The build specs to take modules from.
+    pub specs: Vec<&'a (PathBuf, Spec)>,
+    /// The graph language to write.
+    pub format: Format,
+}
+
+/// The graph languages a module graph can be written in.
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum Format {
+    /// Graphviz DOT.
+    Dot,
+    /// A Mermaid flowchart.
+    Mermaid,
+}
+
+impl ModuleGraph<'_> {
+    /// The nodes of the graph, by name, with whether they have a build spec,
+    /// and the edges between them.
+    fn nodes_and_edges(&self) -> (BTreeMap<&str, bool>, Vec<(&str, &str)>) {
+        let mut nodes = BTreeMap::new();
+        let mut edges = Vec::new();
+        for (_, spec) in &self.specs {
+            if spec.genunix.is_some() {
+                nodes.insert("genunix", true);
+            }
+        }
+        for (_, spec) in &self.specs {
+            let Some(module) = &spec.module else {
+                continue;
+            };
+            nodes.insert(module.name.as_str(), true);
+            for dep in module.dependency_names() {
+                nodes.entry(dep).or_insert(false);
+                edges.push((module.name.as_str(), dep));
+            }
+            nodes.entry("genunix").or_insert(false);
+            edges.push((module.name.as_str(), "genunix"));
+        }
+        (nodes, edges)
+    }
+}
+
+impl Backend for ModuleGraph<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        let (nodes, edges) = self.nodes_and_edges();
+        let ids: BTreeMap<&str, String> = nodes
+            .keys()
+            .enumerate()
+            .map(|(i, name)| (*name, format!("n{}", i)))
+            .collect();
+        match self.format {
+            Format::Dot => {
+                writeln!(w, "digraph modules {{")?;
+                for (name, known) in &nodes {
+                    let style = if *known { "" } else { ", style=dashed" };
+                    writeln!(
+                        w,
+                        "  {} [label=\"{}\"{}];",
+                        ids[name], name, style
+                    )?;
+                }
+                for (from, to) in &edges {
+                    writeln!(w, "  {} -> {};", ids[from], ids[to])?;
+                }
+                Ok(writeln!(w, "}}")?)
+            }
+            Format::Mermaid => {
+                writeln!(w, "graph LR")?;
+                for (name, known) in &nodes {
+                    writeln!(w, "  {}[\"{}\"]", ids[name], name)?;
+                    if !*known {
+                        writeln!(
+                            w,
+                            "  style {} stroke-dasharray: 5 5",
+                            ids[name]
+                        )?;
+                    }
+                }
+                for (from, to) in &edges {
+                    writeln!(w, "  {} --> {}", ids[from], ids[to])?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The transitive dependencies of a module: the modules it needs loaded,
+/// directly or through other modules, and genunix, optionally with the
+/// headers its objects are compiled from, as a tree or JSON.
+#[derive(Serialize)]
+pub struct Closure<'a> {
+    /// The module whose dependencies these are.
+    pub module: &'a str,
+    /// The build spec of each module in the closure, by name, or `None` for
+    /// modules without one yet.
+    pub modules: BTreeMap<&'a str, Option<&'a Path>>,
+    /// The headers the objects of the module depend on, if asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub headers: Option<BTreeSet<String>>,
+    /// The direct dependencies of each module in the closure.
+    #[serde(skip)]
+    edges: BTreeMap<&'a str, Vec<&'a str>>,
+    /// Whether to write JSON instead of a tree.
+    #[serde(skip)]
+    pub json: bool,
+}
+
+impl<'a> Closure<'a> {
+    /// The closure of `module` among the modules of `specs`. Headers are
+    /// taken from the compile edges `ninja_specs` has for the module, if
+    /// given.
+    pub fn new(
+        specs: &'a [(PathBuf, Spec)],
+        module: &'a str,
+        ninja_specs: Option<&[(Arch, ninja::Spec)]>,
+        json: bool,
+    ) -> Result<Closure<'a>> {
+        let mut known = BTreeMap::new();
+        for (path, spec) in specs {
+            if spec.genunix.is_some() {
+                known.insert("genunix", (path.as_path(), None));
+            }
+            if let Some(x) = &spec.module {
+                known.insert(x.name.as_str(), (path.as_path(), Some(x)));
+            }
+        }
+        let Some((path, _)) = known.get(module) else {
+            return Err(Error::Usage(format!("no module named {}", module)));
+        };
+
+        let mut modules = BTreeMap::new();
+        let mut edges = BTreeMap::new();
+        let mut todo = vec![module];
+        while let Some(name) = todo.pop() {
+            if edges.contains_key(name) {
+                continue;
+            }
+            let deps: Vec<&str> = match known.get(name) {
+                Some((_, Some(x))) => {
+                    x.dependency_names().chain(["genunix"]).collect()
+                }
+                _ => Vec::new(),
+            };
+            for dep in &deps {
+                modules.insert(*dep, known.get(dep).map(|x| x.0));
+            }
+            todo.extend(&deps);
+            edges.insert(name, deps);
+        }
+
+        let compile = ninja::Rules::ModCompile.to_string();
+        let headers = ninja_specs.map(|x| {
+            x.iter()
+                .flat_map(|(_, x)| &x.statements)
+                .filter(|x| {
+                    x.rule == compile && x.origin.as_deref() == Some(*path)
+                })
+                .flat_map(|x| x.implicit_deps.iter().cloned())
+                .collect()
+        });
+        Ok(Closure {
+            module,
+            modules,
+            headers,
+            edges,
+            json,
+        })
+    }
+
+    /// Write the dependencies of `name`, `depth` levels down the tree, and
+    /// theirs below them. Modules already written are marked `(*)` and not
+    /// expanded again.
+    fn tree(
+        &self,
+        w: &mut dyn Write,
+        name: &'a str,
+        depth: usize,
+        seen: &mut BTreeSet<&'a str>,
+    ) -> Result<()> {
+        let indent = "  ".repeat(depth);
+        let note = match self.modules.get(name) {
+            Some(None) => " (no build spec)",
+            _ if seen.contains(name) => " (*)",
+            _ => "",
+        };
+        writeln!(w, "{}{}{}", indent, name, note)?;
+        if !note.is_empty() {
+            return Ok(());
+        }
+        seen.insert(name);
+        for dep in &self.edges[name] {
+            self.tree(w, dep, depth + 1, seen)?;
+        }
+        Ok(())
+    }
+}
+
+impl Backend for Closure<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        if self.json {
+            serde_json::to_writer_pretty(&mut *w, self)?;
+            return Ok(writeln!(w)?);
+        }
+        self.tree(w, self.module, 0, &mut BTreeSet::new())?;
+        if let Some(headers) = &self.headers {
+            writeln!(w, "\nheaders:")?;
+            for header in headers {
+                writeln!(w, "  {}", header)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// How bad a diagnostic is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A position in a file, counting from 1.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A problem eos found, with the file and position it is about if known.
+#[derive(Clone, Debug, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: Option<PathBuf>,
+    pub span: Option<Span>,
+    pub message: String,
+    /// The line of the file the span is on, shown under the message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub snippet: Option<String>,
+}
+
+impl Diagnostic {
+    /// An error about `file`.
+    pub fn error(file: &Path, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            file: Some(file.to_owned()),
+            ..Diagnostic::general(message)
+        }
+    }
+
+    /// A warning about `file`.
+    pub fn warning(file: &Path, message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Warning,
+            ..Diagnostic::error(file, message)
+        }
+    }
+
+    /// An error about `file`, which holds `source`, positioned at the byte
+    /// `offset` into it.
+    pub fn at(
+        file: &Path,
+        source: &str,
+        offset: usize,
+        message: impl Into<String>,
+    ) -> Diagnostic {
+        let before = &source[..offset];
+        let start = before.rfind('\n').map_or(0, |x| x + 1);
+        Diagnostic {
+            span: Some(Span {
+                line: before.matches('\n').count() + 1,
+                column: before[start..].chars().count() + 1,
+            }),
+            snippet: source[start..].lines().next().map(|x| x.to_owned()),
+            ..Diagnostic::error(file, message)
+        }
+    }
+
+    /// An error about `file`, which holds `source`, from a TOML parse error,
+    /// positioned where the parser gave up.
+    pub fn toml(file: &Path, source: &str, e: &toml::de::Error) -> Diagnostic {
+        let span = e.line_col().map(|(line, column)| Span {
+            line: line + 1,
+            column: column + 1,
+        });
+        // The position is also part of the message, which is redundant once
+        // it is in the span.
+        let mut message = e.to_string();
+        if let Some(span) = span {
+            let suffix =
+                format!(" at line {} column {}", span.line, span.column);
+            if let Some(x) = message.strip_suffix(&suffix) {
+                message = x.to_owned();
+            }
+        }
+        Diagnostic {
+            span,
+            snippet: span
+                .and_then(|x| source.lines().nth(x.line - 1))
+                .map(|x| x.to_owned()),
+            ..Diagnostic::error(file, message)
+        }
+    }
+
+    /// An error about no file in particular.
+    pub fn general(message: impl Into<String>) -> Diagnostic {
+        Diagnostic {
+            severity: Severity::Error,
+            file: None,
+            span: None,
+            message: message.into(),
+            snippet: None,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(file) = &self.file {
+            write!(f, "{}", file.display())?;
+            if let Some(span) = self.span {
+                write!(f, ":{}:{}", span.line, span.column)?;
+            }
+            write!(f, ": ")?;
+        }
+        write!(f, "{}", self.message)?;
+        // The line, numbered, with a caret under the column, e.g.
+        //
+        //   |
+        // 3 | src = "mac.c"
+        //   |       ^
+        if let (Some(span), Some(snippet)) = (self.span, &self.snippet) {
+            let number = span.line.to_string();
+            let margin = " ".repeat(number.len());
+            // Tabs are kept so the caret lines up however they are shown.
+            let indent: String = snippet
+                .chars()
+                .take(span.column - 1)
+                .map(|x| if x == '\t' { '\t' } else { ' ' })
+                .collect();
+            write!(
+                f,
+                "\n{} |\n{} | {}\n{} | {}^",
+                margin, number, snippet, margin, indent
+            )?;
+        }
+        Ok(())
+    }
+}
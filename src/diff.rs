@@ -0,0 +1,205 @@
+use crate::backend::Backend;
+use crate::check;
+use crate::error::Result;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+/// The differences between a generated ninja file on disk and what eos would
+/// write there now, by build statement, rule and variable rather than by
+/// line, grouped by the build spec they come from.
+pub struct Diff {
+    /// The file compared.
+    pub path: PathBuf,
+    /// Whether the file exists.
+    pub exists: bool,
+    /// The changes, by build spec, or `eos` for what eos adds itself.
+    pub changes: BTreeMap<String, Vec<Change>>,
+}
+
+/// A change to one item of a ninja file.
+pub enum Change {
+    /// An item only in the new file, with its first line.
+    Added(String),
+    /// An item only in the file on disk, with its first line.
+    Removed(String),
+    /// An item in both whose text differs, with what it defines and the
+    /// lines removed and added.
+    Changed(String, Vec<String>, Vec<String>),
+}
+
+/// An item of a ninja file: a build statement, rule, variable, include or
+/// raw ninja text, and the lines it is written as.
+struct Item<'a> {
+    /// The build spec the item comes from.
+    origin: &'a str,
+    lines: Vec<&'a str>,
+}
+
+/// Compare the ninja file at `path` to what eos would write there,
+/// `expected`.
+pub fn compare(path: &Path, expected: &[u8]) -> Result<Diff> {
+    let actual = match std::fs::read(path) {
+        Ok(actual) => Some(actual),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    let mut diff = Diff {
+        path: path.to_owned(),
+        exists: actual.is_some(),
+        changes: BTreeMap::new(),
+    };
+    let Some(actual) = actual else {
+        return Ok(diff);
+    };
+    let actual = String::from_utf8_lossy(&actual);
+    let expected = String::from_utf8_lossy(expected);
+    let old = items(&actual);
+    let new = items(&expected);
+
+    let keys: BTreeSet<&str> = old.keys().chain(new.keys()).copied().collect();
+    for key in keys {
+        let (origin, change) = match (old.get(key), new.get(key)) {
+            (Some(a), Some(b)) if a.lines == b.lines => continue,
+            (Some(a), Some(b)) => (
+                b.origin,
+                Change::Changed(
+                    key.to_owned(),
+                    owned(check::difference(&a.lines, &b.lines)),
+                    owned(check::difference(&b.lines, &a.lines)),
+                ),
+            ),
+            (Some(a), None) => (a.origin, Change::Removed(a.lines[0].into())),
+            (None, Some(b)) => (b.origin, Change::Added(b.lines[0].into())),
+            (None, None) => unreachable!(),
+        };
+        diff.changes
+            .entry(origin.to_owned())
+            .or_default()
+            .push(change);
+    }
+    Ok(diff)
+}
+
+/// `lines` as owned strings.
+fn owned(lines: Vec<&str>) -> Vec<String> {
+    lines.into_iter().map(|x| x.to_owned()).collect()
+}
+
+/// The items of the generated ninja file `text`, by what they define. Build
+/// statements are attributed to the build spec named by the `# from`
+/// comment before them.
+fn items(text: &str) -> BTreeMap<&str, Item<'_>> {
+    let mut items = BTreeMap::new();
+    let mut origin = "eos";
+    let mut current: Option<(&str, Item)> = None;
+    let mut raw = false;
+    for line in text.lines() {
+        if let Some(x) = line.strip_prefix("# raw ninja from ") {
+            items.extend(current.take());
+            current = Some((
+                line,
+                Item {
+                    origin: x,
+                    lines: vec![line],
+                },
+            ));
+            raw = true;
+            continue;
+        }
+        if raw {
+            // Raw ninja text runs to the next raw section or the end.
+            if let Some((_, item)) = &mut current {
+                item.lines.push(line);
+            }
+            continue;
+        }
+        if let Some(x) = line.strip_prefix("# from ") {
+            items.extend(current.take());
+            origin = x;
+            continue;
+        }
+        if line.starts_with(' ') {
+            if let Some((_, item)) = &mut current {
+                item.lines.push(line);
+            }
+            continue;
+        }
+        items.extend(current.take());
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, origin) = match line.strip_prefix("build ") {
+            Some(_) => (statement_key(line), origin),
+            None if line.starts_with("rule ") => (line, "eos"),
+            None => (line.split(" = ").next().unwrap(), "eos"),
+        };
+        current = Some((
+            key,
+            Item {
+                origin,
+                lines: vec![line],
+            },
+        ));
+    }
+    items.extend(current);
+    items
+}
+
+/// The part of a build statement line naming its outputs, up to the first
+/// `:` not escaped with `$`.
+fn statement_key(line: &str) -> &str {
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            ':' if !escaped => return &line[..i],
+            '$' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+impl Backend for Diff {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        if !self.exists {
+            writeln!(w, "{}: missing", self.path.display())?;
+            return Ok(());
+        }
+        let (mut added, mut removed, mut changed) = (0, 0, 0);
+        for (origin, changes) in &self.changes {
+            writeln!(w, "{}: {}", self.path.display(), origin)?;
+            for change in changes {
+                match change {
+                    Change::Added(x) => {
+                        added += 1;
+                        writeln!(w, "  + {}", x)?;
+                    }
+                    Change::Removed(x) => {
+                        removed += 1;
+                        writeln!(w, "  - {}", x)?;
+                    }
+                    Change::Changed(x, old, new) => {
+                        changed += 1;
+                        writeln!(w, "  ~ {}", x)?;
+                        for line in old {
+                            writeln!(w, "      - {}", line.trim_start())?;
+                        }
+                        for line in new {
+                            writeln!(w, "      + {}", line.trim_start())?;
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(
+            w,
+            "{}: {} added, {} removed, {} changed",
+            self.path.display(),
+            added,
+            removed,
+            changed
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,196 @@
+use crate::backend::Backend;
+use crate::error::Result;
+use crate::toolchain::Toolchain;
+use std::io::{ErrorKind, Write};
+use std::process::Command;
+
+/// The oldest ninja that supports dyndep files, which the build relies on.
+const NINJA_VERSION: (u32, u32) = (1, 10);
+
+/// The result of checking that the programs the build needs can be run.
+pub struct Doctor {
+    pub checks: Vec<Check>,
+}
+
+/// The result of checking one program.
+pub struct Check {
+    /// What the program is for, e.g. `cc`.
+    pub tool: &'static str,
+    /// The program run.
+    pub program: String,
+    /// The version it reported, if it reports one.
+    pub version: Option<String>,
+    /// What is wrong with the program and how to fix it, if anything.
+    pub problem: Option<String>,
+}
+
+impl Doctor {
+    /// Check the programs of `toolchain`, and `ninja`.
+    pub fn new(toolchain: &Toolchain, ninja: &str) -> Doctor {
+        let mut doctor = Doctor {
+            checks: vec![
+                check(
+                    "cc",
+                    &toolchain.cc,
+                    Some("--version"),
+                    Some(toolchain.family.minimum_version()),
+                ),
+                check("ld", &toolchain.ld, None, None),
+                check("ctfconvert", &toolchain.ctfconvert, None, None),
+                check("ctfmerge", &toolchain.ctfmerge, None, None),
+                check("strip", &toolchain.strip, None, None),
+                check("objcopy", &toolchain.objcopy, None, None),
+                check("nm", &toolchain.nm, None, None),
+                check("ninja", ninja, Some("--version"), Some(NINJA_VERSION)),
+            ],
+        };
+        if let Some(launcher) = &toolchain.launcher {
+            doctor.checks.push(check(
+                "launcher",
+                launcher,
+                Some("--version"),
+                None,
+            ));
+        }
+        if let Some(distributed) = &toolchain.distributed {
+            doctor.checks.push(check(
+                "wrapper",
+                &distributed.wrapper,
+                Some("--version"),
+                None,
+            ));
+        }
+        doctor
+    }
+
+    /// How many of the programs have problems.
+    pub fn problems(&self) -> usize {
+        self.checks.iter().filter(|x| x.problem.is_some()).count()
+    }
+}
+
+/// Check that `program` can be run, and if it has a `version_flag`, that the
+/// version it reports is at least `minimum`. Programs without one are run
+/// with no arguments, which only shows they can be started, as most of them
+/// fail without input.
+fn check(
+    tool: &'static str,
+    program: &str,
+    version_flag: Option<&str>,
+    minimum: Option<(u32, u32)>,
+) -> Check {
+    let mut result = Check {
+        tool,
+        program: program.to_owned(),
+        version: None,
+        problem: None,
+    };
+    let setting = match tool {
+        "ninja" => "pass --ninja to the command that runs it".to_owned(),
+        "wrapper" => "set distributed.wrapper in eos.toml".to_owned(),
+        "launcher" => {
+            "set toolchain.launcher in eos.toml or EOS_CC_LAUNCHER".to_owned()
+        }
+        _ => format!(
+            "set toolchain.{} in eos.toml or EOS_{}",
+            tool,
+            tool.to_uppercase()
+        ),
+    };
+    let output = match Command::new(program).args(version_flag).output() {
+        Ok(x) => x,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            result.problem = Some(format!(
+                "{} was not found in PATH: install it, or {}",
+                program, setting
+            ));
+            return result;
+        }
+        Err(e) => {
+            result.problem =
+                Some(format!("{} cannot be run: {}; {}", program, e, setting));
+            return result;
+        }
+    };
+    let Some(flag) = version_flag else {
+        return result;
+    };
+    let text = String::from_utf8_lossy(&output.stdout);
+    let version = text.lines().next().unwrap_or("").trim().to_owned();
+    if !output.status.success() || version.is_empty() {
+        result.problem = Some(format!(
+            "`{} {}` failed: {}; {}",
+            program, flag, output.status, setting
+        ));
+        return result;
+    }
+    if let Some((major, minor)) = minimum {
+        match parse_version(&version) {
+            Some(x) if x >= (major, minor) => (),
+            Some(_) => {
+                result.problem = Some(format!(
+                    "{} {}.{} or later is needed: upgrade it, or {}",
+                    tool, major, minor, setting
+                ))
+            }
+            None => {
+                result.problem = Some(format!(
+                    "cannot tell which version of {} {} is, {}.{} or later \
+                     is needed; {}",
+                    tool, program, major, minor, setting
+                ))
+            }
+        }
+    }
+    result.version = Some(version);
+    result
+}
+
+/// The major and minor version in the first word of `line` that looks like
+/// a version, e.g. `gcc-10 (GCC) 10.4.0` -> 10.4.
+fn parse_version(line: &str) -> Option<(u32, u32)> {
+    line.split_whitespace().find_map(|word| {
+        let mut parts = word.split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor = parts.next()?.parse().ok()?;
+        Some((major, minor))
+    })
+}
+
+impl Backend for Doctor {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        for check in &self.checks {
+            let status = match check.problem {
+                Some(_) => "PROBLEM",
+                None => "ok",
+            };
+            write!(w, "{:<8} {:<11} {}", status, check.tool, check.program)?;
+            if let Some(version) = &check.version {
+                write!(w, " ({})", version)?;
+            }
+            writeln!(w)?;
+            if let Some(problem) = &check.problem {
+                writeln!(w, "         {}", problem)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_version_finds_first_version_word() {
+        assert_eq!(parse_version("gcc-10 (GCC) 10.4.0"), Some((10, 4)));
+        assert_eq!(parse_version("ninja 1.11"), Some((1, 11)));
+        assert_eq!(parse_version("1.10.2"), Some((1, 10)));
+    }
+
+    #[test]
+    fn parse_version_needs_major_and_minor() {
+        assert_eq!(parse_version("ld: Software Generation Utilities"), None);
+        assert_eq!(parse_version("version 7"), None);
+    }
+}
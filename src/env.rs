@@ -0,0 +1,106 @@
+use crate::backend::Backend;
+use crate::config::{self, Config, Source};
+use crate::error::Result;
+use crate::ninja;
+use crate::toolchain::{Sanitizer, Toolchain};
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// The configuration eos generates build files with, after merging the
+/// defaults, eos.toml and the command line. Written as TOML or JSON.
+#[derive(Serialize)]
+pub struct Env {
+    /// The configuration file, if there is one.
+    pub config: Option<PathBuf>,
+    /// The source tree being built.
+    pub source_root: PathBuf,
+    /// The directory build outputs are placed under.
+    pub output_dir: PathBuf,
+    /// The directory proto areas are placed under.
+    pub proto_dir: PathBuf,
+    /// The flag profile in use, if any.
+    pub profile: Option<String>,
+    /// The sanitizer kernel objects are instrumented with, if any.
+    pub sanitize: Option<Sanitizer>,
+    /// Files ninja keeps between builds to track what it has done.
+    pub ninja_state: Vec<PathBuf>,
+    /// Top level ninja variables set on the command line.
+    pub defines: BTreeMap<String, String>,
+    /// The programs used to build the tree.
+    pub toolchain: Toolchain,
+    /// What each architecture is built with.
+    pub arch: BTreeMap<String, ArchEnv>,
+    /// Where each setting came from: the built-in default, eos.toml, the
+    /// environment or the command line.
+    pub sources: BTreeMap<&'static str, Source>,
+    /// Whether to write JSON instead of TOML.
+    #[serde(skip)]
+    pub json: bool,
+}
+
+/// The output directory and flags of one architecture.
+#[derive(Serialize)]
+pub struct ArchEnv {
+    /// The directory build outputs are placed under.
+    pub bld: PathBuf,
+    /// The proto area built files are installed into.
+    pub proto: PathBuf,
+    /// Flags kernel objects are compiled with.
+    pub kernel_cflags: Vec<String>,
+    /// Flags kernel components are linked with.
+    pub kernel_ldflags: Vec<String>,
+}
+
+impl Env {
+    /// Resolve the configuration for building with `config` and the top
+    /// level variables in `defines`.
+    pub fn new(
+        config: &Config,
+        defines: &[(String, String)],
+        json: bool,
+    ) -> Result<Env> {
+        let path = Path::new(config::FILE_NAME);
+        let arches = config.arches();
+        let multiarch = arches.len() > 1;
+        let mut arch = BTreeMap::new();
+        for x in arches {
+            let target = config.target(x, multiarch)?;
+            let env = ArchEnv {
+                kernel_cflags: ninja::Spec::kernel_cflags(&target),
+                kernel_ldflags: ninja::Spec::kernel_ldflags(&target),
+                bld: target.bld,
+                proto: target.proto,
+            };
+            arch.insert(x.to_string(), env);
+        }
+        Ok(Env {
+            config: path.exists().then(|| path.to_owned()),
+            source_root: config.source_root().to_owned(),
+            output_dir: config.output_dir().to_owned(),
+            proto_dir: config.proto_dir().to_owned(),
+            profile: config.profile.clone(),
+            sanitize: config.profile()?.and_then(|x| x.sanitize),
+            ninja_state: vec![".ninja_log".into(), ".ninja_deps".into()],
+            defines: defines.iter().cloned().collect(),
+            toolchain: config.toolchain(),
+            arch,
+            sources: config::SETTINGS
+                .iter()
+                .map(|x| (*x, config.source(x)))
+                .collect(),
+            json,
+        })
+    }
+}
+
+impl Backend for Env {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        if self.json {
+            serde_json::to_writer_pretty(&mut *w, self)?;
+            return Ok(writeln!(w)?);
+        }
+        Ok(w.write_all(toml::to_string(self)?.as_bytes())?)
+    }
+}
@@ -0,0 +1,92 @@
+use crate::diagnostic::Diagnostic;
+
+/// The ways eos can fail. Each kind of failure has its own exit status so
+/// scripts can tell them apart.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The command line asked for something that cannot be done.
+    #[error("{0}")]
+    Usage(String),
+    /// A build spec or the configuration could not be parsed.
+    #[error("{0}")]
+    Parse(Diagnostic),
+    /// The build specs do not describe a valid build.
+    #[error("{0}")]
+    Invalid(Diagnostic),
+    /// A program eos runs, such as the compiler or ninja, failed.
+    #[error("{0}")]
+    Toolchain(String),
+    /// Reading or writing a file failed.
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// The command ran but did not succeed, e.g. a build file is stale.
+    #[error("{0}")]
+    Failed(String),
+    /// Several build specs could not be parsed or are invalid, each error
+    /// reported on its own.
+    #[error("{} build spec errors found", .0.len())]
+    Many(Vec<Error>),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// The exit status for this error. Usage errors share clap's status for
+    /// bad arguments.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Failed(_) => 1,
+            Error::Usage(_) => 2,
+            Error::Parse(_) => 3,
+            Error::Invalid(_) => 4,
+            Error::Toolchain(_) => 5,
+            Error::Io(_) => 6,
+            Error::Many(errors) => errors[0].exit_code(),
+        }
+    }
+
+    /// Fail with all of `errors` if there are any.
+    pub fn collect(mut errors: Vec<Error>) -> Result<()> {
+        match errors.len() {
+            0 => Ok(()),
+            1 => Err(errors.remove(0)),
+            _ => Err(Error::Many(errors)),
+        }
+    }
+
+    /// The diagnostics of each error this is made of.
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            Error::Many(errors) => {
+                errors.iter().flat_map(|x| x.diagnostics()).collect()
+            }
+            _ => vec![self.diagnostic()],
+        }
+    }
+
+    /// This error as a diagnostic, with the file it is about if known.
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self {
+            Error::Parse(d) | Error::Invalid(d) => d.clone(),
+            _ => Diagnostic::general(self.to_string()),
+        }
+    }
+}
+
+impl From<Diagnostic> for Error {
+    fn from(d: Diagnostic) -> Error {
+        Error::Invalid(d)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Io(e.into())
+    }
+}
+
+impl From<toml::ser::Error> for Error {
+    fn from(e: toml::ser::Error) -> Error {
+        Error::Invalid(Diagnostic::general(e.to_string()))
+    }
+}
@@ -0,0 +1,216 @@
+use crate::arch::Arch;
+use crate::backend::Backend;
+use crate::error::{Error, Result};
+use crate::ninja::{self, BuildStatement, RuleDefinition, Token};
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// The compile command of a source, or the link command of a module, with
+/// each word annotated with where it comes from: the rule, a toolchain
+/// variable, a layer of the kernel flags, the build spec or a `--define`.
+pub struct Explain<'a> {
+    /// The build generated for each architecture, with the layers its
+    /// kernel flags are made of, by variable.
+    pub ninja_specs: Vec<(Arch, &'a ninja::Spec, Layers)>,
+    /// How many top level variables at the end of each build were defined
+    /// on the command line.
+    pub defines: usize,
+    /// The source or module to explain.
+    pub path: String,
+}
+
+/// The runs of flags a top level variable is built from, with where they
+/// come from, by variable name.
+pub type Layers = BTreeMap<&'static str, Vec<(String, Vec<String>)>>;
+
+/// The words of an evaluated command, each with where it comes from.
+#[derive(Default)]
+struct Words {
+    words: Vec<(String, String)>,
+    /// Whether the next text continues the last word.
+    joined: bool,
+}
+
+impl Words {
+    /// Add `text`, which comes from `origin`. Text that does not start with
+    /// whitespace continues the last word, which keeps its origin.
+    fn push(&mut self, text: &str, origin: &str) {
+        if text.is_empty() {
+            return;
+        }
+        let mut parts = text.split_whitespace();
+        if self.joined && !text.starts_with(char::is_whitespace) {
+            if let (Some(first), Some(last)) =
+                (parts.next(), self.words.last_mut())
+            {
+                last.0 += first;
+            }
+        }
+        self.words
+            .extend(parts.map(|x| (x.to_owned(), origin.to_owned())));
+        self.joined = !text.ends_with(char::is_whitespace);
+    }
+
+    /// Add `words`, each on its own, from `origin`.
+    fn extend(&mut self, words: &[String], origin: &str) {
+        self.joined = false;
+        for word in words {
+            self.push(word, origin);
+            self.joined = false;
+        }
+    }
+}
+
+/// The scope variable references in a piece of text are looked up in.
+#[derive(Clone, Copy)]
+enum Scope {
+    /// The command of the rule, which sees the statement's variables.
+    Rule,
+    /// A variable of the statement or rule, which sees the top level.
+    Statement,
+    /// A top level variable, which sees the top level variables defined
+    /// before it.
+    Top(usize),
+}
+
+/// Evaluates the command of one build statement, keeping track of where
+/// each word comes from.
+struct Evaluator<'a> {
+    spec: &'a ninja::Spec,
+    stmt: &'a BuildStatement,
+    rule: &'a RuleDefinition,
+    layers: &'a Layers,
+    /// The index of the first top level variable defined on the command
+    /// line.
+    defines: usize,
+}
+
+impl Evaluator<'_> {
+    /// Add the words of `text`, whose literal parts come from `origin`, with
+    /// variable references looked up in `scope`.
+    fn evaluate(&self, text: &str, scope: Scope, origin: &str, w: &mut Words) {
+        for token in ninja::tokenize(text) {
+            match token {
+                Token::Literal(x) => w.push(&x, origin),
+                Token::Variable(name) => self.variable(&name, scope, w),
+            }
+        }
+    }
+
+    /// Add the value of the variable `name` as seen from `scope`.
+    fn variable(&self, name: &str, scope: Scope, w: &mut Words) {
+        if let Scope::Rule = scope {
+            match name {
+                "in" => return w.push(&self.stmt.inputs.join(" "), "input"),
+                "out" => return w.push(&self.stmt.output, "output"),
+                _ => (),
+            }
+            let origin = format!("build spec {}", self.stmt.origin_display());
+            if let Some(v) = self.stmt.variables.iter().find(|x| x.name == name)
+            {
+                return self.evaluate(&v.value, Scope::Statement, &origin, w);
+            }
+            if let Some(v) = self.rule.variables.iter().find(|x| x.name == name)
+            {
+                let origin = format!("rule {}", self.rule.name);
+                return self.evaluate(&v.value, Scope::Statement, &origin, w);
+            }
+        }
+        let limit = match scope {
+            Scope::Top(i) => i,
+            _ => self.spec.variables.len(),
+        };
+        let Some(i) = self.spec.variables[..limit]
+            .iter()
+            .rposition(|x| x.name == name)
+        else {
+            return;
+        };
+        let first = self.spec.variables.iter().position(|x| x.name == name);
+        match self.layers.get(name) {
+            Some(layers) if first == Some(i) => {
+                for (origin, flags) in layers {
+                    w.extend(flags, origin);
+                }
+                w.joined = true;
+            }
+            _ => {
+                let origin = match i >= self.defines {
+                    true => "--define",
+                    false => name,
+                };
+                let value = &self.spec.variables[i].value;
+                self.evaluate(value, Scope::Top(i), origin, w);
+            }
+        }
+    }
+}
+
+impl Explain<'_> {
+    /// The statement of `spec` that builds what is being explained: the
+    /// compile edge of a source, or the link edge of a module or genunix.
+    fn statement<'a>(
+        &self,
+        spec: &'a ninja::Spec,
+    ) -> Option<&'a BuildStatement> {
+        let path = self.path.strip_prefix("./").unwrap_or(&self.path);
+        let compile = ninja::Rules::ModCompile.to_string();
+        let link = ninja::Rules::ModLink.to_string();
+        let genunix = ninja::Rules::GenunixLink.to_string();
+        let module = format!("/modules/{}", path);
+        spec.statements
+            .iter()
+            .find(|x| x.rule == compile && x.inputs.iter().any(|x| x == path))
+            .or_else(|| {
+                spec.statements.iter().find(|x| {
+                    (x.rule == link && x.output.ends_with(&module))
+                        || (x.rule == genunix && path == "genunix")
+                })
+            })
+    }
+}
+
+impl Backend for Explain<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        let mut found = false;
+        for (arch, spec, layers) in &self.ninja_specs {
+            let Some(stmt) = self.statement(spec) else {
+                continue;
+            };
+            let Some(rule) = spec.rules.iter().find(|x| x.name == stmt.rule)
+            else {
+                continue;
+            };
+            found = true;
+            let evaluator = Evaluator {
+                spec,
+                stmt,
+                rule,
+                layers,
+                defines: spec.variables.len() - self.defines,
+            };
+            let mut words = Words::default();
+            let origin = format!("rule {}", rule.name);
+            evaluator.evaluate(&rule.command, Scope::Rule, &origin, &mut words);
+
+            writeln!(w, "{} [{}]", stmt.output, arch)?;
+            let width = words
+                .words
+                .iter()
+                .map(|(x, _)| x.len())
+                .max()
+                .unwrap_or(0)
+                .min(40);
+            for (word, origin) in &words.words {
+                writeln!(w, "  {:<width$}  {}", word, origin)?;
+            }
+        }
+        if !found {
+            return Err(Error::Usage(format!(
+                "{}: not a source or module of any build spec",
+                self.path
+            )));
+        }
+        Ok(())
+    }
+}
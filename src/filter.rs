@@ -0,0 +1,148 @@
+use crate::error::{Error, Result};
+use crate::spec::Spec;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+/// Restrict `specs` to the modules named in `only` and the specs under any of
+/// the `subtrees`, plus the modules they depend on and genunix, which every
+/// module links against. If neither is given all specs are kept.
+pub fn select<'a>(
+    specs: &'a [(PathBuf, Spec)],
+    only: &[String],
+    subtrees: &[PathBuf],
+) -> Result<Vec<&'a (PathBuf, Spec)>> {
+    if only.is_empty() && subtrees.is_empty() {
+        return Ok(specs.iter().collect());
+    }
+
+    let mut selected = BTreeSet::new();
+    for name in only {
+        match specs.iter().position(|(_, x)| module_name(x) == Some(name)) {
+            Some(i) => selected.insert(i),
+            None => {
+                return Err(Error::Usage(format!("{}: no such module", name)))
+            }
+        };
+    }
+    for subtree in subtrees {
+        let subtree = subtree.strip_prefix(".").unwrap_or(subtree);
+        let before = selected.len();
+        selected.extend(
+            specs
+                .iter()
+                .enumerate()
+                .filter(|(_, (path, _))| path.starts_with(subtree))
+                .map(|(i, _)| i),
+        );
+        if selected.len() == before {
+            return Err(Error::Usage(format!(
+                "{}: no build specs found",
+                subtree.display()
+            )));
+        }
+    }
+
+    // Follow module dependencies until nothing new is added. Dependencies not
+    // yet described by a build spec are skipped.
+    let mut pending: Vec<usize> = selected.iter().copied().collect();
+    while let Some(i) = pending.pop() {
+        let Some(module) = &specs[i].1.module else {
+            continue;
+        };
+        for name in module.dependency_names() {
+            for (j, (_, spec)) in specs.iter().enumerate() {
+                if module_name(spec).is_some_and(|x| x == name)
+                    && selected.insert(j)
+                {
+                    pending.push(j);
+                }
+            }
+        }
+    }
+    if selected.iter().any(|&i| specs[i].1.module.is_some()) {
+        selected.extend(
+            specs
+                .iter()
+                .enumerate()
+                .filter(|(_, (_, x))| x.genunix.is_some())
+                .map(|(i, _)| i),
+        );
+    }
+
+    Ok(specs
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| selected.contains(i))
+        .map(|(_, x)| x)
+        .collect())
+}
+
+/// The name of the module described by `spec`, if any.
+fn module_name(spec: &Spec) -> Option<&String> {
+    spec.module.as_ref().map(|x| &x.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The build specs of a small tree: genunix, `mac`, and `foo`, which
+    /// depends on `mac`.
+    fn specs() -> Vec<(PathBuf, Spec)> {
+        [
+            ("uts/build.toml", "[genunix]\nsrc = [\"os/main.c\"]\n"),
+            (
+                "uts/io/foo/build.toml",
+                "[module]\nname = \"foo\"\nsrc = [\"foo.c\"]\n\
+                 dependencies = [\"misc/mac\"]\n",
+            ),
+            (
+                "uts/io/mac/build.toml",
+                "[module]\nname = \"mac\"\nsrc = [\"mac.c\"]\n",
+            ),
+        ]
+        .into_iter()
+        .map(|(path, data)| (path.into(), toml::from_str(data).unwrap()))
+        .collect()
+    }
+
+    /// The paths of the specs `select` keeps.
+    fn selected(only: &[&str], subtrees: &[&str]) -> Result<Vec<PathBuf>> {
+        let specs = specs();
+        let only: Vec<String> = only.iter().map(|x| x.to_string()).collect();
+        let subtrees: Vec<PathBuf> =
+            subtrees.iter().map(PathBuf::from).collect();
+        Ok(select(&specs, &only, &subtrees)?
+            .into_iter()
+            .map(|(path, _)| path.clone())
+            .collect())
+    }
+
+    #[test]
+    fn select_keeps_everything_without_filters() {
+        assert_eq!(selected(&[], &[]).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn select_adds_dependencies_and_genunix() {
+        assert_eq!(
+            selected(&["foo"], &[]).unwrap(),
+            [
+                "uts/build.toml",
+                "uts/io/foo/build.toml",
+                "uts/io/mac/build.toml"
+            ]
+            .map(PathBuf::from)
+        );
+        assert_eq!(
+            selected(&[], &["./uts/io/mac"]).unwrap(),
+            ["uts/build.toml", "uts/io/mac/build.toml"].map(PathBuf::from)
+        );
+    }
+
+    #[test]
+    fn select_rejects_unknown_modules_and_empty_subtrees() {
+        assert!(matches!(selected(&["bar"], &[]), Err(Error::Usage(_))));
+        assert!(matches!(selected(&[], &["uts/fs"]), Err(Error::Usage(_))));
+    }
+}
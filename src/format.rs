@@ -0,0 +1,181 @@
+use crate::diagnostic::{Diagnostic, Span};
+use crate::error::{Error, Result};
+use crate::lint;
+use std::path::Path;
+use toml_edit::{Array, DocumentMut, Item, RawString, Table, Value};
+
+/// The widest an array is written on one line, including its key.
+const WIDTH: usize = 80;
+
+/// The arrays whose order does not matter, which are sorted.
+const SORTED: &[&str] = &["src", "dependencies"];
+
+/// Rewrite `text`, the contents of the build spec at `path`, in the
+/// canonical style: tables and their keys in the order the fields of a build
+/// spec are documented in, source and dependency lists sorted, and arrays on
+/// one line when they fit. Comments are kept.
+pub fn format(path: &Path, text: &str) -> Result<String> {
+    let mut doc: DocumentMut =
+        text.parse().map_err(|e: toml_edit::TomlError| {
+            let mut d = Diagnostic::error(path, e.message());
+            if let Some(span) = e.span() {
+                let before = &text[..span.start];
+                d.span = Some(Span {
+                    line: before.matches('\n').count() + 1,
+                    column: before.len()
+                        - before.rfind('\n').map_or(0, |x| x + 1)
+                        + 1,
+                });
+            }
+            Error::Parse(d)
+        })?;
+    let mut first = !doc.iter().any(|(_, x)| x.is_value());
+    visit(doc.as_table_mut(), "", &mut 0, &mut first);
+    Ok(doc.to_string())
+}
+
+/// The fields of a `kind` of table, in canonical order.
+fn fields(kind: &str) -> &'static [&'static str] {
+    match kind {
+        "rule" => &["command", "description"],
+        "arch" => &["src"],
+        "test" => &["command", "needs"],
+        _ => lint::FIELDS
+            .iter()
+            .find(|(x, _)| *x == kind)
+            .map_or(&[], |(_, x)| *x),
+    }
+}
+
+/// The kind of the table under `key` in a `kind` of table.
+fn child(kind: &str, key: &str) -> String {
+    match (kind, key) {
+        ("", key) => key.to_owned(),
+        ("rules", _) => "rule".to_owned(),
+        ("tests", _) => "test".to_owned(),
+        ("module" | "genunix", "arch") => "arches".to_owned(),
+        ("arches", _) => "arch".to_owned(),
+        _ => format!("{}.{}", kind, key),
+    }
+}
+
+/// Put the keys of `table`, a `kind` of table, in canonical order, number
+/// its sub-tables in that order starting at `position` and format its
+/// arrays. `first` is cleared once a table header has been written.
+fn visit(
+    table: &mut Table,
+    kind: &str,
+    position: &mut isize,
+    first: &mut bool,
+) {
+    let fields = fields(kind);
+    let rank = |key: &str| {
+        fields
+            .iter()
+            .position(|x| *x == key)
+            .unwrap_or(fields.len())
+    };
+    table.sort_values_by(|a, _, b, _| rank(a).cmp(&rank(b)));
+
+    for (mut key, item) in table.iter_mut() {
+        let child = child(kind, key.get());
+        if let Item::Value(x) = item {
+            key.leaf_decor_mut().set_suffix(" ");
+            x.decor_mut().set_prefix(" ");
+        }
+        match item {
+            Item::Table(x) => {
+                if !x.is_dotted() && !x.is_implicit() {
+                    header(x, position, first);
+                }
+                visit(x, &child, position, first);
+            }
+            Item::ArrayOfTables(x) => {
+                for x in x.iter_mut() {
+                    header(x, position, first);
+                    visit(x, &child, position, first);
+                }
+            }
+            Item::Value(Value::Array(x)) => {
+                if SORTED.contains(&key.get()) {
+                    sort(x);
+                }
+                layout(x, key.get().len());
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Give the table header of `table` the next `position`, and a blank line
+/// before it unless it is the `first` thing in the file.
+fn header(table: &mut Table, position: &mut isize, first: &mut bool) {
+    table.set_position(Some(*position));
+    *position += 1;
+    let decor = table.decor_mut();
+    let mut prefix = if *first { "" } else { "\n" }.to_owned();
+    for comment in comments(decor.prefix()) {
+        prefix += &format!("{}\n", comment);
+    }
+    decor.set_prefix(prefix);
+    *first = false;
+}
+
+/// Sort the string values of `array`, keeping any comments with the value
+/// they precede.
+fn sort(array: &mut Array) {
+    let mut values: Vec<Value> = array.iter().cloned().collect();
+    values.sort_by(|a, b| a.as_str().cmp(&b.as_str()));
+    let trailing = array.trailing().clone();
+    let comma = array.trailing_comma();
+    array.clear();
+    for value in values {
+        array.push_formatted(value);
+    }
+    array.set_trailing(trailing);
+    array.set_trailing_comma(comma);
+}
+
+/// Write `array`, the value of a key `indent` wide, on one line if it fits
+/// and has no comments, or one value per line otherwise.
+fn layout(array: &mut Array, indent: usize) {
+    let commented = text(Some(array.trailing())).contains('#')
+        || array.iter().any(|x| {
+            text(x.decor().prefix()).contains('#')
+                || text(x.decor().suffix()).contains('#')
+        });
+    if !commented {
+        array.fmt();
+        if indent + " = ".len() + array.to_string().trim().len() <= WIDTH {
+            return;
+        }
+    }
+    for value in array.iter_mut() {
+        let decor = value.decor_mut();
+        let mut prefix = String::new();
+        for comment in comments(decor.prefix()) {
+            prefix += &format!("\n    {}", comment);
+        }
+        decor.set_prefix(prefix + "\n    ");
+        decor.set_suffix("");
+    }
+    let mut trailing = String::new();
+    for comment in comments(Some(array.trailing())) {
+        trailing += &format!("\n    {}", comment);
+    }
+    array.set_trailing_comma(true);
+    array.set_trailing(trailing + "\n");
+}
+
+/// The comments in the whitespace `x`.
+fn comments(x: Option<&RawString>) -> Vec<&str> {
+    text(x)
+        .lines()
+        .map(|x| x.trim())
+        .filter(|x| x.starts_with('#'))
+        .collect()
+}
+
+fn text(x: Option<&RawString>) -> &str {
+    x.and_then(|x| x.as_str()).unwrap_or("")
+}
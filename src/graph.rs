@@ -0,0 +1,23 @@
+use crate::arch::Arch;
+use crate::backend::Backend;
+use crate::error::Result;
+use crate::ninja;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+/// A JSON serialization of the full build graph of a set of ninja build
+/// specifications, keyed by architecture, for consumption by external
+/// analysis tools.
+pub struct Graph<'a> {
+    /// The ninja build specifications to serialize.
+    pub specs: &'a [(Arch, ninja::Spec)],
+}
+
+impl Backend for Graph<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        let specs: BTreeMap<&Arch, &ninja::Spec> =
+            self.specs.iter().map(|(arch, spec)| (arch, spec)).collect();
+        serde_json::to_writer_pretty(&mut *w, &specs)?;
+        Ok(writeln!(w)?)
+    }
+}
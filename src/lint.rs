@@ -0,0 +1,248 @@
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::spec::Spec;
+use crate::upgrade;
+use crate::util;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The fields each table of a build spec may have. Rule tables are absent as
+/// any field of a rule is a rule variable, and so are test tables, which are
+/// checked when they are parsed.
+pub const FIELDS: &[(&str, &[&str])] = &[
+    (
+        "",
+        &[
+            "spec_version",
+            "genunix",
+            "module",
+            "generate",
+            "raw",
+            "rules",
+            "build",
+            "headers",
+            "package",
+            "tests",
+            "ctf",
+        ],
+    ),
+    (
+        "module",
+        &[
+            "name",
+            "src",
+            "dependencies",
+            "arch",
+            "class",
+            "conf",
+            "sanitize",
+            "werror",
+            "werror_exempt",
+            "cflags",
+            "remove_cflags",
+            "files",
+        ],
+    ),
+    (
+        "genunix",
+        &[
+            "src",
+            "arch",
+            "sanitize",
+            "sanitizer_src",
+            "werror",
+            "werror_exempt",
+            "cflags",
+            "remove_cflags",
+            "files",
+        ],
+    ),
+    (
+        "generate",
+        &["command", "rule", "inputs", "outputs", "variables"],
+    ),
+    ("raw", &["text"]),
+    ("ctf", &["label", "convert_flags", "merge_flags"]),
+    ("build", &["rule", "inputs", "outputs", "variables"]),
+    ("headers", &["dir", "src"]),
+    (
+        "package",
+        &["name", "summary", "description", "dependencies"],
+    ),
+];
+
+/// The result of checking a set of build specs. Errors are problems that make
+/// the build specs unusable, warnings are likely mistakes that do not stop eos
+/// from generating a build.
+#[derive(Default)]
+pub struct Report {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Report {
+    fn error(&mut self, path: &Path, message: String) {
+        self.diagnostics.push(Diagnostic::error(path, message));
+    }
+
+    /// The number of errors found.
+    pub fn errors(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|x| x.severity == Severity::Error)
+            .count()
+    }
+}
+
+/// Read the build specs at `paths`, checking each for fields eos does not
+/// know. Specs that cannot be read are reported rather than returned.
+pub fn read(paths: Vec<PathBuf>, report: &mut Report) -> Vec<(PathBuf, Spec)> {
+    let mut specs = Vec::new();
+    for path in paths {
+        let data = match std::fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                report.error(&path, e.to_string());
+                continue;
+            }
+        };
+        if let Err(e) = upgrade::check(&path, &data) {
+            report.diagnostics.push(e.diagnostic());
+            continue;
+        }
+        let unknown = unknown_fields(&path, &data);
+        if !unknown.is_empty() {
+            report.diagnostics.extend(unknown);
+            continue;
+        }
+        match toml::from_str(&data) {
+            Ok(spec) => specs.push((path, spec)),
+            Err(e) => {
+                report.diagnostics.push(Diagnostic::toml(&path, &data, &e))
+            }
+        }
+    }
+    specs
+}
+
+/// The fields of the build spec at `path`, which holds `data`, that eos does
+/// not know, each with the field it is closest to. A build spec that is not
+/// valid TOML has none, leaving the parser to report it.
+pub fn unknown_fields(path: &Path, data: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    if let Ok(doc) = toml_edit::Document::parse(data) {
+        check_fields(path, data, "", doc.as_table(), &mut diagnostics);
+    }
+    diagnostics
+}
+
+/// Report the fields of `entry`, a `table` section of a build spec, that eos
+/// does not know.
+fn check_fields(
+    path: &Path,
+    data: &str,
+    table: &str,
+    entry: &dyn toml_edit::TableLike,
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let Some((_, fields)) = FIELDS.iter().find(|(name, _)| *name == table)
+    else {
+        return;
+    };
+    for (key, item) in entry.iter() {
+        if fields.contains(&key) {
+            if table.is_empty() {
+                for x in tables(item) {
+                    check_fields(path, data, key, x, diagnostics);
+                }
+            }
+            continue;
+        }
+        let name = match table {
+            "" => key.to_owned(),
+            _ => format!("{}.{}", table, key),
+        };
+        let mut message = format!("`{}` is not a field", name);
+        if let Some(x) = util::did_you_mean(key, fields.iter().copied()) {
+            message.push_str(&format!("; did you mean `{}`?", x));
+        }
+        diagnostics.push(match entry.key(key).and_then(|x| x.span()) {
+            Some(span) => Diagnostic::at(path, data, span.start, message),
+            None => Diagnostic::error(path, message),
+        });
+    }
+}
+
+/// The tables `item` holds: itself if it is one, or those of an array of
+/// them.
+fn tables(item: &toml_edit::Item) -> Vec<&dyn toml_edit::TableLike> {
+    match item {
+        toml_edit::Item::ArrayOfTables(x) => {
+            x.iter().map(|x| x as &dyn toml_edit::TableLike).collect()
+        }
+        toml_edit::Item::Value(toml_edit::Value::Array(x)) => x
+            .iter()
+            .filter_map(|x| x.as_inline_table())
+            .map(|x| x as &dyn toml_edit::TableLike)
+            .collect(),
+        _ => item.as_table_like().into_iter().collect(),
+    }
+}
+
+/// Check the source files, module names and dependencies of `specs`.
+pub fn check(specs: &[(PathBuf, Spec)], report: &mut Report) {
+    let mut modules: BTreeMap<&str, &Path> = BTreeMap::new();
+    let mut genunix: Option<&Path> = None;
+    for (path, spec) in specs {
+        for src in spec.sources(None) {
+            if !path.with_file_name(src).exists() {
+                report.error(path, format!("source {} does not exist", src));
+            }
+        }
+
+        if spec.genunix.is_some() {
+            if let Some(prev) = genunix.replace(path) {
+                report.error(
+                    path,
+                    format!("genunix is also described by {}", prev.display()),
+                );
+            }
+        }
+        if let Some(module) = &spec.module {
+            if let Some(prev) = modules.insert(&module.name, path) {
+                report.error(
+                    path,
+                    format!(
+                        "module {} is also described by {}",
+                        module.name,
+                        prev.display()
+                    ),
+                );
+            }
+        }
+    }
+
+    for (path, spec) in specs {
+        let Some(module) = &spec.module else {
+            continue;
+        };
+        let mut seen = Vec::new();
+        for (dep, name) in
+            module.dependencies.iter().zip(module.dependency_names())
+        {
+            if !dep.contains('/') || name.is_empty() {
+                report.error(
+                    path,
+                    format!(
+                        "dependency {} should be a module path such as \
+                        misc/mac",
+                        dep
+                    ),
+                );
+            } else if name == module.name {
+                report.error(path, format!("{} depends on itself", dep));
+            } else if seen.contains(&dep) {
+                report.error(path, format!("dependency {} is repeated", dep));
+            }
+            seen.push(dep);
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use crate::backend::Backend;
+use crate::error::Result;
+use crate::spec::Spec;
+use serde_derive::Serialize;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// An inventory of the build specs in a tree, one line per spec or as JSON.
+pub struct List<'a> {
+    /// The build specs to list.
+    pub specs: Vec<&'a (PathBuf, Spec)>,
+    /// Whether to write JSON instead of text.
+    pub json: bool,
+}
+
+/// What a single build spec describes.
+#[derive(Serialize)]
+struct Entry<'a> {
+    path: &'a PathBuf,
+    kind: &'static str,
+    name: Option<&'a str>,
+    sources: usize,
+    dependencies: &'a [String],
+}
+
+impl<'a> Entry<'a> {
+    fn new(path: &'a PathBuf, spec: &'a Spec) -> Entry<'a> {
+        let (kind, name, sources, dependencies) =
+            match (&spec.module, &spec.genunix) {
+                (Some(x), _) => (
+                    "module",
+                    Some(x.name.as_str()),
+                    spec.sources(None).len(),
+                    &x.dependencies[..],
+                ),
+                (None, Some(_)) => {
+                    let sources = spec.sources(None).len();
+                    ("genunix", Some("genunix"), sources, &[][..])
+                }
+                (None, None) => ("other", None, 0, &[][..]),
+            };
+        Entry {
+            path,
+            kind,
+            name,
+            sources,
+            dependencies,
+        }
+    }
+}
+
+impl Backend for List<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        let entries: Vec<Entry> = self
+            .specs
+            .iter()
+            .map(|(path, spec)| Entry::new(path, spec))
+            .collect();
+        if self.json {
+            serde_json::to_writer_pretty(&mut *w, &entries)?;
+            return Ok(writeln!(w)?);
+        }
+
+        let width = entries
+            .iter()
+            .map(|x| x.path.to_string_lossy().len())
+            .max()
+            .unwrap_or(0);
+        for entry in &entries {
+            let line = format!(
+                "{:width$}  {:7}  {:16}  {:3} src  {}",
+                entry.path.display(),
+                entry.kind,
+                entry.name.unwrap_or("-"),
+                entry.sources,
+                entry.dependencies.join(" "),
+            );
+            writeln!(w, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+use crate::error::{Error, Result};
+use std::fs::{File, TryLockError};
+use std::io::{Read, Seek, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::Command;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// The lock file, at the root of the workspace, that eos holds while it
+/// generates, holding the name of its host and its process ID.
+pub const FILE_NAME: &str = ".eos-lock";
+
+/// The lock on the workspace that keeps two runs of eos, e.g. `eos watch`
+/// and a manual `eos generate`, from writing build files and manifests at the
+/// same time. It is released when dropped, or when eos exits however it
+/// does.
+pub struct Lock {
+    _file: File,
+}
+
+impl Lock {
+    /// Take the lock, waiting for the run of eos that holds it to finish.
+    /// A lock whose holder is no longer running, e.g. on a file system that
+    /// keeps locks after their holder's death, is removed first. Only locks
+    /// taken on this host can be told to be left behind: on a shared file
+    /// system, the holder may be running on another.
+    pub fn acquire() -> Result<Lock> {
+        let path = Path::new(FILE_NAME);
+        let host = hostname();
+        let mut file = open(path)?;
+        // The dead holder seen last, whose lock is only taken to be left
+        // behind when it is still there a moment later, as a run that has
+        // just taken it may not have written its process ID yet.
+        let mut dead = None;
+        loop {
+            match file.try_lock() {
+                Ok(()) => {}
+                Err(TryLockError::WouldBlock) => match holder(&mut file) {
+                    Some(x) if x.left_behind(&host) && dead == Some(x.pid) => {
+                        // Another run may have removed it first, and taken
+                        // the lock on a new one.
+                        if same_file(path, &file) {
+                            warn!(
+                                "removing {}, which eos (pid {}) left behind",
+                                path.display(),
+                                x.pid
+                            );
+                            std::fs::remove_file(path)?;
+                        }
+                        file = open(path)?;
+                        continue;
+                    }
+                    Some(x) if x.left_behind(&host) => {
+                        dead = Some(x.pid);
+                        std::thread::sleep(Duration::from_secs(1));
+                        continue;
+                    }
+                    x => {
+                        info!(
+                            "waiting for eos{}, which is generating in this \
+                             workspace",
+                            x.map(|x| format!(
+                                " (pid {} on {})",
+                                x.pid, x.host
+                            ))
+                            .unwrap_or_default()
+                        );
+                        file.lock()?;
+                    }
+                },
+                Err(TryLockError::Error(e)) => {
+                    return Err(Error::Io(std::io::Error::new(
+                        e.kind(),
+                        format!("cannot lock {}: {}", path.display(), e),
+                    )))
+                }
+            }
+            // The lock file may have been removed as left behind while
+            // this run waited, in which case its lock keeps no one out.
+            if same_file(path, &file) {
+                break;
+            }
+            file = open(path)?;
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}:{}", host, std::process::id())?;
+        Ok(Lock { _file: file })
+    }
+}
+
+/// The run of eos that holds a lock, as it wrote to the lock file.
+struct Holder {
+    /// The name of the host it runs on.
+    host: String,
+    /// Its process ID.
+    pid: u32,
+}
+
+impl Holder {
+    /// Whether the lock was left behind by a run on `host` that is no
+    /// longer running.
+    fn left_behind(&self, host: &str) -> bool {
+        self.host == host && !running(self.pid)
+    }
+}
+
+/// Open the lock file at `path`, creating it if it does not exist.
+fn open(path: &Path) -> Result<File> {
+    Ok(File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?)
+}
+
+/// The holder of the lock `file`, if it has written itself to it.
+fn holder(file: &mut File) -> Option<Holder> {
+    let mut text = String::new();
+    file.rewind().ok()?;
+    file.read_to_string(&mut text).ok()?;
+    let (host, pid) = text.trim().rsplit_once(':')?;
+    Some(Holder {
+        host: host.to_owned(),
+        pid: pid.parse().ok()?,
+    })
+}
+
+/// Whether `path` is still the lock file `file` was opened as.
+fn same_file(path: &Path, file: &File) -> bool {
+    match (std::fs::metadata(path), file.metadata()) {
+        (Ok(x), Ok(y)) => x.dev() == y.dev() && x.ino() == y.ino(),
+        _ => false,
+    }
+}
+
+/// The name of this host, as `uname -n` reports it.
+fn hostname() -> String {
+    Command::new("uname")
+        .arg("-n")
+        .output()
+        .ok()
+        .and_then(|x| String::from_utf8(x.stdout).ok())
+        .map(|x| x.trim().to_owned())
+        .unwrap_or_default()
+}
+
+/// Whether the process `pid` is running, which both illumos and Linux list
+/// under `/proc`.
+fn running(pid: u32) -> bool {
+    !Path::new("/proc").is_dir()
+        || Path::new(&format!("/proc/{}", pid)).exists()
+}
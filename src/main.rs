@@ -1,38 +1,1411 @@
-#![feature(dir_entry_ext2)]
-
-use clap::Parser;
-use colored::*;
-use std::io::Result;
-use std::path::Path;
+use clap::{CommandFactory, Parser};
+use cli::{
+    Args, BackendKind, BuildArgs, Command, GenerateArgs, GraphFormat,
+    MessageFormat, NewKind, TestArgs, WatchArgs,
+};
+use error::{Error, Result};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use tracing::{debug, error, info, warn};
 
+mod arch;
+mod backend;
+mod check;
+mod clean;
+mod cli;
+mod compdb;
+mod config;
+mod deps;
+mod diagnostic;
+mod diff;
+mod doctor;
+mod env;
+mod error;
+mod explain;
+mod filter;
+mod format;
+mod graph;
+mod lint;
+mod list;
+mod lock;
+mod make;
+mod manifest;
+mod migrate;
 mod ninja;
+mod package;
+mod probe;
+mod report;
+mod scaffold;
+mod scan;
 mod spec;
+mod stats;
+mod symbols;
+mod target;
+mod test;
+mod toolchain;
+mod upgrade;
 mod util;
+mod walk;
+mod watch;
+mod why;
 
+/// The label CTF data is given unless eos.toml sets another.
 const VERSION: &str = "5.11";
 
-#[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {}
-
 fn main() {
-    let _args = Args::parse();
+    let mut args = Args::parse();
+    report::init(&args);
+
+    if let Err(e) = enter_workspace(&mut args).and_then(|x| run(&args, &x)) {
+        report::error(&args, &e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+/// Change to the root of the workspace eos is run in, if it is run in a
+/// subdirectory of one, so that paths are relative to the root. Returns the
+/// directory eos was run in, relative to where it now runs.
+fn enter_workspace(args: &mut Args) -> Result<PathBuf> {
+    let dir = std::env::current_dir()?;
+    let Some(root) = config::workspace_root(&dir) else {
+        return Ok(PathBuf::new());
+    };
+    let relative = dir.strip_prefix(root).unwrap().to_owned();
+    if root != dir {
+        std::env::set_current_dir(root)?;
+        args.rebase(&relative);
+        debug!("running in workspace {}", root.display());
+    }
+    Ok(relative)
+}
+
+/// Run the command `args` asks for, in the workspace, where `dir` is the
+/// directory eos was run in.
+fn run(args: &Args, dir: &Path) -> Result<()> {
+    if let Some(jobs) = args.scan_jobs {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(jobs.get())
+            .build_global()
+            .map_err(|e| {
+                Error::Failed(format!("cannot start scanning: {}", e))
+            })?;
+    }
+    let config = &load_config(args, dir)?;
+
+    // Commands that generate hold the lock until they finish, and so does
+    // clean, which removes what they write; those that go on to run ninja
+    // only hold it while regenerating.
+    let _lock = match &args.command {
+        Some(
+            Command::Generate(_)
+            | Command::Check(_)
+            | Command::Diff { .. }
+            | Command::Package { .. }
+            | Command::Deps { .. }
+            | Command::Stats { .. }
+            | Command::Why { .. }
+            | Command::Compdb { .. }
+            | Command::Dump { .. }
+            | Command::Clean { .. },
+        )
+        | None => Some(lock::Lock::acquire()?),
+        _ => None,
+    };
+
+    match &args.command {
+        Some(Command::Generate(gen)) => emit(gen, &generate(args, config)?),
+        Some(Command::Check(gen)) => check(gen, &generate(args, config)?),
+        Some(Command::Diff { output }) => diff(
+            output.as_deref().unwrap_or(Path::new("build.ninja")),
+            &generate(args, config)?,
+        ),
+        Some(Command::Build(build_args)) => {
+            build(args, build_args, config, false)
+        }
+        Some(Command::Install(build_args)) => {
+            build(args, build_args, config, true)
+        }
+        Some(Command::Test(test_args)) => test(args, test_args, config),
+        Some(Command::Package {
+            version,
+            publish,
+            publisher,
+        }) => package(args, config, version, publish.as_deref(), publisher),
+        Some(Command::List { json }) => list(args, config, *json),
+        Some(Command::Doctor { ninja }) => doctor(config, ninja),
+        Some(Command::Env { json }) => backend::emit_file(
+            &env::Env::new(config, &args.defines, *json)?,
+            Path::new("-"),
+        ),
+        Some(Command::Graph { format, output }) => {
+            module_graph(args, config, *format, output)
+        }
+        Some(Command::Deps {
+            module,
+            headers,
+            json,
+        }) => deps(args, config, module, *headers, *json),
+        Some(Command::Watch(watch_args)) => {
+            watch(args, watch_args, config, dir)
+        }
+        Some(Command::Lint) => lint(args, config),
+        Some(Command::Fmt { paths, check }) => fmt(config, paths, *check),
+        Some(Command::UpgradeSpec { paths, check }) => {
+            upgrade_spec(config, paths, *check)
+        }
+        Some(Command::Stats { top, json }) => stats(args, config, *top, *json),
+        Some(Command::Why { path }) => backend::emit_file(
+            &why::Why {
+                ninja_specs: &match recorded(args, config)? {
+                    Some(x) => x,
+                    None => generate(args, config)?,
+                },
+                path: path.clone(),
+            },
+            Path::new("-"),
+        ),
+        Some(Command::ExplainFlags { path }) => {
+            explain_flags(args, config, path)
+        }
+        Some(Command::Clean { dry_run, yes }) => clean(config, *dry_run, *yes),
+        Some(Command::New { kind }) => new(kind),
+        Some(Command::Migrate {
+            makefile,
+            fragments,
+            rules,
+            output,
+            force,
+        }) => {
+            migrate(args, makefile, fragments, rules, output.as_deref(), *force)
+        }
+        Some(Command::Completions { shell }) => completions(config, *shell),
+        Some(Command::Compdb { output }) => compdb(
+            &generate(args, config)?,
+            output
+                .as_deref()
+                .unwrap_or(Path::new("compile_commands.json")),
+        ),
+        Some(Command::Dump { graph }) => dump(&generate(args, config)?, *graph),
+        Some(Command::Dyndep {
+            output,
+            objects,
+            roots,
+            cflags,
+        }) => dyndep(
+            &config.toolchain().cc,
+            config.header_deps.unwrap_or_default(),
+            roots,
+            output,
+            objects,
+            cflags,
+        ),
+        Some(Command::CheckSymbols {
+            nm,
+            output,
+            module,
+            providers,
+        }) => {
+            let providers: Vec<&Path> =
+                providers.iter().map(|x| x.as_path()).collect();
+            symbols::check(nm, module, &providers)?;
+            Ok(std::fs::write(output, "")?)
+        }
+        None => emit(&GenerateArgs::default(), &generate(args, config)?),
+    }
+}
+
+/// Read `eos.toml`, and apply the overrides from the environment and then
+/// the command line to it, where `dir` is the directory eos was run in.
+fn load_config(args: &Args, dir: &Path) -> Result<config::Config> {
+    let mut config = config::Config::load(Path::new(config::FILE_NAME))?;
+    let mut env = config::Overrides::from_env()?;
+    env.rebase(dir);
+    config.apply(env, config::Source::Env);
+    config.apply(
+        config::Overrides {
+            source_root: args.source_root.clone(),
+            output_dir: args.output_dir.clone(),
+            proto_dir: args.proto_dir.clone(),
+            arches: args.arches.clone(),
+            profile: match (args.debug, args.coverage) {
+                (true, _) => Some("debug".into()),
+                (_, true) => Some("coverage".into()),
+                _ => args.profile.clone(),
+            },
+            cc: args.compiler.clone(),
+            ctf: args.no_ctf.then_some(false),
+            reproducible: args.reproducible.then_some(true),
+            werror: args.werror.then_some(true),
+            check_symbols: args.check_symbols.then_some(true),
+            header_deps: args.header_deps,
+            strict_walk: args.strict_walk.then_some(true),
+            follow_symlinks: args.follow_symlinks.then_some(true),
+            ..Default::default()
+        },
+        config::Source::Cli,
+    );
+    Ok(config)
+}
+
+/// The header comments placed at the top of generated files.
+fn header() -> Vec<String> {
+    vec![
+        format!(
+            "This file was generated by eos {}. Do not edit.",
+            env!("CARGO_PKG_VERSION")
+        ),
+        format!(
+            "invocation: {}",
+            std::env::args().collect::<Vec<String>>().join(" ")
+        ),
+    ]
+}
+
+/// Read all the build specs in the source tree.
+fn read_specs(config: &config::Config) -> Result<Vec<(PathBuf, spec::Spec)>> {
+    let source_root = config.source_root();
+    let start = Instant::now();
+    let build_files = walk::find_build_files(source_root, &config.walk()?)?;
+    info!(
+        "found {} build specs under {} in {:.2?}",
+        build_files.len(),
+        source_root.display(),
+        start.elapsed()
+    );
 
-    if let Err(e) = run() {
-        eprintln!("{} {}", "error".red(), e);
-        std::process::exit(1);
+    let start = Instant::now();
+    let mut specs = Vec::new();
+    let mut errors = Vec::new();
+    for path in build_files {
+        debug!("parsing {}", path.display());
+        match util::read_spec(&path) {
+            Ok(spec) => specs.push((path, spec)),
+            Err(e) => errors.push(e),
+        }
     }
+    Error::collect(errors)?;
+    info!(
+        "parsed {} build specs in {:.2?}",
+        specs.len(),
+        start.elapsed()
+    );
+    Ok(specs)
 }
 
-fn run() -> Result<()> {
-    let build_files = util::find_build_files(Path::new("usr/src"))?;
-    let mut ninja_spec = ninja::Spec::new();
-    for path in &build_files {
-        ninja_spec
+/// Create a ninja build spec for each requested architecture from the build
+/// specs in the tree.
+fn generate(
+    args: &Args,
+    config: &config::Config,
+) -> Result<Vec<(arch::Arch, ninja::Spec)>> {
+    ninja_specs(args, config, &read_specs(config)?, true)
+}
+
+/// The build statements of each requested architecture as the last run
+/// recorded them in its manifests, if neither the build specs nor anything
+/// they were generated from, eos.toml or eos itself, has changed since, so
+/// that questions about the build can be answered without generating it.
+fn recorded(
+    args: &Args,
+    config: &config::Config,
+) -> Result<Option<Vec<(arch::Arch, ninja::Spec)>>> {
+    if !args.only.is_empty() || !args.subtrees.is_empty() {
+        return Ok(None);
+    }
+    let build_files =
+        walk::find_build_files(config.source_root(), &config.walk()?)?;
+    let exe = std::env::current_exe()?;
+    let exe_modified = util::modified(&exe);
+    let mut inputs = vec![exe];
+    if Path::new(config::FILE_NAME).exists() {
+        inputs.push(config::FILE_NAME.into());
+    }
+    let toolchain = config.toolchain();
+    let cc_version = toolchain.cc_version();
+    let arches = config.arches();
+    let mut result = Vec::new();
+    for arch in &arches {
+        let mut target = config.target(*arch, arches.len() > 1)?;
+        let path = target.bld.join(manifest::FILE_NAME);
+        if util::is_stale(&path, &inputs)? {
+            return Ok(None);
+        }
+        probe::probe(&toolchain, &cc_version, &mut target)?;
+        let context = context(&toolchain, &cc_version, &target, exe_modified);
+        let Some(statements) = manifest::Manifest::read(&path, context)
+            .and_then(|x| x.statements(&build_files))
+        else {
+            return Ok(None);
+        };
+        result.push((
+            *arch,
+            ninja::Spec {
+                statements,
+                ..Default::default()
+            },
+        ));
+    }
+    debug!("using the build statements the last run recorded");
+    Ok(Some(result))
+}
+
+/// Identifies what the build statements for `target` are generated with, so
+/// that statements recorded with anything else are not used. `exe_modified`
+/// is the modification time of eos itself.
+fn context(
+    toolchain: &toolchain::Toolchain,
+    cc_version: &Option<String>,
+    target: &target::Target,
+    exe_modified: Option<std::time::SystemTime>,
+) -> u64 {
+    manifest::hash((
+        env!("CARGO_PKG_VERSION"),
+        exe_modified,
+        &toolchain.cc,
+        cc_version,
+        ninja::Spec::kernel_cflags(target),
+        &target.bld,
+        &target.src,
+        &target.proto,
+        target.symbols,
+        target.sanitize,
+        target.ctf.enabled,
+        (target.linker, target.header_deps, target.check_symbols),
+    ))
+}
+
+/// Create a ninja build spec for each requested architecture from `specs`.
+/// Header dependencies of objects are only determined if `scan` is set.
+fn ninja_specs(
+    args: &Args,
+    config: &config::Config,
+    specs: &[(PathBuf, spec::Spec)],
+    scan: bool,
+) -> Result<Vec<(arch::Arch, ninja::Spec)>> {
+    let selected = filter::select(specs, &args.only, &args.subtrees)?;
+    spec::check_names(&selected)?;
+    spec::check_dependencies(&selected, &config.external_modules)?;
+    spec::check_cycles(&selected)?;
+    let severity = config
+        .duplicate_sources
+        .unwrap_or(diagnostic::Severity::Warning);
+    let duplicates = spec::duplicate_sources(&selected, severity);
+    match severity {
+        diagnostic::Severity::Error => Error::collect(
+            duplicates.into_iter().map(Error::Invalid).collect(),
+        )?,
+        diagnostic::Severity::Warning => {
+            for d in &duplicates {
+                report::diagnostic(args, d);
+            }
+        }
+    }
+
+    let toolchain = config.toolchain();
+    let arches = config.arches();
+    let multiarch = arches.len() > 1;
+
+    let show = args.quiet == 0 && args.message_format == MessageFormat::Human;
+
+    let cc_version = toolchain.cc_version();
+    toolchain.verify(&cc_version)?;
+    let exe_modified = std::env::current_exe()
+        .ok()
+        .and_then(|x| util::modified(&x));
+    // Sources are only scanned again if they or their headers changed, or
+    // eos or the compiler did.
+    let cache_path = config.cache_dir().join(scan::CACHE_FILE_NAME);
+    let cache = scan::Cache::load(
+        &cache_path,
+        manifest::hash((
+            env!("CARGO_PKG_VERSION"),
+            exe_modified,
+            &toolchain.cc,
+            &cc_version,
+        )),
+    );
+    let mut result = Vec::new();
+    for arch in arches {
+        let start = Instant::now();
+        let mut target = config.target(arch, multiarch)?;
+        probe::probe(&toolchain, &cc_version, &mut target)?;
+
+        // Statements from an earlier run are reused for specs that have not
+        // changed, as long as they were scanned the same way, with the same
+        // compiler.
+        let manifest_path = target.bld.join(manifest::FILE_NAME);
+        let mut manifest = manifest::Manifest::load(
+            &manifest_path,
+            context(&toolchain, &cc_version, &target, exe_modified),
+        );
+        let cached: Vec<_> = selected
+            .iter()
+            .map(|(path, _)| if scan { manifest.get(path) } else { None })
+            .collect();
+        let reused = cached.iter().filter(|x| x.is_some()).count();
+
+        // Objects of specs with generate steps are scanned during the build.
+        let sources = selected
+            .iter()
+            .zip(&cached)
+            .filter(|((_, x), cached)| {
+                x.generate.is_empty() && cached.is_none()
+            })
+            .map(|((_, x), _)| x.sources(Some(arch)).len())
+            .sum::<usize>();
+        let scanner = scan.then(|| {
+            scan::Scan::new(
+                &toolchain.cc,
+                target.header_deps,
+                target.header_roots(),
+                &cache,
+                sources as u64,
+                show,
+            )
+        });
+        let mut ninja_spec = ninja::Spec::new(&toolchain, &target)?;
+        ninja_spec.header = header();
+        ninja_spec.variables.extend(
+            args.defines.iter().map(|(k, v)| ninja::Variable::new(k, v)),
+        );
+        // Mistakes in build specs are collected so they can all be reported
+        // at once, but anything else stops generation.
+        let mut errors = Vec::new();
+        let mut record = |e: Error| match e {
+            Error::Parse(_) | Error::Invalid(_) => {
+                errors.push(e);
+                Ok(())
+            }
+            e => Err(e),
+        };
+        for (path, spec) in specs {
+            for rule in spec.rule_definitions() {
+                if let Err(e) = ninja_spec.add_rule(rule, path) {
+                    record(e)?;
+                }
+            }
+        }
+        for ((path, spec), cached) in selected.iter().zip(cached) {
+            let stmts = match cached {
+                Some(stmts) => stmts,
+                None => {
+                    debug!("generating {} for {}", path.display(), arch);
+                    let mut stmts =
+                        match spec.to_ninja(path, &target, scanner.as_ref()) {
+                            Ok(x) => x,
+                            Err(e) => {
+                                record(e)?;
+                                continue;
+                            }
+                        };
+                    stmts.sort_by(|a, b| a.output.cmp(&b.output));
+                    for stmt in &mut stmts {
+                        stmt.origin = Some(path.clone());
+                    }
+                    if scan {
+                        manifest.insert(path, &stmts, &target.bld)?;
+                    }
+                    stmts
+                }
+            };
+            ninja_spec.statements.extend(stmts);
+            ninja_spec.raw.extend(spec.raw.iter().map(|x| ninja::Raw {
+                text: x.text.clone(),
+                origin: path.clone(),
+            }));
+        }
+        drop(scanner);
+        Error::collect(errors)?;
+        let install = ninja::Rules::Install.to_string();
+        let installed = ninja_spec
             .statements
-            .extend(util::read_spec(path)?.to_ninja(path)?);
+            .iter()
+            .filter(|x| x.rule == install)
+            .map(|x| x.output.clone())
+            .collect();
+        ninja_spec.statements.push(ninja::BuildStatement {
+            inputs: installed,
+            output: target.install()?,
+            rule: ninja::Rules::Phony.to_string(),
+            ..Default::default()
+        });
+        if target.coverage {
+            ninja_spec.add_coverage(&target)?;
+        }
+        ninja_spec.validate()?;
+        if scan {
+            manifest.save(&manifest_path)?;
+        }
+        info!(
+            "generated {} build statements for {} in {:.2?}, reusing {} of \
+             {} build specs",
+            ninja_spec.statements.len(),
+            arch,
+            start.elapsed(),
+            reused,
+            selected.len()
+        );
+        result.push((arch, ninja_spec));
+    }
+    if scan {
+        cache.save(&cache_path)?;
+    }
+    Ok(result)
+}
+
+/// The path of the build file for `arch` when building several architectures,
+/// e.g. build.ninja -> build-amd64.ninja.
+fn arch_path(output: &Path, arch: arch::Arch) -> PathBuf {
+    let stem = output.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match output.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, arch, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, arch),
+    };
+    output.with_file_name(name)
+}
+
+/// Write the build files selected by `args`.
+fn emit(
+    args: &GenerateArgs,
+    ninja_specs: &[(arch::Arch, ninja::Spec)],
+) -> Result<()> {
+    for (path, data) in build_files(args, ninja_specs)? {
+        backend::write(&path, &data)?;
+    }
+    Ok(())
+}
+
+/// Check that the build files selected by `args` are up to date, failing
+/// with a summary of the differences if not.
+fn check(
+    args: &GenerateArgs,
+    ninja_specs: &[(arch::Arch, ninja::Spec)],
+) -> Result<()> {
+    let mut stale = 0;
+    for (path, data) in build_files(args, ninja_specs)? {
+        if path == Path::new("-") {
+            return Err(Error::Usage("cannot check stdout".into()));
+        }
+        match check::compare(&path, &data)? {
+            Some(summary) => {
+                println!("{}", summary);
+                stale += 1;
+            }
+            None => info!("{} is up to date", path.display()),
+        }
+    }
+    match stale {
+        0 => Ok(()),
+        n => Err(Error::Failed(format!(
+            "{} build file{} out of date",
+            n,
+            if n == 1 { " is" } else { "s are" }
+        ))),
+    }
+}
+
+/// Write how the ninja files at `output` would change if they were
+/// regenerated to stdout.
+fn diff(
+    output: &Path,
+    ninja_specs: &[(arch::Arch, ninja::Spec)],
+) -> Result<()> {
+    let args = GenerateArgs {
+        output: Some(output.to_owned()),
+        backend: BackendKind::Ninja,
+        compdb: false,
+    };
+    for (path, data) in build_files(&args, ninja_specs)? {
+        if path == Path::new("-") {
+            return Err(Error::Usage("cannot compare with stdout".into()));
+        }
+        let diff = diff::compare(&path, &data)?;
+        if diff.exists && diff.changes.is_empty() {
+            info!("{} is up to date", path.display());
+            continue;
+        }
+        backend::emit_file(&diff, Path::new("-"))?;
+    }
+    Ok(())
+}
+
+/// Render the build files selected by `args`, as the paths to write them to
+/// and their contents.
+fn build_files(
+    args: &GenerateArgs,
+    ninja_specs: &[(arch::Arch, ninja::Spec)],
+) -> Result<Vec<(PathBuf, Vec<u8>)>> {
+    let output = match args.backend {
+        BackendKind::Ninja => {
+            args.output.as_deref().unwrap_or(Path::new("build.ninja"))
+        }
+        BackendKind::Make => {
+            args.output.as_deref().unwrap_or(Path::new("Makefile"))
+        }
+    };
+    let mut files = Vec::new();
+    match (args.backend, ninja_specs) {
+        (BackendKind::Ninja, [(_, ninja_spec)]) => {
+            files.push((output.to_owned(), backend::render(ninja_spec)?))
+        }
+        (BackendKind::Make, [(_, ninja_spec)]) => files.push((
+            output.to_owned(),
+            backend::render(&make::Makefile { spec: ninja_spec })?,
+        )),
+        (BackendKind::Ninja, _) => {
+            if output == Path::new("-") {
+                return Err(Error::Usage(
+                    "cannot write multiple architectures to stdout".into(),
+                ));
+            }
+            // Every architecture has the same pools, which are shared.
+            let mut root = ninja::Spec {
+                header: header(),
+                pools: ninja_specs
+                    .first()
+                    .map(|(_, x)| x.pools.clone())
+                    .unwrap_or_default(),
+                ..Default::default()
+            };
+            for (arch, ninja_spec) in ninja_specs {
+                let path = arch_path(output, *arch);
+                root.subninjas.push(util::ninja_path(&path)?);
+                files.push((
+                    path,
+                    backend::render(&ninja::Included(ninja_spec))?,
+                ));
+            }
+            files.push((output.to_owned(), backend::render(&root)?))
+        }
+        (BackendKind::Make, _) => {
+            return Err(Error::Usage(
+                "the make backend supports a single architecture".into(),
+            ))
+        }
+    }
+
+    if args.compdb {
+        let dir = match output.parent() {
+            Some(dir) if output != Path::new("-") => dir,
+            _ => Path::new(""),
+        };
+        let compdb = compdb::CompilationDatabase {
+            specs: ninja_specs.iter().map(|(_, x)| x).collect(),
+            directory: std::env::current_dir()?,
+        };
+        files.push((
+            dir.join("compile_commands.json"),
+            backend::render(&compdb)?,
+        ));
+    }
+
+    Ok(files)
+}
+
+/// Regenerate build.ninja if any build spec, the configuration or eos itself
+/// is newer than it and then replace this process with ninja. With `install`
+/// and no targets, ninja installs everything into the proto area.
+fn build(
+    args: &Args,
+    build_args: &BuildArgs,
+    config: &config::Config,
+    install: bool,
+) -> Result<()> {
+    regenerate(args, config, build_args.regenerate)?;
+    let mut ninja =
+        ninja_command(args, config, &build_args.ninja, build_args.jobs)?;
+    ninja.args(&build_args.ninja_args).args(&build_args.targets);
+    if install && build_args.targets.is_empty() {
+        let arches = config.arches();
+        for arch in &arches {
+            ninja.arg(config.target(*arch, arches.len() > 1)?.install()?);
+        }
+    }
+    let e = std::os::unix::process::CommandExt::exec(&mut ninja);
+    Err(Error::Toolchain(format!(
+        "failed to run {}: {}",
+        build_args.ninja, e
+    )))
+}
+
+/// Regenerate build.ninja if `force` is set or any build spec, the
+/// configuration or eos itself is newer than it.
+fn regenerate(args: &Args, config: &config::Config, force: bool) -> Result<()> {
+    let _lock = lock::Lock::acquire()?;
+    let output = Path::new("build.ninja");
+    let mut inputs =
+        walk::find_build_files(config.source_root(), &config.walk()?)?;
+    inputs.push(std::env::current_exe()?);
+    if Path::new(config::FILE_NAME).exists() {
+        inputs.push(config::FILE_NAME.into());
+    }
+    if force || util::is_stale(output, &inputs)? {
+        emit(&GenerateArgs::default(), &generate(args, config)?)?;
+        // The build file is only rewritten when its content changes, so mark
+        // it as up to date explicitly to avoid regenerating next time.
+        std::fs::File::options()
+            .append(true)
+            .open(output)?
+            .set_modified(std::time::SystemTime::now())?;
+    }
+    Ok(())
+}
+
+/// Build what the selected tests need and run them one after another,
+/// writing a line for each as it finishes and then a report.
+fn test(
+    args: &Args,
+    test_args: &TestArgs,
+    config: &config::Config,
+) -> Result<()> {
+    let specs = read_specs(config)?;
+    let selected = filter::select(&specs, &args.only, &args.subtrees)?;
+    let arches = config.arches();
+    let mut cases = Vec::new();
+    let mut reports = Vec::new();
+    for arch in &arches {
+        let target = config.target(*arch, arches.len() > 1)?;
+        cases.extend(test::cases(&selected, &target, &test_args.filters));
+        if target.coverage {
+            reports.push(target.coverage()?);
+        }
+    }
+    if cases.is_empty() {
+        warn!("no tests to run");
+    }
+
+    let needs: Vec<String> = cases
+        .iter()
+        .flat_map(|x| x.needs())
+        .collect::<Result<_>>()?;
+    if !needs.is_empty() {
+        regenerate(args, config, false)?;
+        let mut ninja =
+            ninja_command(args, config, &test_args.ninja, test_args.jobs)?;
+        // Keep ninja's output off stdout, which may be a JSON report.
+        ninja.args(&needs).stdout(std::io::stderr());
+        let status = ninja.status().map_err(|e| {
+            Error::Toolchain(format!(
+                "failed to run {}: {}",
+                test_args.ninja, e
+            ))
+        })?;
+        if !status.success() {
+            return Err(Error::Failed("building the tests failed".into()));
+        }
+    }
+
+    let mut outcomes = Vec::new();
+    for case in &cases {
+        let outcome = case.run();
+        if test_args.json {
+            info!("{}", outcome.line());
+        } else {
+            println!("{}", outcome.line());
+        }
+        outcomes.push(outcome);
+    }
+
+    // The coverage data the tests collected is merged once they have all
+    // run, whether or not they passed.
+    if !reports.is_empty() {
+        if needs.is_empty() {
+            regenerate(args, config, false)?;
+        }
+        let mut ninja =
+            ninja_command(args, config, &test_args.ninja, test_args.jobs)?;
+        ninja.args(&reports).stdout(std::io::stderr());
+        let status = ninja.status().map_err(|e| {
+            Error::Toolchain(format!(
+                "failed to run {}: {}",
+                test_args.ninja, e
+            ))
+        })?;
+        if !status.success() {
+            return Err(Error::Failed(
+                "writing the coverage report failed".into(),
+            ));
+        }
+        for report in &reports {
+            info!("wrote coverage report {}", report);
+        }
+    }
+    let report = test::Report::new(outcomes, test_args.json);
+    backend::emit_file(&report, Path::new("-"))?;
+    match report.failed {
+        0 => Ok(()),
+        n => Err(Error::Failed(format!(
+            "{} test{} failed",
+            n,
+            if n == 1 { "" } else { "s" }
+        ))),
     }
-    ninja_spec.emit_file()?;
+}
+
+/// A command running `ninja` on build.ninja with `jobs` jobs, defaulting to
+/// one per CPU, or when compiling is distributed, enough to fill the pools.
+/// The compiler is told not to color its diagnostics unless `--color`
+/// allows it.
+fn ninja_command(
+    args: &Args,
+    config: &config::Config,
+    ninja: &str,
+    jobs: Option<usize>,
+) -> Result<std::process::Command> {
+    let jobs = match (jobs, &config.distributed) {
+        (Some(jobs), _) => jobs,
+        (None, Some(x)) => x.jobs + x.local_jobs(),
+        (None, None) => std::thread::available_parallelism()?.get(),
+    };
+    let mut command = std::process::Command::new(ninja);
+    command
+        .arg("-f")
+        .arg("build.ninja")
+        .arg("-j")
+        .arg(jobs.to_string());
+    if std::env::var_os("NINJA_STATUS").is_none() {
+        command.env("NINJA_STATUS", "[%f/%t %es] ");
+    }
+    // The build file asks gcc for color regardless, since ninja pipes its
+    // output; an empty GCC_COLORS turns it off again.
+    if !report::color(args.color) {
+        command.env("GCC_COLORS", "");
+    }
+    Ok(command)
+}
+
+/// Regenerate build.ninja whenever a build spec, or a source if asked,
+/// changes, and optionally run ninja afterwards. The configuration is read
+/// again when `eos.toml` changes, with `dir` as in `run`. Errors are reported
+/// and watching carries on, with the last configuration and build specs that
+/// could be read.
+fn watch(
+    args: &Args,
+    watch_args: &WatchArgs,
+    config: &config::Config,
+    dir: &Path,
+) -> Result<()> {
+    let interval = std::time::Duration::from_millis(watch_args.interval);
+    let watcher_for = |config: &config::Config| -> Result<watch::Watcher> {
+        let walk = config.walk()?;
+        let file = std::path::absolute(config::FILE_NAME)?;
+        Ok(watch::Watcher::new(&walk.root, walk.skip, &file, interval))
+    };
+    let config_modified = || util::modified(Path::new(config::FILE_NAME));
+    let mut watcher = watcher_for(config)?;
+    let mut reloaded = None;
+    let mut modified = config_modified();
+    let mut specs = Vec::new();
+    let mut last = watch::Snapshot::default();
+    loop {
+        if config_modified() != modified {
+            modified = config_modified();
+            info!("{} changed, reading it again", config::FILE_NAME);
+            let reload =
+                load_config(args, dir).and_then(|x| Ok((watcher_for(&x)?, x)));
+            match reload {
+                Ok((x, y)) => {
+                    watcher = x;
+                    reloaded = Some(y);
+                }
+                Err(e) => report::error(args, &e),
+            }
+        }
+        let config = reloaded.as_ref().unwrap_or(config);
+        let mut paths =
+            walk::find_build_files(config.source_root(), &config.walk()?)?;
+        paths.push(config::FILE_NAME.into());
+        if watch_args.sources {
+            match read_specs(config) {
+                Ok(x) => specs = x,
+                Err(e) => report::error(args, &e),
+            }
+        }
+        let snapshot = watch::Snapshot::new(&paths, &specs, watch_args.sources);
+        if snapshot != last {
+            last = snapshot;
+            let result = lock::Lock::acquire().and_then(|_lock| {
+                emit(&GenerateArgs::default(), &generate(args, config)?)
+            });
+            if let Err(e) = &result {
+                report::error(args, e);
+            }
+            if result.is_ok() && watch_args.build {
+                let mut ninja = ninja_command(
+                    args,
+                    config,
+                    &watch_args.ninja,
+                    watch_args.jobs,
+                )?;
+                if let Err(e) = ninja.status() {
+                    error!("failed to run {}: {}", watch_args.ninja, e);
+                }
+            }
+        }
+        watcher.wait();
+    }
+}
+
+/// Write an inventory of the selected build specs to stdout.
+fn list(args: &Args, config: &config::Config, json: bool) -> Result<()> {
+    let specs = read_specs(config)?;
+    let list = list::List {
+        specs: filter::select(&specs, &args.only, &args.subtrees)?,
+        json,
+    };
+    backend::emit_file(&list, Path::new("-"))
+}
+
+/// Write a graph of the dependencies between the selected modules to
+/// `output`.
+fn module_graph(
+    args: &Args,
+    config: &config::Config,
+    format: deps::Format,
+    output: &Path,
+) -> Result<()> {
+    let specs = read_specs(config)?;
+    let graph = deps::ModuleGraph {
+        specs: filter::select(&specs, &args.only, &args.subtrees)?,
+        format,
+    };
+    backend::emit_file(&graph, output)
+}
+
+/// Write the command that builds the source or module `path` to stdout, with
+/// where each of its flags comes from.
+fn explain_flags(
+    args: &Args,
+    config: &config::Config,
+    path: &str,
+) -> Result<()> {
+    let ninja_specs = ninja_specs(args, config, &read_specs(config)?, false)?;
+    let multiarch = ninja_specs.len() > 1;
+    let mut explained = Vec::new();
+    let toolchain = config.toolchain();
+    let cc_version = toolchain.cc_version();
+    for (arch, spec) in &ninja_specs {
+        let mut target = config.target(*arch, multiarch)?;
+        probe::probe(&toolchain, &cc_version, &mut target)?;
+        let layers = explain::Layers::from([
+            ("kernel_cflags", ninja::Spec::kernel_cflag_layers(&target)),
+            ("kernel_ldflags", ninja::Spec::kernel_ldflag_layers(&target)),
+        ]);
+        explained.push((*arch, spec, layers));
+    }
+    let explain = explain::Explain {
+        ninja_specs: explained,
+        defines: args.defines.len(),
+        path: path.to_owned(),
+    };
+    backend::emit_file(&explain, Path::new("-"))
+}
+
+/// Write the dependency closure of `module` to stdout, with the headers its
+/// objects depend on if `headers` is set.
+fn deps(
+    args: &Args,
+    config: &config::Config,
+    module: &str,
+    headers: bool,
+    json: bool,
+) -> Result<()> {
+    let specs = read_specs(config)?;
+    let ninja_specs = match headers {
+        true => match recorded(args, config)? {
+            Some(x) => Some(x),
+            None => Some(ninja_specs(args, config, &specs, true)?),
+        },
+        false => None,
+    };
+    let closure =
+        deps::Closure::new(&specs, module, ninja_specs.as_deref(), json)?;
+    backend::emit_file(&closure, Path::new("-"))
+}
+
+/// Write statistics about the selected build specs and the build generated
+/// from them to stdout.
+fn stats(
+    args: &Args,
+    config: &config::Config,
+    top: usize,
+    json: bool,
+) -> Result<()> {
+    let specs = read_specs(config)?;
+    let ninja_specs = ninja_specs(args, config, &specs, true)?;
+    let stats = stats::Stats {
+        specs: filter::select(&specs, &args.only, &args.subtrees)?,
+        ninja_specs: &ninja_specs,
+        top,
+        json,
+    };
+    backend::emit_file(&stats, Path::new("-"))
+}
+
+/// Write the package manifests for each architecture's proto area, and
+/// publish them to `repo` if there is one.
+fn package(
+    args: &Args,
+    config: &config::Config,
+    version: &str,
+    repo: Option<&str>,
+    publisher: &str,
+) -> Result<()> {
+    let specs = read_specs(config)?;
+    let ninja_specs = ninja_specs(args, config, &specs, true)?;
+    let selected = filter::select(&specs, &args.only, &args.subtrees)?;
+    if let Some(repo) = repo {
+        package::create_repo(repo, publisher)?;
+    }
+    for (arch, ninja_spec) in &ninja_specs {
+        let target = config.target(*arch, ninja_specs.len() > 1)?;
+        let manifests =
+            package::manifests(&selected, ninja_spec, &target, version);
+        if manifests.is_empty() {
+            warn!("no build specs for {} describe a package", arch);
+        }
+        let dir = target.bld.join("pkg");
+        std::fs::create_dir_all(&dir)?;
+        for (name, manifest) in &manifests {
+            let path = dir.join(package::file_name(name));
+            backend::emit_file(manifest, &path)?;
+            let Some(repo) = repo else {
+                continue;
+            };
+            if let Some(x) = manifest
+                .files
+                .keys()
+                .find(|x| !target.proto.join(x).exists())
+            {
+                return Err(Error::Failed(format!(
+                    "{} is not installed, run eos install first",
+                    target.proto.join(x).display()
+                )));
+            }
+            package::publish(repo, &target.proto, &path)?;
+            info!("published {} to {}", manifest.fmri, repo);
+        }
+    }
+    if let Some(repo) = repo {
+        package::refresh_repo(repo)?;
+    }
+    Ok(())
+}
+
+/// Check that the programs the build needs can be run, failing if any
+/// cannot.
+fn doctor(config: &config::Config, ninja: &str) -> Result<()> {
+    let doctor = doctor::Doctor::new(&config.toolchain(), ninja);
+    backend::emit_file(&doctor, Path::new("-"))?;
+    match doctor.problems() {
+        0 => Ok(()),
+        n => Err(Error::Toolchain(format!(
+            "{} program{} cannot be used",
+            n,
+            if n == 1 { "" } else { "s" }
+        ))),
+    }
+}
+
+/// Check the build specs in the tree, reporting everything found, and fail
+/// if there are errors. Generation is checked without scanning headers, so no
+/// compiler is needed.
+fn lint(args: &Args, config: &config::Config) -> Result<()> {
+    let mut report = lint::Report::default();
+    let specs = lint::read(
+        walk::find_build_files(config.source_root(), &config.walk()?)?,
+        &mut report,
+    );
+    lint::check(&specs, &mut report);
+    if let Err(e) = ninja_specs(args, config, &specs, false) {
+        report.diagnostics.extend(e.diagnostics());
+    }
+
+    for d in &report.diagnostics {
+        report::diagnostic(args, d);
+    }
+    match report.errors() {
+        0 => Ok(()),
+        n => Err(Error::Invalid(diagnostic::Diagnostic::general(format!(
+            "{} build spec error{} found",
+            n,
+            if n == 1 { "" } else { "s" }
+        )))),
+    }
+}
+
+/// Rewrite the build specs at `paths`, or in the whole tree if there are
+/// none, in the canonical style. With `check` nothing is written, and build
+/// specs that are not formatted are an error.
+fn fmt(config: &config::Config, paths: &[PathBuf], check: bool) -> Result<()> {
+    let mut unformatted = 0;
+    for path in &spec_files(config, paths)? {
+        let text = std::fs::read_to_string(path)?;
+        let formatted = format::format(path, &text)?;
+        if formatted == text {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", path.display());
+            unformatted += 1;
+        } else {
+            util::write_if_changed(path, formatted.as_bytes())?;
+            info!("formatted {}", path.display());
+        }
+    }
+    match unformatted {
+        0 => Ok(()),
+        n => Err(Error::Failed(format!(
+            "{} build spec{} not formatted",
+            n,
+            if n == 1 { " is" } else { "s are" }
+        ))),
+    }
+}
+
+/// Upgrade the build specs at `paths`, or in the whole tree if there are none,
+/// to the current version of the build spec format. With `check` nothing is
+/// written, and build specs that are not the current version are an error.
+fn upgrade_spec(
+    config: &config::Config,
+    paths: &[PathBuf],
+    check: bool,
+) -> Result<()> {
+    let mut outdated = 0;
+    for path in &spec_files(config, paths)? {
+        let text = std::fs::read_to_string(path)?;
+        let Some(upgraded) = upgrade::upgrade(path, &text)? else {
+            continue;
+        };
+        if check {
+            println!("{} is not version {}", path.display(), upgrade::VERSION);
+            outdated += 1;
+        } else {
+            util::write_if_changed(path, upgraded.as_bytes())?;
+            info!("upgraded {}", path.display());
+        }
+    }
+    match outdated {
+        0 => Ok(()),
+        n => Err(Error::Failed(format!(
+            "{} build spec{} not version {}",
+            n,
+            if n == 1 { " is" } else { "s are" },
+            upgrade::VERSION
+        ))),
+    }
+}
 
+/// The build specs at `paths`, and under those that are directories, or in
+/// the whole tree if there are none.
+fn spec_files(
+    config: &config::Config,
+    paths: &[PathBuf],
+) -> Result<Vec<PathBuf>> {
+    let walk = config.walk()?;
+    let mut files = Vec::new();
+    if paths.is_empty() {
+        files = walk::find_build_files(config.source_root(), &walk)?;
+    }
+    for path in paths {
+        if path.is_dir() {
+            files.extend(walk::find_build_files(path, &walk)?);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// Remove everything a build produced in the current directory. Unless
+/// `yes` is set, confirmation is needed if the directory does not look like
+/// an eos workspace, i.e. has neither eos.toml nor a source tree.
+fn clean(config: &config::Config, dry_run: bool, yes: bool) -> Result<()> {
+    let mut dirs = config.output_dirs()?;
+    let cache = config.cache_dir();
+    if !dirs.iter().any(|x| cache.starts_with(x)) {
+        dirs.push(cache);
+    }
+    let mut outputs = clean::outputs(&dirs, config.proto_dir())?;
+    // The lock is held while cleaning, so neither it nor a directory it is
+    // in is removed.
+    let lock = std::path::absolute(lock::FILE_NAME)?;
+    outputs.retain(|x| match std::path::absolute(x) {
+        Ok(x) => !lock.starts_with(util::normalize(&x)),
+        Err(_) => true,
+    });
+    if outputs.is_empty() {
+        info!("nothing to clean");
+        return Ok(());
+    }
+    if dry_run {
+        for path in &outputs {
+            println!("would remove {}", path.display());
+        }
+        return Ok(());
+    }
+
+    let workspace =
+        Path::new(config::FILE_NAME).exists() || config.source_root().is_dir();
+    if !workspace && !yes {
+        eprintln!(
+            "{} does not look like an eos workspace, but has:",
+            std::env::current_dir()?.display()
+        );
+        for path in &outputs {
+            eprintln!("  {}", path.display());
+        }
+        eprint!("remove these? [y/N] ");
+        let mut answer = String::new();
+        std::io::stdin().read_line(&mut answer)?;
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            return Err(Error::Failed("clean cancelled".into()));
+        }
+    }
+    for path in &outputs {
+        println!("removing {}", path.display());
+        clean::remove(path)?;
+    }
+    Ok(())
+}
+
+/// Create the build spec described by `kind`.
+fn new(kind: &NewKind) -> Result<()> {
+    let written = match kind {
+        NewKind::Module {
+            dir,
+            name,
+            dependencies,
+            stub,
+            force,
+        } => scaffold::new_module(
+            dir,
+            name.as_deref(),
+            dependencies,
+            *stub,
+            *force,
+        )?,
+    };
+    for path in written {
+        println!("created {}", path.display());
+    }
     Ok(())
 }
+
+/// Convert the module Makefile at `makefile` to a build spec, written to
+/// `output` or next to the module's sources.
+fn migrate(
+    args: &Args,
+    makefile: &Path,
+    fragments: &[PathBuf],
+    rules: &[PathBuf],
+    output: Option<&Path>,
+    force: bool,
+) -> Result<()> {
+    let migration = migrate::migrate(makefile, fragments, rules)?;
+    for d in &migration.diagnostics {
+        report::diagnostic(args, d);
+    }
+    let text = scaffold::spec_text(&migration.module)?;
+    let path = match output {
+        Some(x) => x.to_owned(),
+        None => migration.dir.join("build.toml"),
+    };
+    if path == Path::new("-") {
+        print!("{}", text);
+        return Ok(());
+    }
+    if path.exists() && !force {
+        return Err(Error::Usage(format!(
+            "{}: already exists, use --force to replace it",
+            path.display()
+        )));
+    }
+    std::fs::write(&path, text)?;
+    println!("created {}", path.display());
+    Ok(())
+}
+
+/// Write a completion script for `shell` to stdout, offering the names of
+/// the modules in the tree, as they are now, for `--only`.
+fn completions(
+    config: &config::Config,
+    shell: clap_complete::Shell,
+) -> Result<()> {
+    let mut command = Args::command();
+    let modules: Vec<String> = read_specs(config)
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(_, x)| x.module.map(|x| x.name))
+        .collect();
+    if !modules.is_empty() {
+        command = command.mut_arg("only", |x| {
+            x.value_parser(clap::builder::PossibleValuesParser::new(modules))
+        });
+    }
+    clap_complete::generate(shell, &mut command, "eos", &mut std::io::stdout());
+    Ok(())
+}
+
+/// Write a compilation database for the compile edges in `ninja_specs` to
+/// `output`.
+fn compdb(
+    ninja_specs: &[(arch::Arch, ninja::Spec)],
+    output: &Path,
+) -> Result<()> {
+    let compdb = compdb::CompilationDatabase {
+        specs: ninja_specs.iter().map(|(_, x)| x).collect(),
+        directory: std::env::current_dir()?,
+    };
+    backend::emit_file(&compdb, output)
+}
+
+/// Write the build graph to stdout.
+fn dump(
+    ninja_specs: &[(arch::Arch, ninja::Spec)],
+    format: GraphFormat,
+) -> Result<()> {
+    match format {
+        GraphFormat::Json => backend::emit_file(
+            &graph::Graph { specs: ninja_specs },
+            Path::new("-"),
+        ),
+    }
+}
+
+/// Scan the sources of `objects` for the `headers` they depend on, in the
+/// workspace or under `roots`, using `cc` and write them to the dyndep file at
+/// `output`.
+fn dyndep(
+    cc: &str,
+    headers: scan::HeaderDeps,
+    roots: &[PathBuf],
+    output: &Path,
+    objects: &[String],
+    cflags: &[String],
+) -> Result<()> {
+    let mut dd = ninja::Dyndep::default();
+    for object in objects {
+        let Some((obj, src)) = object.split_once('=') else {
+            return Err(Error::Usage(format!(
+                "{}: expected object=source",
+                object
+            )));
+        };
+        dd.statements.push(ninja::DyndepStatement {
+            output: obj.to_owned(),
+            implicit_deps: util::header_deps(
+                cc,
+                cflags,
+                Path::new(src),
+                headers,
+                roots,
+            )?
+            .iter()
+            .map(|x| util::ninja_path(x))
+            .collect::<Result<_>>()?,
+            ..Default::default()
+        });
+    }
+    backend::emit_file(&dd, output)
+}
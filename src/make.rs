@@ -0,0 +1,202 @@
+use crate::backend::Backend;
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
+use crate::ninja;
+use std::io::Write;
+
+/// A GNU make rendering of a ninja build specification. Ninja rules have no
+/// make equivalent, so each build statement gets its own recipe with the
+/// rule command expanded against the statement's inputs, outputs and
+/// variables. Top level variables remain make variables.
+pub struct Makefile<'a> {
+    /// The ninja build specification to render.
+    pub spec: &'a ninja::Spec,
+}
+
+impl Backend for Makefile<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        if let Some(raw) = self.spec.raw.first() {
+            return Err(Diagnostic::error(
+                &raw.origin,
+                "raw ninja text cannot be expressed as a makefile",
+            )
+            .into());
+        }
+        for line in &self.spec.header {
+            writeln!(w, "# {}", line)?;
+        }
+        writeln!(w, ".DELETE_ON_ERROR:")?;
+        writeln!(w, ".DEFAULT_GOAL := all")?;
+        // Ninja expands top level variables where they are defined, which
+        // lets a definition refer to an earlier one of the same name.
+        for v in &self.spec.variables {
+            writeln!(w, "{} := {}", v.name, translate(&v.value, &|_| None))?;
+        }
+
+        let outputs: Vec<&str> = self
+            .spec
+            .statements
+            .iter()
+            .map(|x| x.output.as_str())
+            .collect();
+        writeln!(w, ".PHONY: all")?;
+        writeln!(w, "all: {}", outputs.join(" "))?;
+
+        let mut origin = None;
+        for stmt in &self.spec.statements {
+            if stmt.origin.is_some() && stmt.origin != origin {
+                origin = stmt.origin.clone();
+                write!(w, "\n# from {}\n", stmt.origin_display())?;
+            }
+            self.emit_statement(stmt, w)?;
+        }
+        Ok(())
+    }
+}
+
+impl Makefile<'_> {
+    /// Emit a build statement as a make target and recipe.
+    fn emit_statement(
+        &self,
+        stmt: &ninja::BuildStatement,
+        w: &mut dyn Write,
+    ) -> Result<()> {
+        if stmt.rule == ninja::Rules::Phony.to_string() {
+            writeln!(w, ".PHONY: {}", stmt.output)?;
+            writeln!(w, "{}: {}", stmt.output, stmt.inputs.join(" "))?;
+            return Ok(());
+        }
+        let rule = match self.spec.rules.iter().find(|r| r.name == stmt.rule) {
+            Some(rule) => rule,
+            None => {
+                return Err(Error::Failed(format!(
+                    "{}: unknown rule {}",
+                    stmt.output, stmt.rule
+                )))
+            }
+        };
+
+        let outputs: Vec<&str> = stmt.outputs().collect();
+        // The statement's variables see its inputs and outputs, and the
+        // rule's variables see the statement's as well.
+        let files = |name: &str| match name {
+            "in" => Some(stmt.inputs.join(" ")),
+            "out" => Some(stmt.output.clone()),
+            _ => None,
+        };
+        let statement = |name: &str| {
+            files(name).or_else(|| {
+                stmt.variables
+                    .iter()
+                    .find(|v| v.name == name)
+                    .map(|v| translate(&v.value, &files))
+            })
+        };
+        let local = |name: &str| {
+            statement(name).or_else(|| {
+                rule.variables
+                    .iter()
+                    .find(|v| v.name == name)
+                    .map(|v| translate(&v.value, &statement))
+            })
+        };
+
+        // Multiple outputs from one recipe need a grouped target.
+        let sep = if outputs.len() > 1 { "&:" } else { ":" };
+        write!(w, "{}{}", outputs.join(" "), sep)?;
+        for dep in stmt.inputs.iter().chain(&stmt.implicit_deps) {
+            write!(w, " {}", dep)?;
+        }
+        if !stmt.order_only_deps.is_empty() {
+            write!(w, " | {}", stmt.order_only_deps.join(" "))?;
+        }
+        writeln!(w)?;
+
+        // Unlike ninja, make does not create output directories.
+        writeln!(w, "\t@mkdir -p $(dir {})", outputs.join(" "))?;
+        let command = translate(&rule.command, &local);
+        match rule.variables.iter().find(|v| v.name == "description") {
+            Some(desc) => {
+                writeln!(w, "\t@echo \"{}\"", translate(&desc.value, &local))?;
+                writeln!(w, "\t@{}", command)?;
+            }
+            None => writeln!(w, "\t{}", command)?,
+        }
+        Ok(())
+    }
+}
+
+/// Translate ninja text into make syntax. Variable references for which
+/// `local` returns a value are replaced by that value, all others become make
+/// variable references.
+fn translate(text: &str, local: &dyn Fn(&str) -> Option<String>) -> String {
+    let mut s = String::new();
+    for token in ninja::tokenize(text) {
+        match token {
+            ninja::Token::Literal(text) => s += &text.replace('$', "$$"),
+            ninja::Token::Variable(name) => match local(&name) {
+                Some(value) => s += &value,
+                None => s += &format!("$({})", name),
+            },
+        }
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ninja::{BuildStatement, RuleDefinition, Spec, Variable};
+
+    /// `spec` rendered as a makefile.
+    fn render(spec: &Spec) -> String {
+        let mut out = Vec::new();
+        Makefile { spec }.emit(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn recipe_expands_rule_and_statement_variables() {
+        let spec = Spec {
+            rules: vec![RuleDefinition {
+                name: "mytool".into(),
+                command: "cp $flags $extra $in $out".into(),
+                variables: vec![Variable::new("flags", "-p")],
+                ..Default::default()
+            }],
+            statements: vec![BuildStatement {
+                inputs: vec!["a.txt".into()],
+                output: "b.txt".into(),
+                rule: "mytool".into(),
+                variables: vec![Variable::new("extra", "--backup=$out~")],
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        assert!(
+            render(&spec).contains("\n\tcp -p --backup=b.txt~ a.txt b.txt\n")
+        );
+    }
+
+    #[test]
+    fn recipe_leaves_top_level_variables_to_make() {
+        let spec = Spec {
+            variables: vec![Variable::new("cflags", "-O2")],
+            rules: vec![RuleDefinition {
+                name: "cc".into(),
+                command: "cc $cflags -c $in -o $out".into(),
+                ..Default::default()
+            }],
+            statements: vec![BuildStatement {
+                inputs: vec!["a.c".into()],
+                output: "a.o".into(),
+                rule: "cc".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let makefile = render(&spec);
+        assert!(makefile.contains("\ncflags := -O2\n"));
+        assert!(makefile.contains("\n\tcc $(cflags) -c a.c -o a.o\n"));
+    }
+}
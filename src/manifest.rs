@@ -0,0 +1,144 @@
+use crate::error::Result;
+use crate::ninja::BuildStatement;
+use crate::util;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tracing::debug;
+
+/// The build statements generated for each build spec by an earlier run, so
+/// that specs whose files have not changed need not be scanned for headers
+/// again, and questions about the build can be answered without generating
+/// it. Kept in the output directory of each target.
+#[derive(Serialize, Deserialize, Default)]
+pub struct Manifest {
+    /// The version of the format the manifest is written in. Manifests
+    /// written in any other are not read.
+    version: u32,
+    /// Identifies what the statements were generated with. Statements
+    /// generated with anything else are not reused.
+    context: u64,
+    /// The statements of each build spec, by build spec path.
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+/// The statements generated for a build spec, and what they depend on.
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// A hash of the build spec's contents.
+    hash: u64,
+    /// The modification times of the sources and headers the statements
+    /// were generated from.
+    files: BTreeMap<PathBuf, Option<SystemTime>>,
+    /// The statements.
+    statements: Vec<BuildStatement>,
+}
+
+/// The name of the manifest file in an output directory.
+pub const FILE_NAME: &str = ".eos-manifest.json";
+
+/// The version of the format manifests are written in, raised whenever what
+/// they record changes.
+const FORMAT_VERSION: u32 = 1;
+
+impl Manifest {
+    /// Read the manifest at `path`. A manifest that is missing, unreadable
+    /// or was written for a different `context` is treated as empty.
+    pub fn load(path: &Path, context: u64) -> Manifest {
+        match Manifest::read(path, context) {
+            Some(x) => x,
+            None => {
+                debug!("not reusing statements from {}", path.display());
+                Manifest {
+                    version: FORMAT_VERSION,
+                    context,
+                    ..Default::default()
+                }
+            }
+        }
+    }
+
+    /// Read the manifest at `path`, if there is one in the current format
+    /// that was written for `context`.
+    pub fn read(path: &Path, context: u64) -> Option<Manifest> {
+        let manifest = std::fs::read(path)
+            .ok()
+            .and_then(|x| serde_json::from_slice::<Manifest>(&x).ok())?;
+        (manifest.version == FORMAT_VERSION && manifest.context == context)
+            .then_some(manifest)
+    }
+
+    /// The statements recorded for each of the build specs at `paths`, in
+    /// order, if none of them nor any file they were generated from has
+    /// changed since.
+    pub fn statements(&self, paths: &[PathBuf]) -> Option<Vec<BuildStatement>> {
+        let mut result = Vec::new();
+        for path in paths {
+            result.extend(self.get(path)?);
+        }
+        Some(result)
+    }
+
+    /// The statements recorded for the build spec at `path`, if neither it
+    /// nor any file they were generated from has changed since.
+    pub fn get(&self, path: &Path) -> Option<Vec<BuildStatement>> {
+        let entry = self.entries.get(path)?;
+        if hash(&std::fs::read(path).ok()?) != entry.hash {
+            return None;
+        }
+        if entry
+            .files
+            .iter()
+            .any(|(x, time)| util::modified(x) != *time)
+        {
+            return None;
+        }
+        Some(entry.statements.clone())
+    }
+
+    /// Record `statements` as generated for the build spec at `path`.
+    /// Inputs under `bld` are build outputs, which change with every build,
+    /// so they are left out of the files the statements depend on.
+    pub fn insert(
+        &mut self,
+        path: &Path,
+        statements: &[BuildStatement],
+        bld: &Path,
+    ) -> Result<()> {
+        let files = statements
+            .iter()
+            .flat_map(|x| x.inputs.iter().chain(&x.implicit_deps))
+            .map(PathBuf::from)
+            .filter(|x| !x.starts_with(bld))
+            .map(|x| {
+                let time = util::modified(&x);
+                (x, time)
+            })
+            .collect();
+        let entry = Entry {
+            hash: hash(&std::fs::read(path)?),
+            files,
+            statements: statements.to_vec(),
+        };
+        self.entries.insert(path.to_owned(), entry);
+        Ok(())
+    }
+
+    /// Write the manifest to `path`.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        util::write_if_changed(path, &serde_json::to_vec(self)?)
+    }
+}
+
+/// A hash of `x`, for telling whether something has changed. It is only
+/// stable for a given build of eos, which should be part of the context.
+pub fn hash(x: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    x.hash(&mut hasher);
+    hasher.finish()
+}
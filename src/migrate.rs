@@ -0,0 +1,475 @@
+use crate::diagnostic::{Diagnostic, Span};
+use crate::error::{Error, Result};
+use crate::spec::Module;
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+
+/// Variables of a module Makefile that only matter to the make build and
+/// have nothing to translate.
+const IGNORED: &[&str] = &[
+    "ALL_BUILDS",
+    "ALL_TARGET",
+    "CLEANLINTFILES",
+    "CONF_SRCDIR",
+    "DEF_BUILDS",
+    "INSTALL_TARGET",
+    "LINT_TARGET",
+    "LINTS",
+    "ROOTLINK",
+    "ROOTMODULE",
+    "SRCDIR",
+];
+
+/// Targets every module Makefile defines for the make build.
+const BOILERPLATE: &[&str] = &[
+    ".KEEP_STATE",
+    ".KEEP_STATE_FILE",
+    "all",
+    "check",
+    "clean",
+    "clean.lint",
+    "clobber",
+    "def",
+    "install",
+    "install_h",
+    "lint",
+    "modlintlib",
+];
+
+/// A module Makefile converted to a build spec.
+pub struct Migration {
+    /// The directory the build spec belongs in, next to the sources.
+    pub dir: PathBuf,
+    pub module: Module,
+    /// The constructs that could not be translated.
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// A line of a Makefile, with continuations joined and comments removed.
+struct Line {
+    number: usize,
+    text: String,
+}
+
+/// What a line of a Makefile does, as far as migrating it is concerned.
+enum Statement<'a> {
+    Assign {
+        name: &'a str,
+        append: bool,
+        value: &'a str,
+    },
+    Rule {
+        targets: &'a str,
+        prerequisites: &'a str,
+    },
+    /// A line with nothing to translate.
+    Include,
+    /// The start of a conditional, whose contents are read regardless.
+    Conditional,
+}
+
+/// The object lists defined by the Makefile.files fragments, as words with
+/// the file and line each came from.
+type Objects = BTreeMap<String, Vec<(String, PathBuf, usize)>>;
+
+/// Convert the module Makefile at `makefile` to a build spec. The object
+/// lists it names are looked up in `fragments`, and their sources found
+/// with the pattern rules in `rules`, the way make would. Either defaults to
+/// the Makefile.files or Makefile.rules of uts/common and of the directory
+/// above the module.
+pub fn migrate(
+    makefile: &Path,
+    fragments: &[PathBuf],
+    rules: &[PathBuf],
+) -> Result<Migration> {
+    let mut diagnostics = Vec::new();
+    let lines = read_lines(makefile)?;
+    let module_dir = match makefile.parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new("."),
+    };
+
+    let mut utsbase = "../..".to_owned();
+    let mut name = None;
+    let mut lists = Vec::new();
+    let mut dependencies = Vec::new();
+    let mut own_rules = Vec::new();
+    for line in &lines {
+        let warn = |message: String| Diagnostic {
+            span: Some(Span {
+                line: line.number,
+                column: 1,
+            }),
+            ..Diagnostic::warning(makefile, message)
+        };
+        match parse(&line.text) {
+            Some(Statement::Assign {
+                name: var, value, ..
+            }) => match var {
+                "UTSBASE" => utsbase = value.to_owned(),
+                "MODULE" => name = Some(value.to_owned()),
+                "OBJECTS" => {
+                    for word in value.split_whitespace() {
+                        match object_list(word) {
+                            Some(x) => lists.push((x.to_owned(), line.number)),
+                            None => diagnostics.push(warn(format!(
+                                "`{}` in OBJECTS is not translated",
+                                word
+                            ))),
+                        }
+                    }
+                }
+                "LDFLAGS" => {
+                    for word in value.split_whitespace() {
+                        match word.strip_prefix("-N") {
+                            Some(x) => dependencies.push(x.to_owned()),
+                            None if word == "-dy" => {}
+                            None => diagnostics.push(warn(format!(
+                                "linker flag `{}` is not translated",
+                                word
+                            ))),
+                        }
+                    }
+                }
+                "DEPENDS_ON" => dependencies
+                    .extend(value.split_whitespace().map(str::to_owned)),
+                x if IGNORED.contains(&x) => {}
+                x => diagnostics
+                    .push(warn(format!("variable `{}` is not translated", x))),
+            },
+            Some(Statement::Rule {
+                targets,
+                prerequisites,
+            }) => {
+                if targets.split_whitespace().all(|x| BOILERPLATE.contains(&x))
+                {
+                    continue;
+                }
+                match pattern_rule(targets, prerequisites) {
+                    Some(x) => own_rules.push(x.to_owned()),
+                    None => diagnostics.push(warn(format!(
+                        "rule for `{}` is not translated",
+                        targets
+                    ))),
+                }
+            }
+            Some(Statement::Include) => {}
+            Some(Statement::Conditional) => diagnostics.push(warn(
+                "conditional is not translated, all of its branches were read"
+                    .to_owned(),
+            )),
+            None => diagnostics
+                .push(warn(format!("`{}` is not understood", line.text))),
+        }
+    }
+    let name = name.ok_or_else(|| {
+        Error::Usage(format!("{}: no MODULE is set", makefile.display()))
+    })?;
+
+    let uts = normalize(&module_dir.join(&utsbase));
+    let defaults = |file: &str| {
+        let mut paths = vec![uts.join("common").join(file)];
+        paths.push(normalize(&module_dir.join("..").join(file)));
+        paths.dedup();
+        paths.into_iter().filter(|x| x.exists()).collect::<Vec<_>>()
+    };
+    let fragments = match fragments {
+        [] => defaults("Makefile.files"),
+        x => x.to_vec(),
+    };
+    let rules = match rules {
+        [] => defaults("Makefile.rules"),
+        x => x.to_vec(),
+    };
+
+    let mut objects = Objects::new();
+    for path in &fragments {
+        read_objects(path, &mut objects)?;
+    }
+    // Like make, expand paths relative to the module's directory.
+    let utsbase = PathBuf::from(utsbase);
+    let variables = [
+        ("UTSBASE", utsbase.clone()),
+        ("UTSTREE", utsbase.clone()),
+        ("SRC", utsbase.join("..")),
+        ("COMMONBASE", utsbase.join("../common")),
+    ];
+    let mut dirs: Vec<PathBuf> = own_rules
+        .iter()
+        .filter_map(|x| expand(x, &variables))
+        .map(|x| normalize(&module_dir.join(x)))
+        .collect();
+    for path in &rules {
+        for line in read_lines(path)? {
+            if let Some(Statement::Rule {
+                targets,
+                prerequisites,
+            }) = parse(&line.text)
+            {
+                if let Some(x) = pattern_rule(targets, prerequisites)
+                    .and_then(|x| expand(x, &variables))
+                {
+                    dirs.push(normalize(&module_dir.join(x)));
+                }
+            }
+        }
+    }
+
+    let mut src = Vec::new();
+    for (list, number) in lists {
+        let mut words = Vec::new();
+        expand_objects(&list, &objects, &mut Vec::new(), &mut words);
+        if words.is_empty() {
+            diagnostics.push(Diagnostic {
+                span: Some(Span {
+                    line: number,
+                    column: 1,
+                }),
+                ..Diagnostic::warning(
+                    makefile,
+                    format!("`{}` is not defined in any fragment", list),
+                )
+            });
+        }
+        for (word, file, line) in words {
+            let warn = |message: String| Diagnostic {
+                span: Some(Span { line, column: 1 }),
+                ..Diagnostic::warning(&file, message)
+            };
+            let Some(stem) = word.strip_suffix(".o") else {
+                diagnostics.push(warn(format!("`{}` is not translated", word)));
+                continue;
+            };
+            match source(stem, &dirs) {
+                Some(x) if x.extension().is_some_and(|x| x == "c") => {
+                    src.push(x)
+                }
+                Some(x) => diagnostics.push(warn(format!(
+                    "{}: only c sources can be built",
+                    x.display()
+                ))),
+                None => diagnostics
+                    .push(warn(format!("no source found for `{}`", word))),
+            }
+        }
+    }
+    // The build spec goes next to the first source, which is usually where
+    // the rest of them are too.
+    let dir = match src.first() {
+        Some(x) => x.parent().unwrap().to_owned(),
+        None => module_dir.to_owned(),
+    };
+    let mut src: Vec<String> = src
+        .iter()
+        .map(|x| relative(x, &dir).to_string_lossy().into_owned())
+        .collect();
+    src.sort();
+    src.dedup();
+    dependencies.sort();
+    dependencies.dedup();
+    let module = Module {
+        name,
+        src,
+        dependencies,
+        ..Default::default()
+    };
+    Ok(Migration {
+        dir,
+        module,
+        diagnostics,
+    })
+}
+
+/// The lines of the Makefile at `path`.
+fn read_lines(path: &Path) -> Result<Vec<Line>> {
+    let text = std::fs::read_to_string(path).map_err(|e| {
+        Error::Io(std::io::Error::new(
+            e.kind(),
+            format!("{}: {}", path.display(), e),
+        ))
+    })?;
+    let mut lines = Vec::new();
+    let mut current: Option<Line> = None;
+    for (i, raw) in text.lines().enumerate() {
+        let raw = match raw.find('#') {
+            Some(x) => &raw[..x],
+            None => raw,
+        };
+        let (raw, continued) = match raw.strip_suffix('\\') {
+            Some(x) => (x, true),
+            None => (raw, false),
+        };
+        let line = current.get_or_insert_with(|| Line {
+            number: i + 1,
+            text: String::new(),
+        });
+        if !line.text.is_empty() {
+            line.text.push(' ');
+        }
+        line.text.push_str(raw.trim());
+        if !continued {
+            let line = current.take().unwrap();
+            if !line.text.is_empty() {
+                lines.push(line);
+            }
+        }
+    }
+    lines.extend(current.filter(|x| !x.text.is_empty()));
+    Ok(lines)
+}
+
+/// What `line` does, or `None` if it is not something migrating knows.
+fn parse(line: &str) -> Option<Statement<'_>> {
+    let first = line.split_whitespace().next()?;
+    if matches!(first, "include" | "-include") {
+        return Some(Statement::Include);
+    }
+    if matches!(first, "ifeq" | "ifneq" | "ifdef" | "ifndef") {
+        return Some(Statement::Conditional);
+    }
+    if matches!(first, "else" | "endif") {
+        return Some(Statement::Include);
+    }
+    let equals = line.find('=');
+    let colon = line.find(':');
+    match (equals, colon) {
+        (Some(e), c) if c.is_none_or(|c| c + 1 >= e) => {
+            let name = line[..e].trim_end_matches([':', '+', '?']);
+            let append = line[..e].ends_with('+');
+            let name = name.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return None;
+            }
+            Some(Statement::Assign {
+                name,
+                append,
+                value: line[e + 1..].trim(),
+            })
+        }
+        (_, Some(c)) => Some(Statement::Rule {
+            targets: line[..c].trim(),
+            prerequisites: line[c + 1..].trim_start_matches(':').trim(),
+        }),
+        _ => None,
+    }
+}
+
+/// The name of the object list in a word of OBJECTS like
+/// `$(FOO_OBJS:%=$(OBJS_DIR)/%)`.
+fn object_list(word: &str) -> Option<&str> {
+    word.strip_prefix("$(")?
+        .strip_suffix(":%=$(OBJS_DIR)/%)")
+        .filter(|x| !x.contains(['$', '(', ')']))
+}
+
+/// The directory of a pattern rule building objects from the sources in it,
+/// like `$(OBJS_DIR)/%.o: $(UTSBASE)/common/io/foo/%.c`, as written.
+fn pattern_rule<'a>(targets: &str, prerequisites: &'a str) -> Option<&'a str> {
+    if targets != "$(OBJS_DIR)/%.o" {
+        return None;
+    }
+    let prerequisite = prerequisites.split_whitespace().next()?;
+    prerequisite
+        .strip_suffix("/%.c")
+        .or_else(|| prerequisite.strip_suffix("/%.s"))
+}
+
+/// `text` with the `variables` in it replaced, or `None` if it refers to any
+/// others.
+fn expand(text: &str, variables: &[(&str, PathBuf)]) -> Option<PathBuf> {
+    let mut text = text.to_owned();
+    for (name, value) in variables {
+        text = text.replace(&format!("$({})", name), &value.to_string_lossy());
+    }
+    (!text.contains('$')).then(|| PathBuf::from(text))
+}
+
+/// Add the object lists assigned in the Makefile.files fragment at `path`
+/// to `objects`.
+fn read_objects(path: &Path, objects: &mut Objects) -> Result<()> {
+    for line in read_lines(path)? {
+        if let Some(Statement::Assign {
+            name,
+            append,
+            value,
+        }) = parse(&line.text)
+        {
+            let list = objects.entry(name.to_owned()).or_default();
+            if !append {
+                list.clear();
+            }
+            list.extend(
+                value
+                    .split_whitespace()
+                    .map(|x| (x.to_owned(), path.to_owned(), line.number)),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Add the words of the object list `name` to `words`, expanding the other
+/// lists it refers to.
+fn expand_objects(
+    name: &str,
+    objects: &Objects,
+    seen: &mut Vec<String>,
+    words: &mut Vec<(String, PathBuf, usize)>,
+) {
+    if seen.iter().any(|x| x == name) {
+        return;
+    }
+    seen.push(name.to_owned());
+    for word in objects.get(name).into_iter().flatten() {
+        match word.0.strip_prefix("$(").and_then(|x| x.strip_suffix(')')) {
+            Some(x) if objects.contains_key(x) => {
+                expand_objects(x, objects, seen, words)
+            }
+            _ => words.push(word.clone()),
+        }
+    }
+}
+
+/// The source an object named `stem` is built from: the first in `dirs`
+/// that exists.
+fn source(stem: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    dirs.iter()
+        .flat_map(|x| ["c", "s"].map(|ext| x.join(format!("{}.{}", stem, ext))))
+        .find(|x| x.is_file())
+}
+
+/// `path` with `.` and `..` components resolved without looking at the file
+/// system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(
+                    result.components().next_back(),
+                    Some(Component::Normal(_))
+                ) =>
+            {
+                result.pop();
+            }
+            x => result.push(x),
+        }
+    }
+    result
+}
+
+/// `path` relative to the directory `base`, both normalized.
+fn relative(path: &Path, base: &Path) -> PathBuf {
+    let mut base = base.to_owned();
+    let mut result = PathBuf::new();
+    loop {
+        if let Ok(x) = path.strip_prefix(&base) {
+            return result.join(x);
+        }
+        if !base.pop() {
+            return result.join(path);
+        }
+        result.push("..");
+    }
+}
@@ -1,32 +1,81 @@
-use crate::VERSION;
-use std::io::Result;
+use crate::backend::Backend;
+use crate::diagnostic::Diagnostic;
+use crate::error::Result;
+use crate::target::Target;
+use crate::toolchain::{Family, Toolchain};
+use crate::util;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::Write;
+use std::path::{Path, PathBuf};
 
 /// A ninja build specification.
-#[derive(Default)]
+#[derive(Default, Serialize)]
 pub struct Spec {
+    /// Comment lines to place at the top of the ninja build spec.
+    pub header: Vec<String>,
     /// Variables to include in the ninja build spec.
     pub variables: Vec<Variable>,
+    /// Pools to include in the ninja build spec.
+    pub pools: Vec<Pool>,
     /// Rules to include in the ninja build spec.
     pub rules: Vec<RuleDefinition>,
     /// Statements to include in the ninja build spec.
     pub statements: Vec<BuildStatement>,
+    /// Literal ninja text to include in the ninja build spec.
+    pub raw: Vec<Raw>,
+    /// Other ninja files to include, each in its own scope.
+    pub subninjas: Vec<String>,
 }
 
 pub enum Rules {
     /// Used for compiling kernel modules.
     ModCompile,
+    /// Used for converting the debug information of kernel objects to CTF.
+    CtfConvert,
+    /// Used for stripping kernel objects.
+    Strip,
+    /// Used instead of stripping when objects keep their symbols.
+    Copy,
+    /// Used instead of stripping when debug information is split out of
+    /// objects.
+    Split,
     /// Used for linking kernel modules.
     ModLink,
     /// Used for linking genunix.
     GenunixLink,
+    /// Used for running code generation steps.
+    Generate,
+    /// Used for writing dyndep files with the header dependencies of objects.
+    Dyndep,
+    /// Used for copying built files into the proto area.
+    Install,
+    /// Used for writing a coverage report from the data tests collect.
+    Coverage,
+    /// Used for checking that modules only reference symbols genunix and
+    /// their dependencies define.
+    Symbols,
+    /// Ninja's built-in rule for targets that only name other targets.
+    Phony,
 }
 
-impl ToString for Rules {
-    fn to_string(&self) -> String {
+impl fmt::Display for Rules {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Rules::ModCompile => "cc_kernel".into(),
-            Rules::ModLink => "ld_kmod".into(),
-            Rules::GenunixLink => "ld_genunix".into(),
+            Rules::ModCompile => write!(f, "cc_kernel"),
+            Rules::CtfConvert => write!(f, "ctfconvert_kernel"),
+            Rules::Strip => write!(f, "strip_kernel"),
+            Rules::Copy => write!(f, "copy"),
+            Rules::Split => write!(f, "split_debug"),
+            Rules::ModLink => write!(f, "ld_kmod"),
+            Rules::GenunixLink => write!(f, "ld_genunix"),
+            Rules::Generate => write!(f, "generate"),
+            Rules::Dyndep => write!(f, "dyndep_headers"),
+            Rules::Install => write!(f, "install_file"),
+            Rules::Coverage => write!(f, "coverage_report"),
+            Rules::Symbols => write!(f, "check_symbols"),
+            Rules::Phony => write!(f, "phony"),
         }
     }
 }
@@ -34,159 +83,736 @@ impl ToString for Rules {
 impl Spec {
     /// Create and initialize a new ninja build spec. Initializes base rules and
     /// variables.
-    pub fn new() -> Spec {
+    pub fn new(toolchain: &Toolchain, target: &Target) -> Result<Spec> {
         let mut spec = Spec::default();
-        spec.init();
-        spec
+        spec.init(toolchain, target)?;
+        Ok(spec)
     }
 
     /// Initialize base rules and variables.
-    fn init(&mut self) {
-        self.init_rules();
-        self.init_variables();
+    fn init(&mut self, toolchain: &Toolchain, target: &Target) -> Result<()> {
+        self.init_rules(toolchain, target);
+        self.init_pools(toolchain);
+        self.init_toolchain_variables(toolchain);
+        self.init_variables(target)
+    }
+
+    /// Compiler flags used when compiling kernel objects for `target`, as
+    /// the compiler of the target spells them.
+    pub fn kernel_cflags(target: &Target) -> Vec<String> {
+        Self::kernel_cflag_layers(target)
+            .into_iter()
+            .flat_map(|(_, x)| x)
+            .collect()
+    }
+
+    /// The compiler flags of `kernel_cflags`, in order, each run with where it
+    /// comes from: `kernel` for the flags of all kernel objects, the
+    /// architecture for its own flags, `include` for include paths, `cross` for
+    /// cross compiling, `reproducible` for reproducible builds, `werror` for
+    /// treating warnings as errors and `profile` for the flags of the profile
+    /// built with. Built-in flags without an equivalent for the compiler of the
+    /// target are left out, and so is the built-in optimization level if the
+    /// profile sets its own. So are those the compiler was found not to
+    /// support, unless there is a substitute for them.
+    pub fn kernel_cflag_layers(target: &Target) -> Vec<(String, Vec<String>)> {
+        let arch = target.arch;
+        let optimize = !target.cflags.iter().any(|x| x.starts_with("-O"));
+        let strings = |x: Vec<&str>| {
+            x.into_iter()
+                .filter(|x| optimize || !x.starts_with("-O"))
+                .filter_map(|x| target.family.translate(x))
+                .filter_map(|x| match target.substitutes.get(x) {
+                    Some(x) => x.as_deref(),
+                    None => Some(x),
+                })
+                .map(String::from)
+                .collect()
+        };
+        vec![
+            (
+                "kernel".into(),
+                strings(vec![
+                    "-std=gnu99",
+                    "-O3",
+                    "-g",
+                    "-gdwarf-2",
+                    "-gstrict-dwarf",
+                ]),
+            ),
+            (arch.to_string(), strings(arch.kernel_cflags())),
+            (
+                "kernel".into(),
+                strings(vec![
+                    "-D__sun",
+                    "-D__SVR4",
+                    "-D_ASM_INLINES",
+                    "-D_DDI_STRICT",
+                    "-D_KERNEL",
+                    "-D_MACHDEP",
+                    "-Dlint",
+                    "-Dsun",
+                ]),
+            ),
+            (arch.to_string(), strings(arch.kernel_defines())),
+            (
+                "include".into(),
+                [
+                    arch.isa_dir(),
+                    "uts/common",
+                    "common",
+                    arch.platform_dir(),
+                    "uts/common/fs/zfs",
+                ]
+                .map(|x| target.include(x))
+                .into(),
+            ),
+            (
+                "kernel".into(),
+                strings(vec![
+                    "-ffreestanding",
+                    "-fno-inline-small-functions",
+                    "-fno-inline-functions-called-once",
+                    "-fno-ipa-cp",
+                    "-fno-ipa-icf",
+                    "-fno-clone-functions",
+                    "-fno-reorder-functions",
+                    "-fno-reorder-blocks-and-partition",
+                    "-fno-aggressive-loop-optimizations",
+                    "-fno-shrink-wrap",
+                    "-fno-asynchronous-unwind-tables",
+                    "-fstack-protector-strong",
+                    "-fdiagnostics-color=always",
+                    "--param=max-inline-insns-single=450",
+                ]),
+            ),
+            ("cross".into(), Self::cross_cflags(target)),
+            ("reproducible".into(), Self::reproducible_cflags(target)),
+            (
+                "werror".into(),
+                match target.werror {
+                    true => vec!["-Werror".to_owned()],
+                    false => Vec::new(),
+                },
+            ),
+            ("profile".into(), target.cflags.clone()),
+        ]
+    }
+
+    /// The flags telling the compiler what to cross compile for: the target
+    /// triple, which gcc has built in but clang takes as a flag, and the
+    /// system root.
+    fn cross_cflags(target: &Target) -> Vec<String> {
+        let mut flags = Vec::new();
+        if let (Some(triple), Family::Clang) = (&target.triple, target.family) {
+            flags.push(format!("--target={}", triple));
+        }
+        if let Some(sysroot) = &target.sysroot {
+            flags.push(format!("--sysroot={}", sysroot.display()));
+        }
+        flags
     }
 
-    /// Compiler flags used when compiling kernel objects.
-    pub fn kernel_cflags() -> Vec<&'static str> {
+    /// The flags that keep the directory the build runs in out of the paths
+    /// in objects, for reproducible builds.
+    fn reproducible_cflags(target: &Target) -> Vec<String> {
+        let Some(reproducible) = &target.reproducible else {
+            return Vec::new();
+        };
+        let root = reproducible.root.display();
         vec![
-            "-std=gnu99",
-            "-O3",
-            "-g",
-            "-gdwarf-2",
-            "-gstrict-dwarf",
-            "-m64",
-            "-mcmodel=kernel",
-            "-mindirect-branch-register",
-            "-mindirect-branch=thunk-extern",
-            "-mno-mmx",
-            "-mno-red-zone",
-            "-mno-sse",
-            "-msave-args",
-            "-D__sun",
-            "-D__SVR4",
-            "-D_ASM_INLINES",
-            "-D_DDI_STRICT",
-            "-D_ELF64",
-            "-D_KERNEL",
-            "-D_MACHDEP",
-            "-D_SYSCALL32",
-            "-D_SYSCALL32_IMPL",
-            "-Dlint",
-            "-Dsun",
-            "-U__i386",
-            "-Ui386",
-            "-Iusr/src/uts/intel",
-            "-Iusr/src/uts/common",
-            "-Iusr/src/common",
-            "-Iusr/src/uts/i86pc",
-            "-Iusr/src/uts/common/fs/zfs",
-            "-ffreestanding",
-            "-fno-inline-small-functions",
-            "-fno-inline-functions-called-once",
-            "-fno-ipa-cp",
-            "-fno-ipa-icf",
-            "-fno-clone-functions",
-            "-fno-reorder-functions",
-            "-fno-reorder-blocks-and-partition",
-            "-fno-aggressive-loop-optimizations",
-            "-fno-shrink-wrap",
-            "-fno-asynchronous-unwind-tables",
-            "-fstack-protector-strong",
-            "-fdiagnostics-color=always",
-            "--param=max-inline-insns-single=450",
+            format!("-ffile-prefix-map={}=.", root),
+            format!("-fdebug-prefix-map={}=.", root),
         ]
     }
 
     /// Flags to use when linking kernel components.
-    fn kernel_ldflags() -> Vec<&'static str> {
-        vec!["-ztype=kmod"]
+    pub fn kernel_ldflags(target: &Target) -> Vec<String> {
+        Self::kernel_ldflag_layers(target)
+            .into_iter()
+            .flat_map(|(_, x)| x)
+            .collect()
     }
 
-    fn init_variables(&mut self) {
+    /// The flags of `kernel_ldflags`, in order, each run with where it comes
+    /// from: `kernel`, `machine` for the emulation lld links for, or
+    /// `profile`.
+    pub fn kernel_ldflag_layers(target: &Target) -> Vec<(String, Vec<String>)> {
+        let machine = match &target.emulation {
+            Some(x) => vec!["-m".to_owned(), x.clone()],
+            None => Vec::new(),
+        };
+        vec![
+            ("kernel".into(), target.linker.kmod_flags()),
+            ("machine".into(), machine),
+            ("profile".into(), target.ldflags.clone()),
+        ]
+    }
+
+    /// Variables naming the programs invoked by the rules, so the toolchain
+    /// can be changed without touching rule text.
+    fn init_toolchain_variables(&mut self, toolchain: &Toolchain) {
+        for (name, value) in [
+            ("cc", &toolchain.cc),
+            ("ld", &toolchain.ld),
+            ("ctfconvert", &toolchain.ctfconvert),
+            ("ctfmerge", &toolchain.ctfmerge),
+            ("strip", &toolchain.strip),
+            ("objcopy", &toolchain.objcopy),
+            ("nm", &toolchain.nm),
+            ("eos", &toolchain.eos),
+        ] {
+            self.variables.push(Variable {
+                name: name.into(),
+                value: value.clone(),
+            });
+        }
+        if let Some(launcher) = &toolchain.launcher {
+            self.variables.push(Variable::new("launcher", launcher));
+        }
+        if let Some(distributed) = &toolchain.distributed {
+            self.variables
+                .push(Variable::new("wrapper", &distributed.wrapper));
+        }
+    }
+
+    fn init_variables(&mut self, target: &Target) -> Result<()> {
+        self.variables.push(Variable {
+            name: "bld".into(),
+            value: util::ninja_path(&target.bld)?,
+        });
         self.variables.push(Variable {
             name: "kernel_cflags".into(),
-            value: Self::kernel_cflags().join(" "),
+            value: Self::kernel_cflags(target).join(" "),
         });
         self.variables.push(Variable {
             name: "kernel_ldflags".into(),
-            value: Self::kernel_ldflags().join(" "),
+            value: Self::kernel_ldflags(target).join(" "),
         });
+        if !target.ctf.enabled {
+            return Ok(());
+        }
+        for (name, value) in [
+            ("ctf_label", target.ctf.label.clone()),
+            ("ctfconvert_flags", target.ctf.convert_flags.join(" ")),
+            ("ctfmerge_flags", target.ctf.merge_flags.join(" ")),
+        ] {
+            self.variables.push(Variable::new(name, &value));
+        }
+        Ok(())
     }
 
-    fn init_rules(&mut self) {
+    /// The rules of the built-in build statements. Without CTF data there
+    /// are no rules to convert or strip objects, and linking does not merge
+    /// CTF data. Only compiling goes through the compiler cache; scanning
+    /// for headers runs `$cc` itself, as the cache would only get in the
+    /// way.
+    fn init_rules(&mut self, toolchain: &Toolchain, target: &Target) {
+        let ctf = target.ctf.enabled;
+        let mut compile = toolchain.launcher_env();
+        // Reproducible builds date what the compiler dates, e.g. `__DATE__`,
+        // and the random seed gcc names some symbols with, after the output.
+        if let Some(x) = &target.reproducible {
+            compile.push(format!("SOURCE_DATE_EPOCH={}", x.epoch));
+        }
+        compile
+            .extend(toolchain.compiler_words().into_iter().map(String::from));
+        compile.push("$kernel_cflags".into());
+        if target.reproducible.is_some() {
+            compile.push("-frandom-seed=$out".into());
+        }
+        compile.push("-c $in -o $out".into());
+        let compile = compile.join(" ");
         self.rules.push(RuleDefinition {
             name: Rules::ModCompile.to_string(),
-            command: vec![
-                "gcc-10 $kernel_cflags -c $in -o $out",
-                "ctfconvert -X -l '5.11' $out",
-                "strip $out",
-            ]
-            .join(" && "),
+            command: compile,
+            variables: vec![Variable::new("description", "CC $out")],
+            ..Default::default()
         });
+        if ctf {
+            self.rules.push(RuleDefinition {
+                name: Rules::CtfConvert.to_string(),
+                command:
+                    "$ctfconvert $ctfconvert_flags -l '$ctf_label' -o $out $in"
+                        .into(),
+                variables: vec![Variable::new(
+                    "description",
+                    "CTFCONVERT $out",
+                )],
+                ..Default::default()
+            });
+            self.rules.push(RuleDefinition {
+                name: Rules::Strip.to_string(),
+                command: "cp $in $out && $strip $out".into(),
+                variables: vec![Variable::new("description", "STRIP $out")],
+                ..Default::default()
+            });
+            self.rules.push(RuleDefinition {
+                name: Rules::Split.to_string(),
+                command: [
+                    "$objcopy --only-keep-debug $in $debug",
+                    "cp $in $out",
+                    "$strip $out",
+                    "$objcopy --add-gnu-debuglink=$debug $out",
+                ]
+                .join(" && "),
+                variables: vec![Variable::new("description", "SPLIT $out")],
+                ..Default::default()
+            });
+        }
+        self.rules.push(RuleDefinition {
+            name: Rules::Copy.to_string(),
+            command: "cp $in $out".into(),
+            variables: vec![Variable::new("description", "CP $out")],
+            ..Default::default()
+        });
+        let link = |ld: &str, ctfmerge: &str| {
+            let mut commands = vec![ld];
+            if ctf {
+                commands.push(ctfmerge);
+            }
+            commands.join(" && ")
+        };
         self.rules.push(RuleDefinition {
             name: Rules::ModLink.to_string(),
-            command: vec![
-                "ld $kernel_ldflags $mod_deps -o $out $in",
-                &format!(
-                    "ctfmerge -l '{}' -d bld/genunix -o $out $in",
-                    VERSION
-                ),
-            ]
-            .join(" && "),
+            command: link(
+                "$ld $kernel_ldflags $mod_deps -o $out $in",
+                "$ctfmerge $ctfmerge_flags -l '$ctf_label' -d $bld/genunix \
+                 -o $out $in",
+            ),
+            variables: vec![Variable::new("description", "LD $out")],
+            ..Default::default()
         });
         self.rules.push(RuleDefinition {
             name: Rules::GenunixLink.to_string(),
-            command: vec![
-                "ld $kernel_ldflags -o $out $in",
-                &format!("ctfmerge -l '{}' -o $out $in", VERSION),
+            command: link(
+                "$ld $kernel_ldflags -o $out $in",
+                "$ctfmerge $ctfmerge_flags -l '$ctf_label' -o $out $in",
+            ),
+            variables: vec![Variable::new("description", "LD $out")],
+            ..Default::default()
+        });
+        self.rules.push(RuleDefinition {
+            name: Rules::Generate.to_string(),
+            command: "$command".into(),
+            variables: vec![Variable::new("description", "GEN $out")],
+            ..Default::default()
+        });
+        self.rules.push(RuleDefinition {
+            name: Rules::Install.to_string(),
+            command: "cp $in $out && chmod $mode $out".into(),
+            variables: vec![Variable::new("description", "INSTALL $out")],
+            ..Default::default()
+        });
+        self.rules.push(RuleDefinition {
+            name: Rules::Dyndep.to_string(),
+            command: format!(
+                "$eos --compiler $cc --header-deps {} dyndep -o $out{} \
+                 $objects -- $kernel_cflags",
+                target.header_deps,
+                target
+                    .header_roots()
+                    .iter()
+                    .map(|x| format!(" --root {}", x.display()))
+                    .collect::<String>()
+            ),
+            variables: vec![Variable::new("description", "DYNDEP $out")],
+            ..Default::default()
+        });
+        if target.check_symbols {
+            self.rules.push(RuleDefinition {
+                name: Rules::Symbols.to_string(),
+                command: "$eos check-symbols --nm $nm -o $out $in $providers"
+                    .into(),
+                variables: vec![Variable::new("description", "NM $in")],
+                ..Default::default()
+            });
+        }
+    }
+
+    /// When compiling is distributed, the pools that keep more compile
+    /// commands than this machine could run going, and the other built-in
+    /// rules to as many as it can.
+    fn init_pools(&mut self, toolchain: &Toolchain) {
+        let Some(distributed) = &toolchain.distributed else {
+            return;
+        };
+        self.pools.push(Pool {
+            name: "remote".into(),
+            depth: distributed.jobs,
+        });
+        self.pools.push(Pool {
+            name: "local".into(),
+            depth: distributed.local_jobs(),
+        });
+        for rule in &mut self.rules {
+            Spec::assign_pool(&self.pools, rule);
+        }
+    }
+
+    /// Run `rule` in one of `pools`, if compiling is distributed: compile
+    /// commands in the remote pool and everything else in the local one.
+    /// Rules that already choose a pool keep it.
+    fn assign_pool(pools: &[Pool], rule: &mut RuleDefinition) {
+        if pools.is_empty() || rule.variables.iter().any(|x| x.name == "pool") {
+            return;
+        }
+        let pool = match rule.name == Rules::ModCompile.to_string() {
+            true => "remote",
+            false => "local",
+        };
+        rule.variables.push(Variable::new("pool", pool));
+    }
+
+    /// Add the statement that merges the `.gcda` files objects built for a
+    /// coverage `target` leave next to them into an lcov report, with an
+    /// HTML version alongside. It always runs, as ninja cannot tell when
+    /// tests have written new data.
+    pub fn add_coverage(&mut self, target: &Target) -> Result<()> {
+        let mut rule = RuleDefinition {
+            name: Rules::Coverage.to_string(),
+            command: [
+                "lcov --quiet --capture --directory $bld --output-file $out",
+                "genhtml --quiet --output-directory $html $out",
             ]
             .join(" && "),
+            variables: vec![Variable::new("description", "LCOV $out")],
+            ..Default::default()
+        };
+        Spec::assign_pool(&self.pools, &mut rule);
+        self.rules.push(rule);
+        let always = util::ninja_path(&target.bld.join("coverage.always"))?;
+        self.statements.push(BuildStatement {
+            output: always.clone(),
+            rule: Rules::Phony.to_string(),
+            ..Default::default()
+        });
+        let html = target.bld.join("coverage-html");
+        self.statements.push(BuildStatement {
+            output: target.coverage()?,
+            rule: Rules::Coverage.to_string(),
+            implicit_deps: vec![always],
+            variables: vec![Variable::new("html", &util::ninja_path(&html)?)],
+            ..Default::default()
         });
+        Ok(())
+    }
+
+    /// Add a rule defined by the build spec at `origin`. A rule that is
+    /// already defined identically is not added again, a conflicting definition
+    /// is an error.
+    pub fn add_rule(
+        &mut self,
+        mut rule: RuleDefinition,
+        origin: &Path,
+    ) -> Result<()> {
+        Spec::assign_pool(&self.pools, &mut rule);
+        match self.rules.iter().find(|r| r.name == rule.name) {
+            Some(r)
+                if r.command == rule.command
+                    && r.variables == rule.variables =>
+            {
+                Ok(())
+            }
+            Some(r) => Err(Diagnostic::error(
+                origin,
+                format!(
+                    "rule {} conflicts with the definition in {}",
+                    rule.name,
+                    match &r.origin {
+                        Some(path) => path.display().to_string(),
+                        None => "eos".to_owned(),
+                    }
+                ),
+            )
+            .into()),
+            None => {
+                self.rules.push(RuleDefinition {
+                    origin: Some(origin.to_path_buf()),
+                    ..rule
+                });
+                Ok(())
+            }
+        }
     }
 
-    /// Emit this ninja spec as a string.
-    fn emit(&self) -> String {
-        let s = self.emit_variables();
-        let s = s + &self.emit_rules();
-        s + &self.emit_statements()
+    /// Check this spec for problems ninja would otherwise report without
+    /// pointing back at the build spec responsible.
+    pub fn validate(&self) -> Result<()> {
+        self.check_rules()?;
+        self.check_duplicate_outputs()?;
+        self.check_inputs()
+    }
+
+    /// Ensure every build statement uses a rule that is defined.
+    fn check_rules(&self) -> Result<()> {
+        let rules: HashSet<&str> =
+            self.rules.iter().map(|x| x.name.as_str()).collect();
+        let phony = Rules::Phony.to_string();
+        for stmt in &self.statements {
+            if !rules.contains(stmt.rule.as_str()) && stmt.rule != phony {
+                let message = format!(
+                    "{} uses undefined rule {}",
+                    stmt.output, stmt.rule
+                );
+                return Err(match &stmt.origin {
+                    Some(path) => Diagnostic::error(path, message).into(),
+                    None => Diagnostic::general(message).into(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Ensure every input of every build statement either exists or is
+    /// produced by another build statement.
+    fn check_inputs(&self) -> Result<()> {
+        let outputs: HashSet<&str> =
+            self.statements.iter().flat_map(|x| x.outputs()).collect();
+        let mut missing = Vec::new();
+        for stmt in &self.statements {
+            for input in stmt
+                .inputs
+                .iter()
+                .chain(&stmt.implicit_deps)
+                .chain(&stmt.order_only_deps)
+            {
+                if !outputs.contains(input.as_str())
+                    && !Path::new(input).exists()
+                {
+                    missing.push(format!(
+                        "  {} (needed by {} in {})",
+                        input,
+                        stmt.output,
+                        stmt.origin_display()
+                    ));
+                }
+            }
+        }
+        if !missing.is_empty() {
+            return Err(Diagnostic::general(format!(
+                "inputs neither exist nor are built:\n{}",
+                missing.join("\n")
+            ))
+            .into());
+        }
+        Ok(())
     }
 
-    /// Emit this ninja spec to the file build.ninja.
-    pub fn emit_file(&self) -> Result<()> {
-        let out = self.emit();
-        std::fs::write("build.ninja", out)?;
+    /// Ensure no two build statements produce the same output.
+    fn check_duplicate_outputs(&self) -> Result<()> {
+        let mut producers: HashMap<&str, &BuildStatement> = HashMap::new();
+        for stmt in &self.statements {
+            for out in stmt.outputs() {
+                if let Some(prev) = producers.insert(out, stmt) {
+                    return Err(Diagnostic::general(format!(
+                        "{}: output produced by both {} and {}",
+                        out,
+                        prev.origin_display(),
+                        stmt.origin_display(),
+                    ))
+                    .into());
+                }
+            }
+        }
         Ok(())
     }
 
-    /// Emit the variables in this spec in text form.
-    fn emit_variables(&self) -> String {
+    /// Evaluate the command ninja will run for `stmt`, expanding all variable
+    /// references. Returns `None` if the statement's rule is not part of this
+    /// spec.
+    pub fn command(&self, stmt: &BuildStatement) -> Option<String> {
+        let rule = self.rules.iter().find(|r| r.name == stmt.rule)?;
+        let top = self.variables.len();
+        Some(self.evaluate(&rule.command, top, &|name| {
+            match name {
+                "in" => Some(stmt.inputs.join(" ")),
+                "out" => Some(stmt.output.clone()),
+                _ => stmt
+                    .variables
+                    .iter()
+                    .chain(rule.variables.iter())
+                    .find(|v| v.name == name)
+                    .map(|v| self.evaluate(&v.value, top, &|_| None)),
+            }
+        }))
+    }
+
+    /// Expand the variable references in `text`, consulting `local` before the
+    /// first `top` top level variables of this spec. A top level variable is
+    /// expanded against those defined before it, as in ninja, which lets a
+    /// definition refer to an earlier one of the same name. Unknown variables
+    /// expand to nothing.
+    fn evaluate(
+        &self,
+        text: &str,
+        top: usize,
+        local: &dyn Fn(&str) -> Option<String>,
+    ) -> String {
         let mut s = String::new();
-        for d in &self.variables {
-            s += &d.emit();
+        for token in tokenize(text) {
+            match token {
+                Token::Literal(text) => s += &text,
+                Token::Variable(name) => {
+                    if let Some(value) = local(&name) {
+                        s += &value;
+                    } else if let Some(i) = self.variables[..top]
+                        .iter()
+                        .rposition(|v| v.name == name)
+                    {
+                        s += &self.evaluate(
+                            &self.variables[i].value,
+                            i,
+                            &|_| None,
+                        );
+                    }
+                }
+            }
         }
         s
     }
 
-    /// Emit the rules in this spec in text form.
-    fn emit_rules(&self) -> String {
-        let mut s = String::new();
+    /// Emit the header comments in this spec.
+    fn emit_header(&self, w: &mut dyn Write) -> Result<()> {
+        for line in &self.header {
+            writeln!(w, "# {}", line)?;
+        }
+        Ok(())
+    }
+
+    /// Emit the variables in this spec.
+    fn emit_variables(&self, w: &mut dyn Write) -> Result<()> {
+        for d in &self.variables {
+            d.emit(w)?;
+        }
+        Ok(())
+    }
+
+    /// Emit the pools in this spec.
+    fn emit_pools(&self, w: &mut dyn Write) -> Result<()> {
+        for p in &self.pools {
+            p.emit(w)?;
+        }
+        Ok(())
+    }
+
+    /// Emit the rules in this spec.
+    fn emit_rules(&self, w: &mut dyn Write) -> Result<()> {
         for r in &self.rules {
-            s += &r.emit();
+            r.emit(w)?;
         }
-        s
+        Ok(())
     }
 
-    /// Emit the build statements in this spec in text form.
-    fn emit_statements(&self) -> String {
-        let mut s = String::new();
-        for stmt in &self.statements {
-            s += &stmt.emit();
+    /// Emit the build statements in this spec. Each run of statements that
+    /// came from the same build spec is preceded by a comment naming that
+    /// spec, and a run of statements eos adds itself after one from a build
+    /// spec by `# from eos`.
+    fn emit_statements(&self, w: &mut dyn Write) -> Result<()> {
+        let mut origin = None;
+        for (i, stmt) in self.statements.iter().enumerate() {
+            if stmt.origin.as_ref() != origin
+                && (i > 0 || stmt.origin.is_some())
+            {
+                write!(w, "\n# from {}\n", stmt.origin_display())?;
+            }
+            origin = stmt.origin.as_ref();
+            stmt.emit(w)?;
         }
-        s
+        Ok(())
+    }
+}
+
+impl Backend for Spec {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        self.emit_header(w)?;
+        self.emit_variables(w)?;
+        self.emit_pools(w)?;
+        self.emit_body(w)
+    }
+}
+
+/// A build spec included from another with `subninja`, which declares its
+/// pools instead, as ninja only lets a pool be declared once.
+pub struct Included<'a>(pub &'a Spec);
+
+impl Backend for Included<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        self.0.emit_header(w)?;
+        self.0.emit_variables(w)?;
+        self.0.emit_body(w)
+    }
+}
+
+impl Spec {
+    /// Emit the rules, statements and included files in this spec.
+    fn emit_body(&self, w: &mut dyn Write) -> Result<()> {
+        self.emit_rules(w)?;
+        self.emit_statements(w)?;
+        for raw in &self.raw {
+            write!(w, "\n# raw ninja from {}\n", raw.origin.display())?;
+            writeln!(w, "{}", raw.text.trim_end())?;
+        }
+        for path in &self.subninjas {
+            writeln!(w, "subninja {}", path)?;
+        }
+        Ok(())
+    }
+}
+
+/// A piece of ninja text.
+#[derive(Debug, PartialEq)]
+pub enum Token {
+    /// Literal text, with ninja escapes resolved.
+    Literal(String),
+    /// A `$name` or `${name}` variable reference.
+    Variable(String),
+}
+
+/// Split ninja text into literal text and variable references.
+pub fn tokenize(text: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '$' {
+            literal.push(c);
+            continue;
+        }
+        let name = match chars.peek() {
+            Some('$') | Some(' ') | Some(':') => {
+                literal.push(chars.next().unwrap());
+                continue;
+            }
+            Some('{') => {
+                chars.next();
+                chars.by_ref().take_while(|c| *c != '}').collect()
+            }
+            _ => {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        break;
+                    }
+                    name.push(c);
+                    chars.next();
+                }
+                name
+            }
+        };
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(std::mem::take(&mut literal)));
+        }
+        tokens.push(Token::Variable(name));
+    }
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
     }
+    tokens
 }
 
 /// A ninja variable
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct Variable {
     /// Name of the variable
     pub name: String,
@@ -195,32 +821,77 @@ pub struct Variable {
 }
 
 impl Variable {
+    /// Create a new variable.
+    pub fn new(name: &str, value: &str) -> Variable {
+        Variable {
+            name: name.into(),
+            value: value.into(),
+        }
+    }
+
     /// Emit this variable in text form.
-    fn emit(&self) -> String {
-        format!("{} = {}\n", self.name, self.value)
+    fn emit(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(w, "{} = {}", self.name, self.value)
+    }
+}
+
+/// A ninja pool, which limits how many commands of the rules in it run at
+/// once.
+#[derive(Clone, Serialize)]
+pub struct Pool {
+    /// Name of the pool
+    pub name: String,
+    /// How many commands may run at once
+    pub depth: usize,
+}
+
+impl Pool {
+    /// Emit this pool in text form.
+    fn emit(&self, w: &mut dyn Write) -> std::io::Result<()> {
+        writeln!(w, "pool {}\n  depth = {}", self.name, self.depth)
     }
 }
 
 /// A ninja rule definition.
+#[derive(Default, Serialize)]
 pub struct RuleDefinition {
     /// Name of the rule
     pub name: String,
     /// Command text
     pub command: String,
+    /// Additional rule variables such as `description`, `depfile`, `deps`,
+    /// `pool`, `restat` or `rspfile`.
+    pub variables: Vec<Variable>,
+    /// The build spec that defined this rule, if any.
+    pub origin: Option<PathBuf>,
 }
 
 impl RuleDefinition {
     /// Emit this rule in text form.
-    fn emit(&self) -> String {
-        format!("rule {}\n  command = {}\n", self.name, self.command)
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        write!(w, "rule {}\n  command = {}\n", self.name, self.command)?;
+        for v in &self.variables {
+            write!(w, "  ")?;
+            v.emit(w)?;
+        }
+        Ok(())
     }
 }
 
+/// Literal ninja text.
+#[derive(Serialize)]
+pub struct Raw {
+    /// The ninja text.
+    pub text: String,
+    /// The build spec the text came from.
+    pub origin: PathBuf,
+}
+
 /// A ninja build statement.
-#[derive(Default)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct BuildStatement {
     /// Explicit inputs.
-    pub input: String,
+    pub inputs: Vec<String>,
     /// What to produce.
     pub output: String,
     /// What build rule to use.
@@ -229,21 +900,203 @@ pub struct BuildStatement {
     pub variables: Vec<Variable>,
     /// Optional implicit dependencies.
     pub implicit_deps: Vec<String>,
+    /// Optional implicit outputs, produced as a side effect of building the
+    /// explicit output.
+    pub implicit_outputs: Vec<String>,
+    /// Optional order-only dependencies, which must be built first but do
+    /// not cause a rebuild when they change.
+    pub order_only_deps: Vec<String>,
+    /// Optional dyndep file supplying additional dependencies discovered
+    /// during the build. It must also be an input of this statement.
+    pub dyndep: Option<String>,
+    /// The build spec this statement was generated from, if any.
+    pub origin: Option<PathBuf>,
 }
 
 impl BuildStatement {
+    /// All outputs of this build statement, explicit and implicit.
+    pub fn outputs(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.output.as_str())
+            .chain(self.implicit_outputs.iter().map(|x| x.as_str()))
+    }
+
+    /// A printable name for the build spec this statement came from.
+    pub fn origin_display(&self) -> String {
+        match &self.origin {
+            Some(path) => path.display().to_string(),
+            None => "eos".to_owned(),
+        }
+    }
+
     /// Emit this build statement in text form.
-    fn emit(&self) -> String {
-        let mut s =
-            format!("build {}: {} {}", self.output, self.rule, self.input);
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        write!(w, "build {}", self.output)?;
+        if !self.implicit_outputs.is_empty() {
+            write!(w, " | {}", self.implicit_outputs.join(" "))?;
+        }
+        write!(w, ": {}", self.rule)?;
+        for input in &self.inputs {
+            write!(w, " {}", input)?;
+        }
         if !self.implicit_deps.is_empty() {
-            s += &format!(" | {}\n", self.implicit_deps.join(" "));
-        } else {
-            s += "\n";
+            write!(w, " | {}", self.implicit_deps.join(" "))?;
         }
+        if !self.order_only_deps.is_empty() {
+            write!(w, " || {}", self.order_only_deps.join(" "))?;
+        }
+        writeln!(w)?;
         for d in &self.variables {
-            s += &format!("  {}", d.emit());
+            write!(w, "  ")?;
+            d.emit(w)?;
         }
-        s
+        if let Some(dyndep) = &self.dyndep {
+            writeln!(w, "  dyndep = {}", dyndep)?;
+        }
+        Ok(())
+    }
+}
+
+/// A ninja dyndep file, supplying dependency information for build statements
+/// that is only known once part of the build has run.
+#[derive(Default)]
+pub struct Dyndep {
+    /// The build statements to supply information for.
+    pub statements: Vec<DyndepStatement>,
+}
+
+/// The dynamically discovered dependencies of a single build statement.
+#[derive(Default)]
+pub struct DyndepStatement {
+    /// An explicit output of the build statement this applies to.
+    pub output: String,
+    /// Additional implicit outputs of the build statement.
+    pub implicit_outputs: Vec<String>,
+    /// Additional implicit dependencies of the build statement.
+    pub implicit_deps: Vec<String>,
+}
+
+impl Backend for Dyndep {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        writeln!(w, "ninja_dyndep_version = 1")?;
+        for stmt in &self.statements {
+            write!(w, "build {}", stmt.output)?;
+            if !stmt.implicit_outputs.is_empty() {
+                write!(w, " | {}", stmt.implicit_outputs.join(" "))?;
+            }
+            write!(w, ": dyndep")?;
+            if !stmt.implicit_deps.is_empty() {
+                write!(w, " | {}", stmt.implicit_deps.join(" "))?;
+            }
+            writeln!(w)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A spec with a `cc` rule and one statement using it.
+    fn spec(variables: Vec<Variable>) -> Spec {
+        Spec {
+            variables,
+            rules: vec![RuleDefinition {
+                name: "cc".into(),
+                command: "cc $cflags -c $in -o $out".into(),
+                ..Default::default()
+            }],
+            statements: vec![BuildStatement {
+                inputs: vec!["a.c".into()],
+                output: "a.o".into(),
+                rule: "cc".into(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn command_expands_self_reference_against_earlier_definition() {
+        let spec = spec(vec![
+            Variable::new("cflags", "-O2"),
+            Variable::new("cflags", "$cflags -DDEBUG"),
+        ]);
+        assert_eq!(
+            spec.command(&spec.statements[0]).unwrap(),
+            "cc -O2 -DDEBUG -c a.c -o a.o"
+        );
+    }
+
+    #[test]
+    fn command_prefers_statement_variables_to_rule_and_top_level_ones() {
+        let mut spec = spec(vec![Variable::new("cflags", "-O2")]);
+        spec.rules[0].variables.push(Variable::new("cflags", "-O1"));
+        spec.statements[0]
+            .variables
+            .push(Variable::new("cflags", "$cflags -g"));
+        assert_eq!(
+            spec.command(&spec.statements[0]).unwrap(),
+            "cc -O2 -g -c a.c -o a.o"
+        );
+    }
+
+    #[test]
+    fn command_of_unknown_rule_is_none() {
+        let spec = spec(Vec::new());
+        let stmt = BuildStatement {
+            rule: "ld".into(),
+            ..Default::default()
+        };
+        assert_eq!(spec.command(&stmt), None);
+    }
+
+    #[test]
+    fn tokenize_splits_variables_from_literals() {
+        assert_eq!(
+            tokenize("cc $cflags -o ${out}.tmp $in"),
+            vec![
+                Token::Literal("cc ".into()),
+                Token::Variable("cflags".into()),
+                Token::Literal(" -o ".into()),
+                Token::Variable("out".into()),
+                Token::Literal(".tmp ".into()),
+                Token::Variable("in".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tokenize_resolves_escapes() {
+        assert_eq!(
+            tokenize("a$$b$ c$:d"),
+            vec![Token::Literal("a$b c:d".into())]
+        );
+    }
+
+    #[test]
+    fn emit_writes_variables_rules_and_statements() {
+        let mut spec = spec(vec![Variable::new("cflags", "-O2")]);
+        spec.statements[0].implicit_deps.push("a.h".into());
+        spec.statements[0]
+            .variables
+            .push(Variable::new("cflags", "-g"));
+        let mut out = Vec::new();
+        spec.emit(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("cflags = -O2\n"));
+        assert!(
+            text.contains("rule cc\n  command = cc $cflags -c $in -o $out\n")
+        );
+        assert!(text.contains("build a.o: cc a.c | a.h\n  cflags = -g\n"));
+    }
+
+    #[test]
+    fn command_expands_first_self_reference_to_nothing() {
+        let spec = spec(vec![Variable::new("cflags", "$cflags -DDEBUG")]);
+        assert_eq!(
+            spec.command(&spec.statements[0]).unwrap(),
+            "cc  -DDEBUG -c a.c -o a.o"
+        );
     }
 }
@@ -0,0 +1,201 @@
+use crate::backend::Backend;
+use crate::error::{Error, Result};
+use crate::ninja;
+use crate::spec::Spec;
+use crate::target::Target;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// An IPS package manifest for the files build specs install into one proto
+/// area.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    /// The package name and version, e.g. `driver/foo@0.5.11`.
+    pub fmri: String,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    /// The packages this package requires, as FMRIs.
+    pub dependencies: BTreeSet<String>,
+    /// The files delivered, relative to the proto area, with their
+    /// permissions.
+    pub files: BTreeMap<PathBuf, String>,
+    /// The drivers delivered, by name.
+    pub drivers: BTreeSet<String>,
+}
+
+/// The manifests of the packages the build specs in `specs` describe, with
+/// the files `ninja_spec` installs for `target`, by package name. Files of
+/// build specs without a package are not delivered by any.
+pub fn manifests(
+    specs: &[&(PathBuf, Spec)],
+    ninja_spec: &ninja::Spec,
+    target: &Target,
+    version: &str,
+) -> BTreeMap<String, Manifest> {
+    let mut manifests = BTreeMap::new();
+    let install = ninja::Rules::Install.to_string();
+    for (path, spec) in specs {
+        let Some(package) = &spec.package else {
+            continue;
+        };
+        let manifest =
+            manifests
+                .entry(package.name.clone())
+                .or_insert_with(|| Manifest {
+                    fmri: format!("{}@{}", package.name, version),
+                    ..Default::default()
+                });
+        if manifest.summary.is_none() {
+            manifest.summary = package.summary.clone();
+        }
+        if manifest.description.is_none() {
+            manifest.description = package.description.clone();
+        }
+        manifest.dependencies.extend(
+            package
+                .dependencies
+                .iter()
+                .map(|x| format!("{}@{}", x, version)),
+        );
+        if let Some(module) = &spec.module {
+            if module.class.as_deref() == Some("drv") {
+                manifest.drivers.insert(module.name.clone());
+            }
+        }
+        for stmt in &ninja_spec.statements {
+            if stmt.rule != install || stmt.origin.as_ref() != Some(path) {
+                continue;
+            }
+            let Ok(file) = Path::new(&stmt.output).strip_prefix(&target.proto)
+            else {
+                continue;
+            };
+            let mode = stmt
+                .variables
+                .iter()
+                .find(|x| x.name == "mode")
+                .map_or("0644", |x| x.value.as_str());
+            manifest.files.insert(file.to_owned(), mode.to_owned());
+        }
+    }
+    manifests
+}
+
+/// The name of the manifest file for the package `name`.
+pub fn file_name(name: &str) -> String {
+    format!("{}.p5m", name.replace('/', "-"))
+}
+
+impl Manifest {
+    /// The directories the files of this package are in, including their
+    /// parents.
+    fn dirs(&self) -> BTreeSet<&Path> {
+        self.files
+            .keys()
+            .flat_map(|x| x.ancestors().skip(1))
+            .filter(|x| !x.as_os_str().is_empty())
+            .collect()
+    }
+}
+
+impl Backend for Manifest {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        writeln!(w, "set name=pkg.fmri value=pkg:/{}", self.fmri)?;
+        if let Some(x) = &self.summary {
+            writeln!(w, "set name=pkg.summary value={}", quote(x))?;
+        }
+        if let Some(x) = &self.description {
+            writeln!(w, "set name=pkg.description value={}", quote(x))?;
+        }
+        for dir in self.dirs() {
+            writeln!(
+                w,
+                "dir path={} owner=root group={} mode=0755",
+                quote(&dir.to_string_lossy()),
+                group(dir)
+            )?;
+        }
+        for (file, mode) in &self.files {
+            writeln!(
+                w,
+                "file path={} owner=root group={} mode={}",
+                quote(&file.to_string_lossy()),
+                group(file),
+                mode
+            )?;
+        }
+        for driver in &self.drivers {
+            writeln!(w, "driver name={}", driver)?;
+        }
+        for dependency in &self.dependencies {
+            writeln!(w, "depend fmri=pkg:/{} type=require", dependency)?;
+        }
+        Ok(())
+    }
+}
+
+/// The group owning `path` once installed: `sys` for the kernel, `bin` for
+/// everything else.
+fn group(path: &Path) -> &'static str {
+    match path.starts_with("kernel") || path.starts_with("platform") {
+        true => "sys",
+        false => "bin",
+    }
+}
+
+/// `value` quoted for a manifest action if it needs to be.
+fn quote(value: &str) -> String {
+    if value.is_empty() || value.contains([' ', '\t', '"', '\\', '=']) {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Publish the package whose manifest is at `manifest` to `repo`, with the
+/// files it delivers taken from `proto`.
+pub fn publish(repo: &str, proto: &Path, manifest: &Path) -> Result<()> {
+    run(Command::new("pkgsend")
+        .arg("publish")
+        .arg("-s")
+        .arg(repo)
+        .arg("-d")
+        .arg(proto)
+        .arg(manifest))
+}
+
+/// Create the package repository `repo` for `publisher` if it is a local
+/// directory that does not exist yet.
+pub fn create_repo(repo: &str, publisher: &str) -> Result<()> {
+    if repo.contains("://") || Path::new(repo).exists() {
+        return Ok(());
+    }
+    run(Command::new("pkgrepo").arg("create").arg(repo))?;
+    run(Command::new("pkgrepo")
+        .arg("set")
+        .arg("-s")
+        .arg(repo)
+        .arg(format!("publisher/prefix={}", publisher)))
+}
+
+/// Refresh the catalog of `repo` after publishing to it.
+pub fn refresh_repo(repo: &str) -> Result<()> {
+    run(Command::new("pkgrepo").arg("refresh").arg("-s").arg(repo))
+}
+
+/// Run `command`, failing if it does not succeed.
+fn run(command: &mut Command) -> Result<()> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let status = command.status().map_err(|e| {
+        Error::Toolchain(format!("failed to run {}: {}", program, e))
+    })?;
+    if !status.success() {
+        return Err(Error::Toolchain(format!(
+            "{} failed: {}",
+            program, status
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use crate::error::Result;
+use crate::manifest;
+use crate::ninja;
+use crate::target::Target;
+use crate::toolchain::Toolchain;
+use crate::util;
+use rayon::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::process::{Command, Stdio};
+use tracing::{debug, warn};
+
+/// The name of the file the results of probing are kept in, in an output
+/// directory.
+pub const FILE_NAME: &str = ".eos-flags.json";
+
+/// Flags to use in place of built-in ones a compiler does not support, if it
+/// supports these.
+const SUBSTITUTES: &[(&str, &str)] = &[
+    ("-fstack-protector-strong", "-fstack-protector"),
+    ("-fno-reorder-blocks-and-partition", "-fno-reorder-blocks"),
+];
+
+/// Which flags a compiler supports, from an earlier run.
+#[derive(Serialize, Deserialize, Default)]
+struct Cache {
+    /// Identifies the compiler the flags were probed with.
+    context: u64,
+    /// Whether the compiler accepts each flag.
+    supported: BTreeMap<String, bool>,
+}
+
+/// Probe the compiler of `toolchain`, which reports `cc_version`, for the
+/// built-in flags `target` compiles kernel objects with, and have `target`
+/// leave out those it does not support, or use a substitute for them. Flags
+/// are only probed again when the compiler changes.
+pub fn probe(
+    toolchain: &Toolchain,
+    cc_version: &Option<String>,
+    target: &mut Target,
+) -> Result<()> {
+    target.substitutes.clear();
+    let layers = ninja::Spec::kernel_cflag_layers(target);
+    let arch = target.arch.to_string();
+    // Flags are probed with those that choose what to compile for, which
+    // others can depend on.
+    let base: Vec<&String> = layers
+        .iter()
+        .filter(|(origin, _)| *origin == "cross")
+        .flat_map(|(_, x)| x)
+        .chain(
+            layers
+                .iter()
+                .filter(|(origin, _)| *origin == arch)
+                .flat_map(|(_, x)| x)
+                .filter(|x| *x == "-m32" || *x == "-m64"),
+        )
+        .collect();
+    let mut flags: Vec<&str> = layers
+        .iter()
+        .filter(|(origin, _)| *origin == "kernel" || *origin == arch)
+        .flat_map(|(_, x)| x)
+        .map(|x| x.as_str())
+        .filter(|x| {
+            x.starts_with("-f") || x.starts_with("-m") || x.starts_with("--")
+        })
+        .collect();
+    flags.extend(
+        SUBSTITUTES
+            .iter()
+            .filter(|(x, _)| flags.contains(x))
+            .map(|(_, x)| *x)
+            .collect::<Vec<_>>(),
+    );
+
+    let path = target.bld.join(FILE_NAME);
+    let context = manifest::hash((&toolchain.cc, cc_version, &base));
+    let mut cache = std::fs::read(&path)
+        .ok()
+        .and_then(|x| serde_json::from_slice::<Cache>(&x).ok())
+        .filter(|x| x.context == context)
+        .unwrap_or(Cache {
+            context,
+            ..Default::default()
+        });
+    let missing: Vec<&str> = flags
+        .iter()
+        .copied()
+        .filter(|x| !cache.supported.contains_key(*x))
+        .collect();
+    if !missing.is_empty() {
+        if !accepts(&toolchain.cc, &base, None) {
+            warn!(
+                "cannot probe {} for the flags it supports, as it fails to \
+                 compile an empty file",
+                toolchain.cc
+            );
+            return Ok(());
+        }
+        let results: Vec<(&str, bool)> = missing
+            .par_iter()
+            .map(|x| (*x, accepts(&toolchain.cc, &base, Some(x))))
+            .collect();
+        cache
+            .supported
+            .extend(results.into_iter().map(|(x, y)| (x.to_owned(), y)));
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        util::write_if_changed(&path, &serde_json::to_vec(&cache)?)?;
+    }
+
+    for flag in flags {
+        if cache.supported.get(flag) != Some(&false) {
+            continue;
+        }
+        let substitute = SUBSTITUTES
+            .iter()
+            .find(|(x, y)| *x == flag && cache.supported.get(*y) == Some(&true))
+            .map(|(_, x)| x.to_string());
+        let message = match &substitute {
+            Some(x) => format!(
+                "{} does not support {}, using {} instead",
+                toolchain.cc, flag, x
+            ),
+            None => format!(
+                "{} does not support {}, leaving it out",
+                toolchain.cc, flag
+            ),
+        };
+        match missing.contains(&flag) {
+            true => warn!("{}", message),
+            false => debug!("{}", message),
+        }
+        target.substitutes.insert(flag.to_owned(), substitute);
+    }
+    Ok(())
+}
+
+/// Whether `cc` accepts `flag` when checking an empty file with `base`, or
+/// the file at all if there is no `flag`. Only a flag the compiler does not
+/// recognize counts against it, not one that conflicts with how it was
+/// configured, e.g. `-mcmodel=kernel` with a compiler that defaults to PIE.
+fn accepts(cc: &str, base: &[&String], flag: Option<&str>) -> bool {
+    let output = Command::new(cc)
+        .args(base)
+        .arg("-Werror")
+        .args(flag)
+        .args(["-fsyntax-only", "-x", "c", "-"])
+        .stdin(Stdio::null())
+        .output();
+    let Ok(output) = output else {
+        return false;
+    };
+    if output.status.success() {
+        return true;
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    flag.is_some()
+        && !["unrecognized", "unknown argument", "is not supported"]
+            .iter()
+            .any(|x| stderr.contains(x))
+}
@@ -0,0 +1,67 @@
+use crate::cli::{Args, ColorChoice, MessageFormat};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::error::Error;
+use std::io::IsTerminal;
+use tracing::{error, warn};
+
+/// Log to stderr at the level selected by `-v` and `-q`, unless RUST_LOG
+/// says otherwise, in color if `--color` allows it.
+pub fn init(args: &Args) {
+    let json = args.message_format == MessageFormat::Json;
+    let level = match i16::from(args.verbose) - i16::from(args.quiet) {
+        // JSON diagnostics should not be mixed with log lines unless asked
+        // for.
+        _ if json && args.verbose == 0 => "off",
+        ..=-2 => "error",
+        -1 => "warn",
+        0 => "info",
+        1 => "debug",
+        2.. => "trace",
+    };
+    let filter = tracing_subscriber::EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| tracing_subscriber::EnvFilter::new(level));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(color(args.color))
+        .with_target(false)
+        .without_time()
+        .init();
+}
+
+/// Write `d` in the format selected by `args`.
+pub fn diagnostic(args: &Args, d: &Diagnostic) {
+    match (args.message_format, d.severity) {
+        (MessageFormat::Human, Severity::Error) => error!("{}", d),
+        (MessageFormat::Human, Severity::Warning) => warn!("{}", d),
+        (MessageFormat::Json, _) => {
+            eprintln!("{}", serde_json::to_string(d).unwrap())
+        }
+    }
+}
+
+/// Report `e`, with each of the errors it is made of and then a summary if
+/// there are several.
+pub fn error(args: &Args, e: &Error) {
+    for d in e.diagnostics() {
+        diagnostic(args, &d);
+    }
+    if let Error::Many(_) = e {
+        diagnostic(args, &e.diagnostic());
+    }
+}
+
+/// Whether output to stderr should be colored. With `auto` it is only when
+/// stderr is a terminal that can show color and NO_COLOR is unset or empty,
+/// as https://no-color.org asks.
+pub fn color(choice: ColorChoice) -> bool {
+    match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            std::env::var_os("NO_COLOR").is_none_or(|x| x.is_empty())
+                && std::env::var_os("TERM").is_none_or(|x| x != "dumb")
+                && std::io::stderr().is_terminal()
+        }
+    }
+}
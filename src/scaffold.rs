@@ -0,0 +1,134 @@
+use crate::error::{Error, Result};
+use crate::spec::Module;
+use serde_derive::Serialize;
+use std::path::{Path, PathBuf};
+
+/// The build spec written for a new module.
+#[derive(Serialize)]
+struct Skeleton<'a> {
+    module: &'a Module,
+}
+
+/// Create a build spec for a kernel module in `dir`, built from the c
+/// sources already there. If there are none and `stub` is set, a source file
+/// with the boilerplate every module needs is created first. Returns the
+/// files written.
+pub fn new_module(
+    dir: &Path,
+    name: Option<&str>,
+    dependencies: &[String],
+    stub: bool,
+    force: bool,
+) -> Result<Vec<PathBuf>> {
+    let name = match name {
+        Some(name) => name.to_owned(),
+        None => std::path::absolute(dir)?
+            .components()
+            .filter_map(|x| match x {
+                std::path::Component::Normal(x) => Some(x),
+                _ => None,
+            })
+            .next_back()
+            .map(|x| x.to_string_lossy().into_owned())
+            .ok_or_else(|| {
+                Error::Usage(format!(
+                    "{}: cannot name the module, use --name",
+                    dir.display()
+                ))
+            })?,
+    };
+    let spec_path = dir.join("build.toml");
+    if spec_path.exists() && !force {
+        return Err(Error::Usage(format!(
+            "{}: already exists, use --force to replace it",
+            spec_path.display()
+        )));
+    }
+
+    let mut written = Vec::new();
+    let mut src = c_sources(dir)?;
+    if src.is_empty() {
+        if !stub {
+            return Err(Error::Usage(format!(
+                "{}: no c sources, use --stub to create one",
+                dir.display()
+            )));
+        }
+        std::fs::create_dir_all(dir)?;
+        let stub_path = dir.join(format!("{}.c", name));
+        std::fs::write(&stub_path, stub_source(&name))?;
+        written.push(stub_path);
+        src.push(format!("{}.c", name));
+    }
+
+    let module = Module {
+        name,
+        src,
+        dependencies: dependencies.to_vec(),
+        ..Default::default()
+    };
+    std::fs::write(&spec_path, spec_text(&module)?)?;
+    written.push(spec_path);
+    Ok(written)
+}
+
+/// The text of a build spec holding just `module`.
+pub fn spec_text(module: &Module) -> Result<String> {
+    Ok(toml::to_string(&Skeleton { module })?)
+}
+
+/// The names of the c sources directly in `dir`, sorted.
+fn c_sources(dir: &Path) -> Result<Vec<String>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Vec::new())
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let mut result = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.ends_with(".c") && entry.file_type()?.is_file() {
+            result.push(name);
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// The source of a module that does nothing but load and unload.
+fn stub_source(name: &str) -> String {
+    format!(
+        "#include <sys/modctl.h>
+
+static struct modlmisc {name}_modlmisc = {{
+\t&mod_miscops,
+\t\"{name}\"
+}};
+
+static struct modlinkage {name}_modlinkage = {{
+\tMODREV_1, {{ &{name}_modlmisc, NULL }}
+}};
+
+int
+_init(void)
+{{
+\treturn (mod_install(&{name}_modlinkage));
+}}
+
+int
+_fini(void)
+{{
+\treturn (mod_remove(&{name}_modlinkage));
+}}
+
+int
+_info(struct modinfo *modinfop)
+{{
+\treturn (mod_info(&{name}_modlinkage, modinfop));
+}}
+"
+    )
+}
@@ -0,0 +1,259 @@
+use crate::error::Result;
+use crate::manifest;
+use crate::util;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::debug;
+
+/// The directory under the output directory caches are kept in.
+pub const CACHE_DIR: &str = ".eos-cache";
+
+/// The name of the header cache file in the cache directory.
+pub const CACHE_FILE_NAME: &str = "headers.json";
+
+/// Which of the headers a source includes objects depend on.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Hash,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum HeaderDeps {
+    /// Only headers in the workspace, or in the source tree, output or proto
+    /// directories wherever they are, leaving out those of the system, e.g.
+    /// under `/usr/include`, which do not change from one build to the next.
+    #[default]
+    Workspace,
+    /// Every header.
+    All,
+}
+
+impl fmt::Display for HeaderDeps {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderDeps::Workspace => write!(f, "workspace"),
+            HeaderDeps::All => write!(f, "all"),
+        }
+    }
+}
+
+/// A scan of sources for the headers they include. Scanning runs the compiler
+/// once per source, which takes a while for a whole tree, so progress is shown
+/// on stderr while it runs, unless stderr is not a terminal.
+pub struct Scan<'a> {
+    cc: String,
+    headers: HeaderDeps,
+    roots: Vec<PathBuf>,
+    cache: &'a Cache,
+    progress: ProgressBar,
+}
+
+impl<'a> Scan<'a> {
+    /// A scan of `total` sources using the compiler `cc`, for the `headers`
+    /// objects depend on, in the workspace or under `roots`, showing progress
+    /// if `show` is set. Sources `cache` has the headers of are not scanned
+    /// again.
+    pub fn new(
+        cc: &str,
+        headers: HeaderDeps,
+        roots: Vec<PathBuf>,
+        cache: &'a Cache,
+        total: u64,
+        show: bool,
+    ) -> Scan<'a> {
+        let progress = if show {
+            ProgressBar::new(total)
+        } else {
+            ProgressBar::hidden()
+        };
+        progress.set_style(
+            ProgressStyle::with_template(
+                "scanning headers [{bar:30}] {pos}/{len} {wide_msg}",
+            )
+            .unwrap()
+            .progress_chars("=> "),
+        );
+        Scan {
+            cc: cc.to_owned(),
+            headers,
+            roots,
+            cache,
+            progress,
+        }
+    }
+
+    /// The headers `src` includes when compiled with `cflags`.
+    pub fn headers(
+        &self,
+        cflags: &[String],
+        src: &Path,
+    ) -> Result<Vec<PathBuf>> {
+        self.progress.set_message(src.display().to_string());
+        let flags = manifest::hash((cflags, self.headers, &self.roots));
+        let hash = std::fs::read(src).ok().map(manifest::hash);
+        let cached = hash.and_then(|x| self.cache.get(src, flags, x));
+        let result = match cached {
+            Some(x) => Ok(x),
+            None => util::header_deps(
+                &self.cc,
+                cflags,
+                src,
+                self.headers,
+                &self.roots,
+            )
+            .inspect(|headers| {
+                if let Some(hash) = hash {
+                    self.cache.insert(src, flags, hash, headers);
+                }
+            }),
+        };
+        self.progress.inc(1);
+        result
+    }
+}
+
+impl Drop for Scan<'_> {
+    fn drop(&mut self) {
+        self.progress.finish_and_clear();
+    }
+}
+
+/// The headers each source was found to include by earlier scans, so that a
+/// source that has not changed, nor have its headers, need not be scanned
+/// again when compiled with the same flags. Shared by every target, which the
+/// flags tell apart, in the cache directory under the output directory.
+pub struct Cache {
+    /// Identifies what the headers were found with. Those found with
+    /// anything else are not used.
+    context: u64,
+    /// The headers of each source, by source and a hash of its flags.
+    entries: Mutex<BTreeMap<(PathBuf, u64), Entry>>,
+    /// The entries looked up or recorded during this run.
+    used: Mutex<BTreeSet<(PathBuf, u64)>>,
+}
+
+/// The cache, as it is written to a file.
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    context: u64,
+    entries: Vec<Entry>,
+}
+
+/// The headers found in a source.
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+    /// The source.
+    source: PathBuf,
+    /// A hash of the flags it was scanned with.
+    flags: u64,
+    /// A hash of its contents.
+    hash: u64,
+    /// The headers it includes, and their modification times.
+    headers: BTreeMap<PathBuf, Option<SystemTime>>,
+}
+
+impl Cache {
+    /// Read the cache at `path`. A cache that is missing, unreadable or was
+    /// written for a different `context` is treated as empty.
+    pub fn load(path: &Path, context: u64) -> Cache {
+        let file = std::fs::read(path)
+            .ok()
+            .and_then(|x| serde_json::from_slice::<CacheFile>(&x).ok());
+        let entries = match file {
+            Some(x) if x.context == context => x
+                .entries
+                .into_iter()
+                .map(|x| ((x.source.clone(), x.flags), x))
+                .collect(),
+            _ => {
+                debug!("not reusing headers from {}", path.display());
+                BTreeMap::new()
+            }
+        };
+        Cache {
+            context,
+            entries: Mutex::new(entries),
+            used: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    /// The headers recorded for `src`, scanned with the flags whose hash is
+    /// `flags`, if its contents still hash to `hash` and none of its headers
+    /// has changed since.
+    fn get(&self, src: &Path, flags: u64, hash: u64) -> Option<Vec<PathBuf>> {
+        let key = (src.to_owned(), flags);
+        let entry = self.entries.lock().ok()?.get(&key)?.clone();
+        if entry.hash != hash
+            || entry
+                .headers
+                .iter()
+                .any(|(x, time)| util::modified(x) != *time)
+        {
+            return None;
+        }
+        if let Ok(mut used) = self.used.lock() {
+            used.insert(key);
+        }
+        Some(entry.headers.into_keys().collect())
+    }
+
+    /// Record `headers` as those `src` includes.
+    fn insert(&self, src: &Path, flags: u64, hash: u64, headers: &[PathBuf]) {
+        let entry = Entry {
+            source: src.to_owned(),
+            flags,
+            hash,
+            headers: headers
+                .iter()
+                .map(|x| (x.clone(), util::modified(x)))
+                .collect(),
+        };
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.insert((src.to_owned(), flags), entry);
+        }
+        if let Ok(mut used) = self.used.lock() {
+            used.insert((src.to_owned(), flags));
+        }
+    }
+
+    /// Write the cache to `path`. Sources that no longer exist are left
+    /// out, and so are the flags a source scanned during this run was not
+    /// scanned with, so that the cache does not grow with every change of
+    /// flags.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let (Ok(entries), Ok(used)) = (self.entries.lock(), self.used.lock())
+        else {
+            return Ok(());
+        };
+        let scanned: BTreeSet<&Path> =
+            used.iter().map(|(x, _)| x.as_path()).collect();
+        let entries = entries
+            .iter()
+            .filter(|(key, _)| match scanned.contains(key.0.as_path()) {
+                true => used.contains(*key),
+                false => key.0.exists(),
+            })
+            .map(|(_, x)| x.clone())
+            .collect();
+        let file = CacheFile {
+            context: self.context,
+            entries,
+        };
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        util::write_if_changed(path, &serde_json::to_vec(&file)?)
+    }
+}
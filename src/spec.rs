@@ -1,56 +1,899 @@
+use crate::arch::Arch;
+use crate::config::CtfConfig;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::error::{Error, Result};
 use crate::ninja;
+use crate::scan::Scan;
+use crate::target::Target;
+use crate::toolchain::Linker;
 use crate::util;
-use serde_derive::Deserialize;
-use std::io::Result;
-use std::path::PathBuf;
+use clap::ValueEnum;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
 
 /// An Eos build specification
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "lowercase")]
-pub enum Spec {
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Spec {
+    /// The version of the build spec format this spec is written in, which
+    /// `eos upgrade-spec` brings up to date. Defaults to 1. It is checked
+    /// before the rest of the spec is parsed, which it may not match.
+    #[serde(rename = "spec_version")]
+    _spec_version: Option<u32>,
     /// A genunix build spec.
-    Genunix(Genunix),
+    pub genunix: Option<Genunix>,
     /// A kernel module build spec.
-    Module(Module),
+    pub module: Option<Module>,
+    /// Code generation steps.
+    #[serde(default = "Vec::new")]
+    pub generate: Vec<Generate>,
+    /// Literal ninja text for things eos does not model.
+    #[serde(default = "Vec::new")]
+    pub raw: Vec<Raw>,
+    /// Rules defined by this spec, by name.
+    #[serde(default)]
+    pub rules: BTreeMap<String, Rule>,
+    /// Build steps using rules defined by build specs.
+    #[serde(default = "Vec::new")]
+    pub build: Vec<Build>,
+    /// Headers to install into the proto area.
+    #[serde(default = "Vec::new")]
+    pub headers: Vec<Headers>,
+    /// The package the files this spec installs are delivered in.
+    pub package: Option<Package>,
+    /// Tests run by `eos test`, by name.
+    #[serde(default)]
+    pub tests: BTreeMap<String, Test>,
+    /// How the CTF data of this spec's objects is converted and merged,
+    /// instead of as eos.toml says.
+    pub ctf: Option<CtfConfig>,
 }
 
 impl Spec {
-    /// Produce a set of ninja build statements from this spec.
+    /// The c sources of the genunix or module this spec describes when
+    /// building for `arch`, or for any architecture if there is no `arch`.
+    pub fn sources(&self, arch: Option<Arch>) -> Vec<&String> {
+        match (&self.module, &self.genunix) {
+            (Some(x), _) => sources(&x.src, &x.arch, arch),
+            (None, Some(x)) => sources(&x.src, &x.arch, arch),
+            (None, None) => Vec::new(),
+        }
+    }
+
+    /// Produce a set of ninja build statements from this spec. Header
+    /// dependencies of objects are only determined if there is a `scan`.
     pub fn to_ninja(
         &self,
-        path: &PathBuf,
+        path: &Path,
+        target: &Target,
+        scan: Option<&Scan>,
+    ) -> Result<Vec<ninja::BuildStatement>> {
+        // Headers produced by code generation do not exist until the build
+        // runs, so objects in specs with generation steps are scanned for
+        // header dependencies at build time, through a dyndep file.
+        let by_arch = match (&self.genunix, &self.module) {
+            (Some(x), _) => x.arch.keys(),
+            (None, Some(x)) => x.arch.keys(),
+            (None, None) => Default::default(),
+        };
+        for name in by_arch {
+            if Arch::from_str(name, false).is_err() {
+                return Err(Diagnostic::error(
+                    path,
+                    format!("unknown architecture `{}`", name),
+                )
+                .into());
+            }
+        }
+
+        let dyndep = !self.generate.is_empty();
+        let scan = if dyndep { None } else { scan };
+        let mut stmts =
+            match (&self.genunix, &self.module) {
+                (Some(x), None) => x.to_ninja(path, target, scan)?,
+                (None, Some(x)) => x.to_ninja(path, target, scan)?,
+                (None, None) => Vec::new(),
+                (Some(_), Some(_)) => return Err(Diagnostic::error(
+                    path,
+                    "a build spec may describe genunix or a module, not both",
+                )
+                .into()),
+            };
+        if let Some(ctf) = &self.ctf {
+            if ctf.enabled.is_some() {
+                return Err(Diagnostic::error(
+                    path,
+                    "ctf.enabled can only be set in eos.toml",
+                )
+                .into());
+            }
+            if target.ctf.enabled {
+                ctf_variables(ctf, &mut stmts);
+            }
+        }
+        if dyndep {
+            let generated =
+                self.generate_statements(path, target, &mut stmts)?;
+            stmts.extend(generated);
+        }
+        let dir = target.output_dir(path);
+        for build in &self.build {
+            stmts.push(step_statement(
+                path,
+                &dir,
+                build.rule.clone(),
+                &build.inputs,
+                &build.outputs,
+                &build.variables,
+            )?);
+        }
+        for headers in &self.headers {
+            let dir = target.proto.join("usr/include").join(&headers.dir);
+            for src in &headers.src {
+                let src = path.with_file_name(src);
+                let Some(name) = src.file_name() else {
+                    return Err(Diagnostic::error(
+                        path,
+                        format!("{} is not a header", src.display()),
+                    )
+                    .into());
+                };
+                stmts.push(install_statement(&src, &dir.join(name), "0644")?);
+            }
+        }
+        Ok(stmts)
+    }
+
+    /// The ninja rule definitions for the rules defined in this spec.
+    pub fn rule_definitions(&self) -> Vec<ninja::RuleDefinition> {
+        self.rules
+            .iter()
+            .map(|(name, rule)| ninja::RuleDefinition {
+                name: name.clone(),
+                command: rule.command.clone(),
+                variables: rule
+                    .variables
+                    .iter()
+                    .map(|(k, v)| ninja::Variable::new(k, v))
+                    .collect(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Produce the build statements for the code generation steps in this
+    /// spec, and hook the compile statements in `stmts` up to a dyndep file
+    /// that supplies their header dependencies once generation has run.
+    fn generate_statements(
+        &self,
+        path: &Path,
+        target: &Target,
+        stmts: &mut [ninja::BuildStatement],
     ) -> Result<Vec<ninja::BuildStatement>> {
-        match self {
-            Spec::Genunix(x) => x.to_ninja(path),
-            Spec::Module(x) => x.to_ninja(path),
+        let dir = target.output_dir(path);
+        let cflags = ninja::Variable::new(
+            "kernel_cflags",
+            &format!("$kernel_cflags -I{}", dir.display()),
+        );
+
+        let mut result = Vec::new();
+        let mut generated = Vec::new();
+        for gen in &self.generate {
+            let mut variables = gen.variables.clone();
+            let rule =
+                match (&gen.command, &gen.rule) {
+                    (Some(command), None) => {
+                        variables.insert("command".into(), command.clone());
+                        ninja::Rules::Generate.to_string()
+                    }
+                    (None, Some(rule)) => rule.clone(),
+                    _ => return Err(Diagnostic::error(
+                        path,
+                        "generate steps need exactly one of command or rule",
+                    )
+                    .into()),
+                };
+            let stmt = step_statement(
+                path,
+                &dir,
+                rule,
+                &gen.inputs,
+                &gen.outputs,
+                &variables,
+            )?;
+            generated.extend(stmt.outputs().map(|x| x.to_owned()));
+            result.push(stmt);
+        }
+
+        let dyndep = util::ninja_path(&dir.join("headers.dd"))?;
+        let compile = ninja::Rules::ModCompile.to_string();
+        let mut sources = Vec::new();
+        let mut objects = Vec::new();
+        for stmt in stmts.iter_mut().filter(|x| x.rule == compile) {
+            sources.extend(stmt.inputs.iter().cloned());
+            objects.push(format!(
+                "--object {}={}",
+                stmt.output,
+                stmt.inputs.join(" ")
+            ));
+            stmt.order_only_deps.push(dyndep.clone());
+            stmt.dyndep = Some(dyndep.clone());
+            add_cflags(stmt, &[format!("-I{}", dir.display())]);
+        }
+
+        if objects.is_empty() {
+            return Ok(result);
         }
+        result.push(ninja::BuildStatement {
+            inputs: sources,
+            output: dyndep,
+            rule: ninja::Rules::Dyndep.to_string(),
+            implicit_deps: generated,
+            variables: vec![
+                ninja::Variable::new("objects", &objects.join(" ")),
+                cflags,
+            ],
+            ..Default::default()
+        });
+        Ok(result)
     }
 }
 
-/// A build specification for a kernel module.
+/// Check that no two of `specs` describe a module of the same name, or
+/// genunix, whose outputs would collide. Every collision is reported, with
+/// both build specs.
+pub fn check_names(specs: &[&(PathBuf, Spec)]) -> Result<()> {
+    let mut modules: BTreeMap<&str, &Path> = BTreeMap::new();
+    let mut genunix: Option<&Path> = None;
+    let mut errors = Vec::new();
+    for (path, spec) in specs {
+        let (name, prev) = match (&spec.module, &spec.genunix) {
+            (Some(x), _) => {
+                (format!("module {}", x.name), modules.insert(&x.name, path))
+            }
+            (None, Some(_)) => ("genunix".to_owned(), genunix.replace(path)),
+            (None, None) => continue,
+        };
+        if let Some(prev) = prev {
+            errors.push(
+                Diagnostic::error(
+                    path,
+                    format!(
+                        "{} is also described by {}, and both would be built \
+                         as the same file",
+                        name,
+                        prev.display()
+                    ),
+                )
+                .into(),
+            );
+        }
+    }
+    Error::collect(errors)
+}
+
+/// Check that every dependency of the modules of `specs` names a module one of
+/// them describes, or one of the `external` modules built some other way. A
+/// dependency that does not is reported with the module it is closest to.
+pub fn check_dependencies(
+    specs: &[&(PathBuf, Spec)],
+    external: &[String],
+) -> Result<()> {
+    let names: BTreeSet<&str> = specs
+        .iter()
+        .filter_map(|(_, x)| x.module.as_ref())
+        .map(|x| x.name.as_str())
+        .collect();
+    let mut errors = Vec::new();
+    for (path, spec) in specs {
+        let Some(module) = &spec.module else {
+            continue;
+        };
+        for (dep, name) in
+            module.dependencies.iter().zip(module.dependency_names())
+        {
+            if names.contains(name) || external.contains(dep) {
+                continue;
+            }
+            let mut message = format!(
+                "dependency {} is not a module of the tree, nor one of the \
+                 external_modules in eos.toml",
+                dep
+            );
+            // Names are compared without their kernel directories, which
+            // most modules share.
+            let dir = &dep[..dep.len() - name.len()];
+            let candidates = names
+                .iter()
+                .copied()
+                .chain(external.iter().filter_map(|x| x.strip_prefix(dir)));
+            if let Some(x) = util::did_you_mean(name, candidates) {
+                message.push_str(&format!("; did you mean {}{}?", dir, x));
+            }
+            errors.push(Diagnostic::error(path, message).into());
+        }
+    }
+    Error::collect(errors)
+}
+
+/// Sources that more than one of `specs` compiles, each into an object of its
+/// own, reported with `severity` against every build spec after the first to
+/// compile it.
+pub fn duplicate_sources(
+    specs: &[&(PathBuf, Spec)],
+    severity: Severity,
+) -> Vec<Diagnostic> {
+    let mut owners: BTreeMap<PathBuf, &Path> = BTreeMap::new();
+    let mut diagnostics = Vec::new();
+    for (path, spec) in specs {
+        for src in spec.sources(None) {
+            let file = util::normalize(&path.with_file_name(src));
+            match owners.get(&file) {
+                Some(prev) if prev != path => diagnostics.push(Diagnostic {
+                    severity,
+                    ..Diagnostic::error(
+                        path,
+                        format!(
+                            "source {} is also compiled by {}, into a \
+                             different object",
+                            file.display(),
+                            prev.display()
+                        ),
+                    )
+                }),
+                Some(_) => {}
+                None => {
+                    owners.insert(file, path);
+                }
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Check that the modules of `specs` do not depend on themselves, directly or
+/// through other modules, which would leave ninja nothing it could build
+/// first. Every cycle is reported, with the modules it goes through.
+pub fn check_cycles(specs: &[&(PathBuf, Spec)]) -> Result<()> {
+    let modules: BTreeMap<&str, (&Path, &Module)> = specs
+        .iter()
+        .filter_map(|(path, spec)| {
+            spec.module
+                .as_ref()
+                .map(|x| (x.name.as_str(), (path.as_path(), x)))
+        })
+        .collect();
+    let mut done = BTreeSet::new();
+    let mut errors = Vec::new();
+    for name in modules.keys() {
+        visit_dependencies(
+            name,
+            &modules,
+            &mut Vec::new(),
+            &mut done,
+            &mut errors,
+        );
+    }
+    Error::collect(errors)
+}
+
+/// Follow the dependencies of module `name` depth first, reporting a cycle
+/// when one leads back to a module on the `path` taken to reach it. Modules
+/// whose dependencies have all been followed are kept in `done`.
+fn visit_dependencies<'a>(
+    name: &'a str,
+    modules: &BTreeMap<&'a str, (&'a Path, &'a Module)>,
+    path: &mut Vec<&'a str>,
+    done: &mut BTreeSet<&'a str>,
+    errors: &mut Vec<Error>,
+) {
+    if done.contains(name) {
+        return;
+    }
+    let Some((spec, module)) = modules.get(name) else {
+        return;
+    };
+    if let Some(i) = path.iter().position(|x| *x == name) {
+        let cycle: Vec<&str> =
+            path[i..].iter().copied().chain([name]).collect();
+        errors.push(
+            Diagnostic::error(
+                spec,
+                format!(
+                    "module {} depends on itself, through {}",
+                    name,
+                    cycle.join(" -> ")
+                ),
+            )
+            .into(),
+        );
+        return;
+    }
+    path.push(name);
+    for dep in module.dependency_names() {
+        visit_dependencies(dep, modules, path, done, errors);
+    }
+    path.pop();
+    done.insert(name);
+}
+
+/// Set the CTF label and flags of `ctf` on the statements in `stmts` that
+/// convert and merge CTF data.
+fn ctf_variables(ctf: &CtfConfig, stmts: &mut [ninja::BuildStatement]) {
+    let convert = ninja::Rules::CtfConvert.to_string();
+    let link = ninja::Rules::ModLink.to_string();
+    let genunix = ninja::Rules::GenunixLink.to_string();
+    for stmt in stmts {
+        let flags = match &stmt.rule {
+            x if *x == convert => ("ctfconvert_flags", &ctf.convert_flags),
+            x if *x == link || *x == genunix => {
+                ("ctfmerge_flags", &ctf.merge_flags)
+            }
+            _ => continue,
+        };
+        if let Some(label) = &ctf.label {
+            stmt.variables
+                .push(ninja::Variable::new("ctf_label", label));
+        }
+        if let (name, Some(flags)) = flags {
+            stmt.variables
+                .push(ninja::Variable::new(name, &flags.join(" ")));
+        }
+    }
+}
+
+/// Add `flags` to the kernel flags `stmt` is compiled with, after any it
+/// already adds.
+fn add_cflags(stmt: &mut ninja::BuildStatement, flags: &[String]) {
+    let flags = flags.join(" ");
+    match stmt
+        .variables
+        .iter_mut()
+        .find(|x| x.name == "kernel_cflags")
+    {
+        Some(x) => {
+            x.value.push(' ');
+            x.value.push_str(&flags);
+        }
+        None => stmt.variables.push(ninja::Variable::new(
+            "kernel_cflags",
+            &format!("$kernel_cflags {}", flags),
+        )),
+    }
+}
+
+/// Instrument the compile statements in `stmts` with the sanitizer of
+/// `target`, if there is one and the build spec has not opted out with
+/// `sanitize = false`.
+fn sanitize(
+    target: &Target,
+    enabled: Option<bool>,
+    stmts: &mut [ninja::BuildStatement],
+) {
+    let Some(flags) = target.sanitize.and_then(|x| x.cflags(target.family))
+    else {
+        return;
+    };
+    if !enabled.unwrap_or(true) {
+        return;
+    }
+    let compile = ninja::Rules::ModCompile.to_string();
+    for stmt in stmts.iter_mut().filter(|x| x.rule == compile) {
+        add_cflags(stmt, &flags);
+    }
+}
+
+/// Flags a module or genunix, or one of its sources, is compiled with on top
+/// of the kernel flags, or instead of some of them.
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Cflags {
+    /// Flags to add after the kernel flags.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub cflags: Vec<String>,
+    /// Kernel flags to leave out, e.g. `-fstack-protector-strong`.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub remove_cflags: Vec<String>,
+}
+
+impl Cflags {
+    /// `flags` without those this removes, followed by those it adds.
+    fn apply(&self, flags: &mut Vec<String>) {
+        flags.retain(|x| !self.remove_cflags.contains(x));
+        flags.extend(self.cflags.iter().cloned());
+    }
+}
+
+/// The flags of a module or genunix described by the build spec at `path`,
+/// and of its sources, which `files` has by name.
+struct SourceCflags<'a> {
+    path: &'a Path,
+    /// The kernel flags.
+    base: Vec<String>,
+    module: &'a Cflags,
+    files: &'a BTreeMap<String, Cflags>,
+}
+
+impl SourceCflags<'_> {
+    /// Check that the flags in `files` are for some of the sources in `src`.
+    fn check(&self, src: &[&String]) -> Result<()> {
+        match self.files.keys().find(|x| !src.contains(x)) {
+            Some(x) => Err(Diagnostic::error(
+                self.path,
+                format!("files: {} is not a source", x),
+            )
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// The flags of the source `src`, if it has any.
+    fn file(&self, src: &Path) -> Option<&Cflags> {
+        self.files
+            .iter()
+            .find(|(x, _)| self.path.with_file_name(x) == src)
+            .map(|(_, x)| x)
+    }
+
+    /// The flags `src` is compiled with: the kernel flags without those the
+    /// module and then the source remove, followed by those they add.
+    fn resolve(&self, src: &Path) -> Vec<String> {
+        let mut flags = self.base.clone();
+        self.module.apply(&mut flags);
+        if let Some(file) = self.file(src) {
+            file.apply(&mut flags);
+        }
+        flags
+    }
+
+    /// Compile the statements in `stmts` with the flags of their sources.
+    /// Those that only add flags keep referring to the kernel flags; the
+    /// others are given their own list in full.
+    fn apply(&self, stmts: &mut [ninja::BuildStatement]) {
+        let compile = ninja::Rules::ModCompile.to_string();
+        for stmt in stmts.iter_mut().filter(|x| x.rule == compile) {
+            let src = Path::new(&stmt.inputs[0]);
+            let file = self.file(src);
+            let removes = !self.module.remove_cflags.is_empty()
+                || file.is_some_and(|x| !x.remove_cflags.is_empty());
+            if removes {
+                let flags = self.resolve(src).join(" ");
+                stmt.variables
+                    .push(ninja::Variable::new("kernel_cflags", &flags));
+                continue;
+            }
+            let flags: Vec<String> = self
+                .module
+                .cflags
+                .iter()
+                .chain(file.iter().flat_map(|x| &x.cflags))
+                .cloned()
+                .collect();
+            if !flags.is_empty() {
+                add_cflags(stmt, &flags);
+            }
+        }
+    }
+}
+
+/// Let the compile statements in `stmts` for the build spec at `path` warn
+/// without failing when `target` treats warnings as errors, all of them if
+/// the build spec has set `werror = false`, or else those compiling a source
+/// in `exempt`, which must be among the spec's sources `src`.
+fn werror(
+    path: &Path,
+    target: &Target,
+    enabled: Option<bool>,
+    exempt: &[String],
+    src: &[&String],
+    stmts: &mut [ninja::BuildStatement],
+) -> Result<()> {
+    let compile = ninja::Rules::ModCompile.to_string();
+    let mut paths = Vec::new();
+    for x in exempt {
+        if !src.contains(&x) {
+            return Err(Diagnostic::error(
+                path,
+                format!("werror_exempt: {} is not a source", x),
+            )
+            .into());
+        }
+        paths.push(util::ninja_path(&path.with_file_name(x))?);
+    }
+    if !target.werror {
+        return Ok(());
+    }
+    let all = !enabled.unwrap_or(true);
+    for stmt in stmts.iter_mut().filter(|x| x.rule == compile) {
+        if all || paths.contains(&stmt.inputs[0]) {
+            add_cflags(stmt, &["-Wno-error".to_owned()]);
+        }
+    }
+    Ok(())
+}
+
+/// Produce the build statement for a generate or build step of the spec at
+/// `path`, with outputs placed in `dir`.
+fn step_statement(
+    path: &Path,
+    dir: &Path,
+    rule: String,
+    inputs: &[String],
+    outputs: &[String],
+    variables: &BTreeMap<String, String>,
+) -> Result<ninja::BuildStatement> {
+    let mut outputs = outputs
+        .iter()
+        .map(|x| util::ninja_path(&dir.join(x)))
+        .collect::<Result<Vec<String>>>()?
+        .into_iter();
+    let Some(output) = outputs.next() else {
+        return Err(Diagnostic::error(
+            path,
+            format!("{} step has no outputs", rule),
+        )
+        .into());
+    };
+    Ok(ninja::BuildStatement {
+        inputs: inputs
+            .iter()
+            .map(|x| util::ninja_path(&path.with_file_name(x)))
+            .collect::<Result<_>>()?,
+        output,
+        implicit_outputs: outputs.collect(),
+        rule,
+        variables: variables
+            .iter()
+            .map(|(k, v)| ninja::Variable::new(k, v))
+            .collect(),
+        ..Default::default()
+    })
+}
+
+/// Produce the build statement installing `input` into the proto area as
+/// `output` with permissions `mode`.
+fn install_statement(
+    input: &Path,
+    output: &Path,
+    mode: &str,
+) -> Result<ninja::BuildStatement> {
+    Ok(ninja::BuildStatement {
+        inputs: vec![util::ninja_path(input)?],
+        output: util::ninja_path(output)?,
+        rule: ninja::Rules::Install.to_string(),
+        variables: vec![ninja::Variable::new("mode", mode)],
+        ..Default::default()
+    })
+}
+
+/// The sources in `src` plus those in `by_arch` for `arch`, or for every
+/// architecture if there is no `arch`.
+fn sources<'a>(
+    src: &'a [String],
+    by_arch: &'a BTreeMap<String, ArchSources>,
+    arch: Option<Arch>,
+) -> Vec<&'a String> {
+    let extra = by_arch
+        .iter()
+        .filter(|(x, _)| arch.is_none_or(|arch| **x == arch.to_string()))
+        .flat_map(|(_, x)| &x.src);
+    let mut result: Vec<&String> = Vec::new();
+    for x in src.iter().chain(extra) {
+        if !result.contains(&x) {
+            result.push(x);
+        }
+    }
+    result
+}
+
+/// Sources of a genunix or module that are only compiled for a particular
+/// architecture.
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ArchSources {
+    /// Source c files.
+    pub src: Vec<String>,
+}
+
+/// A rule defined by a build spec, for use by its generate and build steps
+/// and those of other specs. Specs may define the same rule as long as the
+/// definitions agree.
 #[derive(Debug, Deserialize, Default)]
-#[serde(rename_all = "lowercase")]
+pub struct Rule {
+    /// The command to run.
+    pub command: String,
+    /// Other rule variables such as `description`, `depfile` or `restat`.
+    #[serde(flatten)]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// A build step using a rule defined by a build spec.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Build {
+    /// The name of the rule to use.
+    pub rule: String,
+    /// Input files, relative to the build spec.
+    #[serde(default = "Vec::new")]
+    pub inputs: Vec<String>,
+    /// Files the step produces, relative to the build spec's output
+    /// directory.
+    pub outputs: Vec<String>,
+    /// Variables to set for the step.
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// Literal ninja text to include in the generated build file, e.g. an extra
+/// rule or build edge. Outputs should be placed under `$bld` so they are kept
+/// apart when building several architectures.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Raw {
+    /// The ninja text.
+    pub text: String,
+}
+
+/// Headers to install into the proto area, e.g. the public headers of a
+/// driver.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Headers {
+    /// The directory under `usr/include` to install into, e.g. `sys`.
+    pub dir: String,
+    /// Header files, relative to the build spec.
+    pub src: Vec<String>,
+}
+
+/// IPS packaging metadata for the files a build spec installs. Build specs
+/// naming the same package contribute to one manifest.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Package {
+    /// The name of the package, e.g. `driver/network/foo`.
+    pub name: String,
+    /// A one line summary of the package.
+    pub summary: Option<String>,
+    /// A longer description of the package.
+    pub description: Option<String>,
+    /// Packages this package requires, by name.
+    #[serde(default = "Vec::new")]
+    pub dependencies: Vec<String>,
+}
+
+/// A test, e.g. a userland unit test built by a build step or a ktest
+/// module, run by `eos test` from the directory eos is run from.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Test {
+    /// The command to run with `sh -c`. `EOS_ARCH`, `EOS_BLD`, `EOS_OUT` and
+    /// `EOS_SRC` in its environment are the architecture, the output
+    /// directory of the architecture and of the build spec, and the
+    /// directory of the build spec.
+    pub command: String,
+    /// Build outputs the test needs, relative to the build spec's output
+    /// directory. They are built before any test runs.
+    #[serde(default = "Vec::new")]
+    pub needs: Vec<String>,
+}
+
+/// A code generation step, e.g. a script that produces C source and headers
+/// from a description file.
+#[derive(Debug, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Generate {
+    /// The command to run. `$in` and `$out` expand to the inputs and outputs
+    /// of the step.
+    pub command: Option<String>,
+    /// The name of a rule defined by a build spec to run instead of
+    /// `command`.
+    pub rule: Option<String>,
+    /// Input files, relative to the build spec.
+    #[serde(default = "Vec::new")]
+    pub inputs: Vec<String>,
+    /// Files the command produces, relative to the build spec's output
+    /// directory. Headers the command produces need not be listed, they are
+    /// found by scanning after the command runs.
+    pub outputs: Vec<String>,
+    /// Variables to set for the step.
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// A build specification for a kernel module.
+#[derive(Debug, Deserialize, Serialize, Default)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub struct Module {
     /// Name of the kernel module.
     pub name: String,
     /// Source c files.
     pub src: Vec<String>,
     /// Other kernel modules this module depends on.
-    #[serde(default = "Vec::new")]
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
     pub dependencies: Vec<String>,
+    /// Source c files for particular architectures, e.g. `arch.amd64.src`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub arch: BTreeMap<String, ArchSources>,
+    /// The kernel directory the module is installed in, e.g. `drv` or
+    /// `misc`. Modules without one are not installed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub class: Option<String>,
+    /// A driver configuration file, relative to the build spec, installed as
+    /// `<name>.conf` in the module's kernel directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conf: Option<String>,
+    /// Whether to instrument the module's objects when building with a
+    /// sanitizer. Defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sanitize: Option<bool>,
+    /// Whether warnings in the module's objects are errors when building
+    /// with `--werror`. Defaults to true.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub werror: Option<bool>,
+    /// Source c files with known warnings that cannot be fixed, which only
+    /// warn when building with `--werror`.
+    #[serde(default = "Vec::new", skip_serializing_if = "Vec::is_empty")]
+    pub werror_exempt: Vec<String>,
+    /// Flags the module's objects are compiled with on top of the kernel
+    /// flags, or instead of some of them.
+    #[serde(flatten)]
+    pub cflags: Cflags,
+    /// Flags particular sources are compiled with on top of the module's,
+    /// e.g. `files."foo.c".cflags`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub files: BTreeMap<String, Cflags>,
 }
 
 impl Module {
-    /// Produce a set of ninja build statements from this spec.
+    /// The names of the modules this module depends on. Dependencies name
+    /// modules by their installed path, e.g. `misc/mac` for `mac`.
+    pub fn dependency_names(&self) -> impl Iterator<Item = &str> {
+        self.dependencies
+            .iter()
+            .map(|x| x.rsplit('/').next().unwrap_or(x))
+    }
+
+    /// Produce a set of ninja build statements from this spec. Header
+    /// dependencies of objects are only determined if there is a `scan`.
     pub fn to_ninja(
         &self,
-        path: &PathBuf,
+        path: &Path,
+        target: &Target,
+        scan: Option<&Scan>,
     ) -> Result<Vec<ninja::BuildStatement>> {
-        let osm = util::object_source_map(path, &self.src)?;
-        let mut stmts =
-            util::object_build_statements(ninja::Spec::kernel_cflags(), &osm);
+        let src = sources(&self.src, &self.arch, Some(target.arch));
+        let cflags = SourceCflags {
+            path,
+            base: ninja::Spec::kernel_cflags(target),
+            module: &self.cflags,
+            files: &self.files,
+        };
+        let all = sources(&self.src, &self.arch, None);
+        cflags.check(&all)?;
+        let osm = util::object_source_map(path, &src, target)?;
+        let mut stmts = util::object_build_statements(
+            &|x| cflags.resolve(x),
+            &osm,
+            scan,
+            target,
+        )?;
+        cflags.apply(&mut stmts);
+        sanitize(target, self.sanitize, &mut stmts);
+        werror(
+            path,
+            target,
+            self.werror,
+            &self.werror_exempt,
+            &all,
+            &mut stmts,
+        )?;
 
-        let mod_deps = if !self.dependencies.is_empty() {
+        // lld has no way to record the modules a module depends on, and
+        // takes `-N` to mean something else entirely.
+        let mod_deps = if !self.dependencies.is_empty()
+            && target.linker == Linker::Illumos
+        {
             vec![ninja::Variable {
                 name: "mod_deps".to_owned(),
                 value: self
@@ -65,16 +908,69 @@ impl Module {
         };
 
         stmts.push(ninja::BuildStatement {
-            input: osm
+            inputs: osm
                 .iter()
-                .map(|(_, obj)| obj.to_str().unwrap())
-                .collect::<Vec<&str>>()
-                .join(" "),
-            output: format!("bld/modules/{}", self.name),
+                .map(|(_, obj)| util::ninja_path(obj))
+                .collect::<Result<_>>()?,
+            output: util::ninja_path(
+                &target.bld.join("modules").join(&self.name),
+            )?,
             rule: ninja::Rules::ModLink.to_string(),
             variables: mod_deps,
-            implicit_deps: vec!["bld/genunix".to_owned()],
+            implicit_deps: vec![target.genunix()?],
+            ..Default::default()
         });
+        let module = stmts.last().unwrap().output.clone();
+        let stamp = target.symbols_stamp(&self.name)?;
+        if target.check_symbols {
+            let mut providers = vec![target.genunix()?];
+            for name in self.dependency_names() {
+                providers.push(util::ninja_path(
+                    &target.bld.join("modules").join(name),
+                )?);
+            }
+            stmts.push(ninja::BuildStatement {
+                inputs: vec![module.clone()],
+                output: stamp.clone(),
+                rule: ninja::Rules::Symbols.to_string(),
+                variables: vec![ninja::Variable::new(
+                    "providers",
+                    &providers.join(" "),
+                )],
+                implicit_deps: providers,
+                ..Default::default()
+            });
+        }
+
+        match (&self.class, &self.conf) {
+            (Some(class), conf) => {
+                let installed = target.kernel_dir(Some(class)).join(&self.name);
+                let mut install =
+                    install_statement(Path::new(&module), &installed, "0755")?;
+                // A module that would fail to load is not installed.
+                if target.check_symbols {
+                    install.implicit_deps.push(stamp);
+                }
+                stmts.push(install);
+                if let Some(conf) = conf {
+                    let name = format!("{}.conf", self.name);
+                    let installed = target.proto.join("kernel").join(class);
+                    stmts.push(install_statement(
+                        &path.with_file_name(conf),
+                        &installed.join(name),
+                        "0644",
+                    )?);
+                }
+            }
+            (None, Some(_)) => {
+                return Err(Diagnostic::error(
+                    path,
+                    "a module with a conf file needs a class to install into",
+                )
+                .into())
+            }
+            (None, None) => {}
+        }
 
         Ok(stmts)
     }
@@ -82,31 +978,100 @@ impl Module {
 
 /// A build specification for genunix.
 #[derive(Debug, Deserialize, Default)]
-#[serde(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
 pub struct Genunix {
     /// Source c files.
     pub src: Vec<String>,
+    /// Source c files for particular architectures, e.g. `arch.amd64.src`.
+    #[serde(default)]
+    pub arch: BTreeMap<String, ArchSources>,
+    /// Whether to instrument genunix's objects when building with a
+    /// sanitizer. Defaults to true.
+    pub sanitize: Option<bool>,
+    /// Source c files of the sanitizer runtime, only compiled when building
+    /// with a sanitizer, and never instrumented.
+    #[serde(default = "Vec::new")]
+    pub sanitizer_src: Vec<String>,
+    /// Whether warnings in genunix's objects are errors when building with
+    /// `--werror`. Defaults to true.
+    pub werror: Option<bool>,
+    /// Source c files with known warnings that cannot be fixed, which only
+    /// warn when building with `--werror`.
+    #[serde(default = "Vec::new")]
+    pub werror_exempt: Vec<String>,
+    /// Flags genunix's objects are compiled with on top of the kernel flags,
+    /// or instead of some of them.
+    #[serde(flatten)]
+    pub cflags: Cflags,
+    /// Flags particular sources are compiled with on top of genunix's, e.g.
+    /// `files."foo.c".cflags`.
+    #[serde(default)]
+    pub files: BTreeMap<String, Cflags>,
 }
 
 impl Genunix {
-    /// Produce a set of ninja build statements from this spec.
+    /// Produce a set of ninja build statements from this spec. Header
+    /// dependencies of objects are only determined if there is a `scan`.
     pub fn to_ninja(
         &self,
-        path: &PathBuf,
+        path: &Path,
+        target: &Target,
+        scan: Option<&Scan>,
     ) -> Result<Vec<ninja::BuildStatement>> {
-        let osm = util::object_source_map(path, &self.src)?;
-        let mut stmts =
-            util::object_build_statements(ninja::Spec::kernel_cflags(), &osm);
+        let src = sources(&self.src, &self.arch, Some(target.arch));
+        let cflags = SourceCflags {
+            path,
+            base: ninja::Spec::kernel_cflags(target),
+            module: &self.cflags,
+            files: &self.files,
+        };
+        let mut all = sources(&self.src, &self.arch, None);
+        all.extend(&self.sanitizer_src);
+        cflags.check(&all)?;
+        let mut osm = util::object_source_map(path, &src, target)?;
+        let mut stmts = util::object_build_statements(
+            &|x| cflags.resolve(x),
+            &osm,
+            scan,
+            target,
+        )?;
+        cflags.apply(&mut stmts);
+        sanitize(target, self.sanitize, &mut stmts);
+        if target.sanitize.is_some() {
+            let src: Vec<&String> = self.sanitizer_src.iter().collect();
+            let runtime = util::object_source_map(path, &src, target)?;
+            let mut runtime_stmts = util::object_build_statements(
+                &|x| cflags.resolve(x),
+                &runtime,
+                scan,
+                target,
+            )?;
+            cflags.apply(&mut runtime_stmts);
+            stmts.extend(runtime_stmts);
+            osm.extend(runtime);
+        }
+        werror(
+            path,
+            target,
+            self.werror,
+            &self.werror_exempt,
+            &all,
+            &mut stmts,
+        )?;
         stmts.push(ninja::BuildStatement {
-            input: osm
+            inputs: osm
                 .iter()
-                .map(|(_, obj)| obj.to_str().unwrap())
-                .collect::<Vec<&str>>()
-                .join(" "),
-            output: "bld/genunix".to_owned(),
-            rule: ninja::Rules::ModLink.to_string(),
+                .map(|(_, obj)| util::ninja_path(obj))
+                .collect::<Result<_>>()?,
+            output: target.genunix()?,
+            rule: ninja::Rules::GenunixLink.to_string(),
             ..Default::default()
         });
+        stmts.push(install_statement(
+            Path::new(&target.genunix()?),
+            &target.kernel_dir(None).join("genunix"),
+            "0755",
+        )?);
 
         Ok(stmts)
     }
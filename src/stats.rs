@@ -0,0 +1,155 @@
+use crate::arch::Arch;
+use crate::backend::Backend;
+use crate::error::Result;
+use crate::ninja;
+use crate::spec::Spec;
+use serde_derive::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// Statistics about the selected build specs and the build generated from
+/// them, as text or JSON.
+pub struct Stats<'a> {
+    /// The selected build specs.
+    pub specs: Vec<&'a (PathBuf, Spec)>,
+    /// The build generated for each architecture.
+    pub ninja_specs: &'a [(Arch, ninja::Spec)],
+    /// How many of the largest targets to show.
+    pub top: usize,
+    /// Whether to write JSON instead of text.
+    pub json: bool,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    specs: usize,
+    modules: usize,
+    sources: usize,
+    arches: BTreeMap<Arch, ArchReport<'a>>,
+}
+
+#[derive(Serialize)]
+struct ArchReport<'a> {
+    compile_edges: usize,
+    link_edges: usize,
+    header_deps: usize,
+    largest: Vec<TargetReport<'a>>,
+}
+
+/// A linked target, with the number of objects and header dependencies of
+/// the objects it is linked from.
+#[derive(Serialize)]
+struct TargetReport<'a> {
+    output: &'a str,
+    spec: Option<&'a Path>,
+    objects: usize,
+    headers: usize,
+}
+
+impl<'a> Stats<'a> {
+    fn report(&self) -> Report<'a> {
+        let mut arches = BTreeMap::new();
+        for (arch, spec) in self.ninja_specs {
+            arches.insert(*arch, self.arch_report(spec));
+        }
+        Report {
+            specs: self.specs.len(),
+            modules: self.specs.iter().filter(|x| x.1.module.is_some()).count(),
+            sources: self.specs.iter().map(|x| x.1.sources(None).len()).sum(),
+            arches,
+        }
+    }
+
+    fn arch_report(&self, spec: &'a ninja::Spec) -> ArchReport<'a> {
+        let compile = ninja::Rules::ModCompile.to_string();
+        let links = [
+            ninja::Rules::ModLink.to_string(),
+            ninja::Rules::GenunixLink.to_string(),
+        ];
+
+        // Objects and headers by the build spec they come from.
+        let mut by_origin: BTreeMap<Option<&Path>, (usize, usize)> =
+            BTreeMap::new();
+        for stmt in spec.statements.iter().filter(|x| x.rule == compile) {
+            let entry = by_origin.entry(stmt.origin.as_deref()).or_default();
+            entry.0 += 1;
+            entry.1 += stmt.implicit_deps.len();
+        }
+
+        let mut largest: Vec<TargetReport> = spec
+            .statements
+            .iter()
+            .filter(|x| links.contains(&x.rule))
+            .map(|x| {
+                let origin = x.origin.as_deref();
+                let (objects, headers) =
+                    by_origin.get(&origin).copied().unwrap_or_default();
+                TargetReport {
+                    output: &x.output,
+                    spec: origin,
+                    objects,
+                    headers,
+                }
+            })
+            .collect();
+        let link_edges = largest.len();
+        largest.sort_by(|a, b| {
+            (b.objects, b.headers)
+                .cmp(&(a.objects, a.headers))
+                .then(a.output.cmp(b.output))
+        });
+        largest.truncate(self.top);
+
+        ArchReport {
+            compile_edges: by_origin.values().map(|x| x.0).sum(),
+            link_edges,
+            header_deps: by_origin.values().map(|x| x.1).sum(),
+            largest,
+        }
+    }
+}
+
+impl Backend for Stats<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        let report = self.report();
+        if self.json {
+            serde_json::to_writer_pretty(&mut *w, &report)?;
+            return Ok(writeln!(w)?);
+        }
+
+        writeln!(w, "build specs    {}", report.specs)?;
+        writeln!(w, "modules        {}", report.modules)?;
+        writeln!(w, "sources        {}", report.sources)?;
+        for (arch, x) in &report.arches {
+            writeln!(w, "\n{}", arch)?;
+            writeln!(w, "compile edges  {}", x.compile_edges)?;
+            writeln!(w, "link edges     {}", x.link_edges)?;
+            let average = match x.compile_edges {
+                0 => 0.0,
+                n => x.header_deps as f64 / n as f64,
+            };
+            writeln!(
+                w,
+                "header deps    {} ({:.1} per object)",
+                x.header_deps, average
+            )?;
+            if x.largest.is_empty() {
+                continue;
+            }
+            writeln!(w, "largest targets")?;
+            let width = x.largest.iter().map(|x| x.output.len()).max();
+            for target in &x.largest {
+                writeln!(
+                    w,
+                    "  {:width$}  {:4} objects  {:6} headers",
+                    target.output,
+                    target.objects,
+                    target.headers,
+                    width = width.unwrap_or(0),
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+use crate::error::{Error, Result};
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::process::Command;
+
+/// Check that every symbol the module at `module` references is defined by
+/// one of `providers`, genunix and the modules it depends on, listing their
+/// symbols with `nm`. Without this a missing dependency only shows when the
+/// module fails to load.
+pub fn check(nm: &str, module: &Path, providers: &[&Path]) -> Result<()> {
+    let mut defined = BTreeSet::new();
+    for provider in providers {
+        defined.extend(
+            symbols(nm, provider)?
+                .into_iter()
+                .filter(|(_, kind)| !matches!(kind, 'U' | 'w' | 'v'))
+                .map(|(name, _)| name),
+        );
+    }
+    let missing: Vec<String> = symbols(nm, module)?
+        .into_iter()
+        .filter(|(name, kind)| *kind == 'U' && !defined.contains(name))
+        .map(|(name, _)| name)
+        .collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(Error::Failed(format!(
+        "{} references symbols that neither genunix nor its dependencies \
+         define, {}; add the modules that do to its dependencies",
+        module.display(),
+        missing.join(", ")
+    )))
+}
+
+/// The external symbols of the object at `path`, with their `nm` type
+/// letters, e.g. `U` for undefined.
+fn symbols(nm: &str, path: &Path) -> Result<Vec<(String, char)>> {
+    let output = Command::new(nm)
+        .args(["-P", "-g"])
+        .arg(path)
+        .output()
+        .map_err(|e| {
+            Error::Toolchain(format!("failed to run {}: {}", nm, e))
+        })?;
+    if !output.status.success() {
+        return Err(Error::Toolchain(format!(
+            "{} {} failed: {}",
+            nm,
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let name = words.next()?;
+            let kind = words.next()?.chars().next()?;
+            Some((name.to_owned(), kind))
+        })
+        .collect())
+}
@@ -0,0 +1,222 @@
+use crate::arch::Arch;
+use crate::error::{Error, Result};
+use crate::scan::HeaderDeps;
+use crate::toolchain::{Family, Linker, Sanitizer};
+use crate::util;
+use crate::VERSION;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// What a set of build specs is being built for. Build statements generated
+/// for different targets do not share outputs. Its directories are valid
+/// UTF-8, so the paths of the files it names are too.
+pub struct Target {
+    /// The architecture being built for.
+    pub arch: Arch,
+    /// The directory all build outputs are placed under.
+    pub bld: PathBuf,
+    /// The source tree being built, i.e. `usr/src` of an illumos checkout.
+    pub src: PathBuf,
+    /// The proto area built files are installed into, e.g. `proto/root_amd64`.
+    pub proto: PathBuf,
+    /// Flags to compile kernel objects with after the built-in ones.
+    pub cflags: Vec<String>,
+    /// Flags to link kernel components with after the built-in ones.
+    pub ldflags: Vec<String>,
+    /// What to do with the symbols and debug information of kernel objects.
+    pub symbols: Symbols,
+    /// The kind of compiler kernel objects are compiled with.
+    pub family: Family,
+    /// The target triple when cross compiling.
+    pub triple: Option<String>,
+    /// The kind of link editor kernel components are linked with.
+    pub linker: Linker,
+    /// The emulation lld links for, when linking with lld.
+    pub emulation: Option<String>,
+    /// The system root to compile against when cross compiling.
+    pub sysroot: Option<PathBuf>,
+    /// The sanitizer to instrument kernel objects with, if any.
+    pub sanitize: Option<Sanitizer>,
+    /// Whether kernel objects collect coverage data.
+    pub coverage: bool,
+    /// How CTF data is converted and merged.
+    pub ctf: Ctf,
+    /// How objects are made the same from one build to the next, if they
+    /// should be.
+    pub reproducible: Option<Reproducible>,
+    /// Whether compiler warnings are errors.
+    pub werror: bool,
+    /// Whether modules are checked for references to symbols neither
+    /// genunix nor their dependencies define.
+    pub check_symbols: bool,
+    /// Which headers objects depend on.
+    pub header_deps: HeaderDeps,
+    /// Built-in flags the compiler does not support, with what to use
+    /// instead, if anything.
+    pub substitutes: BTreeMap<String, Option<String>>,
+}
+
+/// What a build must not depend on for two builds of the same sources to
+/// produce bit-identical objects.
+pub struct Reproducible {
+    /// The directory the build runs in, which is left out of the paths
+    /// compiled into objects.
+    pub root: PathBuf,
+    /// The time builds are dated, in seconds since the epoch, from
+    /// `SOURCE_DATE_EPOCH` or else 0.
+    pub epoch: u64,
+}
+
+impl Reproducible {
+    /// Settings for a build run from the current directory.
+    pub fn new() -> Result<Reproducible> {
+        let epoch = match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(x) => x.parse().map_err(|_| {
+                Error::Usage(format!(
+                    "SOURCE_DATE_EPOCH: expected seconds since the epoch, \
+                     not `{}`",
+                    x
+                ))
+            })?,
+            Err(_) => 0,
+        };
+        Ok(Reproducible {
+            root: std::env::current_dir()?,
+            epoch,
+        })
+    }
+}
+
+/// What to do with the symbols and debug information of kernel objects once
+/// their CTF data has been added.
+#[derive(
+    Clone, Copy, Debug, Default, Hash, PartialEq, Eq, Deserialize, Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Symbols {
+    /// Leave them in the objects.
+    Keep,
+    /// Strip them.
+    #[default]
+    Strip,
+    /// Strip them, keeping the debug information in a file in the debug
+    /// tree that the object links to, for post-mortem debugging.
+    Split,
+}
+
+/// How CTF data is converted and merged.
+pub struct Ctf {
+    /// Whether objects get CTF data at all. Without it, objects are not
+    /// stripped either.
+    pub enabled: bool,
+    /// The label CTF data is given.
+    pub label: String,
+    /// Flags to run ctfconvert with.
+    pub convert_flags: Vec<String>,
+    /// Flags to run ctfmerge with.
+    pub merge_flags: Vec<String>,
+}
+
+impl Default for Ctf {
+    fn default() -> Self {
+        Ctf {
+            enabled: true,
+            label: VERSION.into(),
+            convert_flags: vec!["-X".into()],
+            merge_flags: Vec::new(),
+        }
+    }
+}
+
+impl Target {
+    /// Create a target for `arch` building the tree at `src` into `bld` and
+    /// installing into the `root_<arch>` proto area under `proto`.
+    pub fn new(arch: Arch, src: &Path, bld: &Path, proto: &Path) -> Target {
+        Target {
+            arch,
+            bld: bld.to_owned(),
+            src: src.to_owned(),
+            proto: proto.join(format!("root_{}", arch)),
+            cflags: Vec::new(),
+            ldflags: Vec::new(),
+            symbols: Symbols::Strip,
+            family: Family::Gcc,
+            triple: None,
+            linker: Linker::Illumos,
+            emulation: None,
+            sysroot: None,
+            sanitize: None,
+            coverage: false,
+            ctf: Ctf::default(),
+            reproducible: None,
+            werror: false,
+            check_symbols: false,
+            header_deps: HeaderDeps::Workspace,
+            substitutes: BTreeMap::new(),
+        }
+    }
+
+    /// The file the debug information split out of `obj` is kept in: its
+    /// path under `.debug` in the output directory, with `.debug` added. The
+    /// directory is hidden so that no profile's directory can be it.
+    pub fn debug_file(&self, obj: &Path) -> Result<String> {
+        let path = obj.strip_prefix(&self.bld).unwrap_or(obj);
+        let mut file = self.bld.join(".debug").join(path).into_os_string();
+        file.push(".debug");
+        util::ninja_path(Path::new(&file))
+    }
+
+    /// The path of the generated genunix.
+    pub fn genunix(&self) -> Result<String> {
+        util::ninja_path(&self.bld.join("genunix"))
+    }
+
+    /// The file recording that the module `name` only references symbols
+    /// genunix or its dependencies define.
+    pub fn symbols_stamp(&self, name: &str) -> Result<String> {
+        let file = format!("{}.symbols", name);
+        util::ninja_path(&self.bld.join("modules").join(file))
+    }
+
+    /// The lcov report of the coverage data tests have collected, written
+    /// by builds with coverage.
+    pub fn coverage(&self) -> Result<String> {
+        util::ninja_path(&self.bld.join("coverage.info"))
+    }
+
+    /// The directory in the proto area that modules of `class`, e.g. `drv`,
+    /// are installed in, or genunix if there is no `class`.
+    pub fn kernel_dir(&self, class: Option<&str>) -> PathBuf {
+        let mut dir = self.proto.join("kernel");
+        dir.extend(class);
+        dir.extend(self.arch.kernel_subdir());
+        dir
+    }
+
+    /// The phony target that installs everything built for this target.
+    pub fn install(&self) -> Result<String> {
+        util::ninja_path(&self.bld.join("install"))
+    }
+
+    /// The directory outputs of the build spec at `path` are placed in. The
+    /// layout under `bld/usr/src` mirrors the source tree wherever it lives.
+    pub fn output_dir(&self, path: &Path) -> PathBuf {
+        let path = path.strip_prefix(&self.src).unwrap_or(path);
+        let mut dir = self.bld.join("usr/src").join(path);
+        dir.pop();
+        dir
+    }
+
+    /// The directories headers that are part of the build are in when they
+    /// are outside the workspace: the source tree, and the output directory
+    /// and proto area, which hold generated and installed headers.
+    pub fn header_roots(&self) -> Vec<PathBuf> {
+        vec![self.src.clone(), self.bld.clone(), self.proto.clone()]
+    }
+
+    /// An include flag for `dir`, relative to the source root.
+    pub fn include(&self, dir: &str) -> String {
+        format!("-I{}", self.src.join(dir).display())
+    }
+}
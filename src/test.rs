@@ -0,0 +1,170 @@
+use crate::arch::Arch;
+use crate::backend::Backend;
+use crate::error::Result;
+use crate::spec::{Spec, Test};
+use crate::target::Target;
+use crate::util;
+use serde_derive::Serialize;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Instant;
+
+/// A test of a build spec, to be run for one architecture.
+pub struct Case<'a> {
+    /// The name of the test in its build spec.
+    pub name: &'a str,
+    /// The build spec the test is declared by.
+    pub spec: &'a Path,
+    pub arch: Arch,
+    pub test: &'a Test,
+    /// The output directory of the architecture.
+    pub bld: PathBuf,
+    /// The output directory of the build spec.
+    pub out: PathBuf,
+}
+
+/// The tests of `specs` to run for `target`, those whose names contain one of
+/// `filters` if there are any.
+pub fn cases<'a>(
+    specs: &[&'a (PathBuf, Spec)],
+    target: &Target,
+    filters: &[String],
+) -> Vec<Case<'a>> {
+    let mut result = Vec::new();
+    for (path, spec) in specs {
+        for (name, test) in &spec.tests {
+            if !filters.is_empty()
+                && !filters.iter().any(|x| name.contains(x.as_str()))
+            {
+                continue;
+            }
+            result.push(Case {
+                name,
+                spec: path,
+                arch: target.arch,
+                test,
+                bld: target.bld.clone(),
+                out: target.output_dir(path),
+            });
+        }
+    }
+    result
+}
+
+impl Case<'_> {
+    /// The build outputs this test needs.
+    pub fn needs(&self) -> impl Iterator<Item = Result<String>> + '_ {
+        self.test
+            .needs
+            .iter()
+            .map(|x| util::ninja_path(&self.out.join(x)))
+    }
+
+    /// Run this test, capturing what it writes.
+    pub fn run(&self) -> Outcome {
+        let start = Instant::now();
+        let result = Command::new("sh")
+            .arg("-c")
+            .arg(&self.test.command)
+            .env("EOS_ARCH", self.arch.to_string())
+            .env("EOS_BLD", &self.bld)
+            .env("EOS_OUT", &self.out)
+            .env("EOS_SRC", self.spec.parent().unwrap_or(Path::new(".")))
+            .output();
+        let (passed, output) = match result {
+            Ok(x) => {
+                let mut output =
+                    String::from_utf8_lossy(&x.stdout).into_owned();
+                output += &String::from_utf8_lossy(&x.stderr);
+                if !x.status.success() {
+                    output += &format!("{}\n", x.status);
+                }
+                (x.status.success(), output)
+            }
+            Err(e) => (false, format!("failed to run sh: {}\n", e)),
+        };
+        Outcome {
+            name: self.name.to_owned(),
+            spec: self.spec.to_owned(),
+            arch: self.arch,
+            passed,
+            seconds: start.elapsed().as_secs_f64(),
+            output,
+        }
+    }
+}
+
+/// The result of running a test.
+#[derive(Serialize)]
+pub struct Outcome {
+    pub name: String,
+    pub spec: PathBuf,
+    pub arch: Arch,
+    pub passed: bool,
+    /// How long the test took.
+    pub seconds: f64,
+    /// What the test wrote to stdout and stderr.
+    pub output: String,
+}
+
+impl Outcome {
+    /// A line saying how the test went.
+    pub fn line(&self) -> String {
+        format!(
+            "test {} ({}, {}) ... {} in {:.2}s",
+            self.name,
+            self.spec.display(),
+            self.arch,
+            if self.passed { "ok" } else { "FAILED" },
+            self.seconds
+        )
+    }
+}
+
+/// The results of a test run, with a summary, as text or JSON.
+#[derive(Serialize)]
+pub struct Report {
+    pub passed: usize,
+    pub failed: usize,
+    pub tests: Vec<Outcome>,
+    /// Whether to write JSON instead of text.
+    #[serde(skip)]
+    pub json: bool,
+}
+
+impl Report {
+    /// The report of `tests`.
+    pub fn new(tests: Vec<Outcome>, json: bool) -> Report {
+        let passed = tests.iter().filter(|x| x.passed).count();
+        Report {
+            passed,
+            failed: tests.len() - passed,
+            tests,
+            json,
+        }
+    }
+}
+
+impl Backend for Report {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        if self.json {
+            serde_json::to_writer_pretty(&mut *w, self)?;
+            return Ok(writeln!(w)?);
+        }
+        // Lines for each test have already been written as they ran, so
+        // only the output of failures is left.
+        for test in self.tests.iter().filter(|x| !x.passed) {
+            writeln!(w, "\n---- {} ({}) ----", test.name, test.arch)?;
+            write!(w, "{}", test.output)?;
+        }
+        writeln!(
+            w,
+            "\ntest result: {}. {} passed, {} failed",
+            if self.failed == 0 { "ok" } else { "FAILED" },
+            self.passed,
+            self.failed
+        )?;
+        Ok(())
+    }
+}
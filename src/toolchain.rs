@@ -0,0 +1,364 @@
+use crate::error::{Error, Result};
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// The kinds of C compiler the kernel can be built with. Kernel flags are
+/// written for gcc and translated for the others.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Family {
+    #[default]
+    Gcc,
+    Clang,
+}
+
+impl Family {
+    /// The family of the compiler `cc`, going by its name, e.g. `clang-17`.
+    pub fn detect(cc: &str) -> Family {
+        let name = Path::new(cc).file_name().unwrap_or_default();
+        match name.to_string_lossy().contains("clang") {
+            true => Family::Clang,
+            false => Family::Gcc,
+        }
+    }
+
+    /// The gcc flag `flag` as this compiler spells it, or `None` if it has
+    /// no equivalent and is dropped. Most of the dropped flags tune gcc's
+    /// inliner and interprocedural passes so that functions stay where
+    /// DTrace and the debugger expect them, which clang does not need.
+    pub fn translate(self, flag: &str) -> Option<&str> {
+        match self {
+            Family::Gcc => Some(flag),
+            Family::Clang => match flag {
+                "-mindirect-branch=thunk-extern" => {
+                    Some("-mretpoline-external-thunk")
+                }
+                "-msave-args"
+                | "-mindirect-branch-register"
+                | "-fno-inline-small-functions"
+                | "-fno-inline-functions-called-once"
+                | "-fno-ipa-cp"
+                | "-fno-ipa-icf"
+                | "-fno-clone-functions"
+                | "-fno-reorder-functions"
+                | "-fno-reorder-blocks-and-partition"
+                | "-fno-aggressive-loop-optimizations"
+                | "-fno-shrink-wrap"
+                | "--param=max-inline-insns-single=450" => None,
+                _ => Some(flag),
+            },
+        }
+    }
+
+    /// The oldest version of this compiler the kernel is known to build
+    /// with.
+    pub fn minimum_version(self) -> (u32, u32) {
+        match self {
+            Family::Gcc => (10, 0),
+            Family::Clang => (14, 0),
+        }
+    }
+}
+
+/// The kinds of link editor the kernel can be linked with.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    Hash,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Linker {
+    /// The illumos link editor.
+    #[default]
+    Illumos,
+    /// LLVM's link editor.
+    Lld,
+}
+
+impl Linker {
+    /// The kind of link editor `ld` is, going by its name, e.g. `ld.lld`.
+    pub fn detect(ld: &str) -> Linker {
+        let name = Path::new(ld).file_name().unwrap_or_default();
+        match name.to_string_lossy().contains("lld") {
+            true => Linker::Lld,
+            false => Linker::Illumos,
+        }
+    }
+
+    /// The flags that link kernel modules, which are relocatable objects.
+    /// lld has no kmod type and links them as plain relocatable objects.
+    pub fn kmod_flags(self) -> Vec<String> {
+        match self {
+            Linker::Illumos => vec!["-ztype=kmod".into()],
+            Linker::Lld => vec!["-r".into()],
+        }
+    }
+
+    /// The flag that makes this link editor report its version.
+    fn version_flag(self) -> &'static str {
+        match self {
+            Linker::Illumos => "-V",
+            Linker::Lld => "--version",
+        }
+    }
+}
+
+/// The kernel sanitizers objects can be instrumented with.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Hash,
+    PartialEq,
+    Eq,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Sanitizer {
+    /// KASAN, which finds out of bounds and use after free accesses.
+    Address,
+    /// KMSAN, which finds uses of uninitialized memory. Only clang has it.
+    Memory,
+}
+
+impl Sanitizer {
+    /// The flags that instrument objects compiled with `family` for this
+    /// sanitizer, or `None` if the compiler does not have it.
+    pub fn cflags(self, family: Family) -> Option<Vec<String>> {
+        match (self, family) {
+            (Sanitizer::Address, _) => {
+                Some(vec!["-fsanitize=kernel-address".into()])
+            }
+            (Sanitizer::Memory, Family::Clang) => {
+                Some(vec!["-fsanitize=kernel-memory".into()])
+            }
+            (Sanitizer::Memory, Family::Gcc) => None,
+        }
+    }
+}
+
+/// The versions of programs a workspace expects to build with. Each is text
+/// the first line the program reports its version with must contain, e.g.
+/// `10.4.0`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Versions {
+    /// The version of the C compiler, as `cc --version` reports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cc: Option<String>,
+    /// The version of the link editor, as `ld -V`, or `ld --version` for
+    /// lld, reports it.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ld: Option<String>,
+}
+
+/// The programs used to build illumos.
+#[derive(Serialize)]
+pub struct Toolchain {
+    /// The C compiler.
+    pub cc: String,
+    /// The kind of compiler `cc` is.
+    pub family: Family,
+    /// The target triple to compile for when cross compiling, e.g.
+    /// `x86_64-unknown-illumos`.
+    pub triple: Option<String>,
+    /// The illumos system root to compile against when cross compiling.
+    pub sysroot: Option<PathBuf>,
+    /// The link editor.
+    pub ld: String,
+    /// The kind of link editor `ld` is.
+    pub linker: Linker,
+    /// The emulation lld links for, e.g. `elf_x86_64`, instead of the one
+    /// for the architecture.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub emulation: Option<String>,
+    /// Converts debug information in objects to CTF.
+    pub ctfconvert: String,
+    /// Merges the CTF data of objects being linked together.
+    pub ctfmerge: String,
+    /// Removes symbol and debug information from objects.
+    pub strip: String,
+    /// Copies debug information out of objects when it is split from them.
+    pub objcopy: String,
+    /// Lists the symbols of objects.
+    pub nm: String,
+    /// The compiler cache compile commands are run through, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub launcher: Option<String>,
+    /// Eos itself, which the build calls back into for steps such as scanning
+    /// generated headers.
+    pub eos: String,
+    /// The versions the programs are expected to be. Written last, as a
+    /// table.
+    pub versions: Versions,
+    /// How compiling is spread over other machines, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub distributed: Option<Distributed>,
+}
+
+impl Default for Toolchain {
+    fn default() -> Self {
+        Toolchain {
+            cc: "gcc-10".into(),
+            family: Family::Gcc,
+            triple: None,
+            sysroot: None,
+            ld: "ld".into(),
+            linker: Linker::Illumos,
+            emulation: None,
+            ctfconvert: "ctfconvert".into(),
+            ctfmerge: "ctfmerge".into(),
+            strip: "strip".into(),
+            objcopy: "objcopy".into(),
+            nm: "nm".into(),
+            launcher: None,
+            eos: std::env::current_exe()
+                .map(|x| x.to_string_lossy().into_owned())
+                .unwrap_or_else(|_| "eos".into()),
+            versions: Versions::default(),
+            distributed: None,
+        }
+    }
+}
+
+impl Toolchain {
+    /// The version the C compiler reports, the first line of `cc --version`,
+    /// if it can be run. Upgrading the compiler without renaming it changes
+    /// this, so it identifies the compiler along with its name.
+    pub fn cc_version(&self) -> Option<String> {
+        version(&self.cc, "--version")
+    }
+
+    /// The words compile commands start with to run the compiler: `$cc`,
+    /// behind the `$launcher` and `$wrapper` variables if there are any.
+    /// ccache runs the wrapper itself, from the environment.
+    pub fn compiler_words(&self) -> Vec<&'static str> {
+        let mut words = Vec::new();
+        if self.launcher.is_some() {
+            words.push("$launcher");
+        }
+        if self.distributed.is_some() && self.launcher_env().is_empty() {
+            words.push("$wrapper");
+        }
+        words.push("$cc");
+        words
+    }
+
+    /// The environment the compiler cache needs for objects built in one
+    /// checkout to be found from another, as `NAME=value` words. ccache
+    /// hashes paths under the directory the build runs in relative to it,
+    /// and the compiler by its content, so upgrading it in place misses the
+    /// cache; sccache does both already.
+    pub fn launcher_env(&self) -> Vec<String> {
+        let Some(launcher) = &self.launcher else {
+            return Vec::new();
+        };
+        let name = Path::new(launcher).file_name().unwrap_or_default();
+        if name != "ccache" {
+            return Vec::new();
+        }
+        let mut env = vec!["CCACHE_COMPILERCHECK=content".to_owned()];
+        if let Some(distributed) = &self.distributed {
+            env.push(format!("CCACHE_PREFIX={}", distributed.wrapper));
+        }
+        if let Ok(dir) = std::env::current_dir() {
+            env.push(format!("CCACHE_BASEDIR={}", dir.display()));
+        }
+        env
+    }
+
+    /// Check that the programs are the versions they are expected to be,
+    /// `cc_version` being the version of the compiler.
+    pub fn verify(&self, cc_version: &Option<String>) -> Result<()> {
+        let ld_version = match self.versions.ld {
+            Some(_) => version(&self.ld, self.linker.version_flag()),
+            None => None,
+        };
+        for (tool, program, expected, actual) in [
+            ("cc", &self.cc, &self.versions.cc, cc_version),
+            ("ld", &self.ld, &self.versions.ld, &ld_version),
+        ] {
+            let Some(expected) = expected else {
+                continue;
+            };
+            match actual {
+                Some(x) if x.contains(expected.as_str()) => (),
+                Some(x) => {
+                    return Err(Error::Toolchain(format!(
+                        "{} is `{}`, not version {} as toolchain.versions.{} \
+                         in eos.toml expects",
+                        program, x, expected, tool
+                    )))
+                }
+                None => {
+                    return Err(Error::Toolchain(format!(
+                    "cannot tell which version {} is, toolchain.versions.{} \
+                         in eos.toml expects {}",
+                    program, tool, expected
+                )))
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compiling on other machines with distcc or icecream, from eos.toml.
+/// Compile commands run through the wrapper, many more at a time than the
+/// machine could manage alone, while linking and CTF conversion, which only
+/// ever run here, keep to a pool the size of this machine.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Distributed {
+    /// The program compile commands run through, e.g. `distcc` or `icecc`.
+    pub wrapper: String,
+    /// How many compile commands to run at once.
+    pub jobs: usize,
+    /// How many other commands to run at once. Defaults to one per CPU.
+    pub local_jobs: Option<usize>,
+}
+
+impl Distributed {
+    /// How many commands that cannot be sent elsewhere to run at once.
+    pub fn local_jobs(&self) -> usize {
+        self.local_jobs.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |x| x.get())
+        })
+    }
+}
+
+/// The first line `program` reports its version with when run with `flag`,
+/// on stdout or, as the illumos link editor does, stderr, if it runs.
+fn version(program: &str, flag: &str) -> Option<String> {
+    let output = std::process::Command::new(program)
+        .arg(flag)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let text = match output.stdout.is_empty() {
+        true => String::from_utf8_lossy(&output.stderr),
+        false => String::from_utf8_lossy(&output.stdout),
+    };
+    Some(text.lines().next()?.trim().to_owned())
+}
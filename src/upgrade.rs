@@ -0,0 +1,112 @@
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
+use std::path::Path;
+use toml_edit::DocumentMut;
+
+/// How to rewrite a build spec of each version in the next one: the first
+/// upgrades version 1 to 2, and so on. Adding one raises `VERSION`.
+const UPGRADES: &[fn(&mut DocumentMut)] = &[];
+
+/// The version of the build spec format this eos reads, named by the
+/// `spec_version` of a build spec. Build specs without one are version 1.
+pub const VERSION: u32 = UPGRADES.len() as u32 + 1;
+
+/// The version of the build spec at `path`, which holds `data`, if it is one
+/// this eos reads. A build spec that is not valid TOML is left for the parser
+/// to report.
+pub fn check(path: &Path, data: &str) -> Result<()> {
+    let Ok(doc) = data.parse::<DocumentMut>() else {
+        return Ok(());
+    };
+    match version(path, &doc)? {
+        x if x < VERSION => Err(Error::Parse(Diagnostic::error(
+            path,
+            format!(
+                "spec_version {} is older than {}, the version this eos \
+                 reads; `eos upgrade-spec` upgrades it",
+                x, VERSION
+            ),
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// `text`, the contents of the build spec at `path`, upgraded to the current
+/// version, or nothing if it already is that version. Comments are kept.
+pub fn upgrade(path: &Path, text: &str) -> Result<Option<String>> {
+    let mut doc: DocumentMut = text.parse().map_err(|e| {
+        Error::Parse(Diagnostic::error(path, format!("{}", e).trim_end()))
+    })?;
+    let from = version(path, &doc)?;
+    if from == VERSION {
+        return Ok(None);
+    }
+    for upgrade in &UPGRADES[from as usize - 1..] {
+        upgrade(&mut doc);
+    }
+    doc["spec_version"] = toml_edit::value(i64::from(VERSION));
+    Ok(Some(doc.to_string()))
+}
+
+/// The `spec_version` of `doc`, the build spec at `path`, which must be one
+/// this eos knows.
+fn version(path: &Path, doc: &DocumentMut) -> Result<u32> {
+    let Some(item) = doc.get("spec_version") else {
+        return Ok(1);
+    };
+    match item.as_integer() {
+        Some(x) if x >= 1 && x <= i64::from(VERSION) => Ok(x as u32),
+        Some(x) if x > i64::from(VERSION) => {
+            Err(Error::Parse(Diagnostic::error(
+                path,
+                format!(
+                    "spec_version {} is newer than {}, the version this eos \
+                     reads; upgrade eos",
+                    x, VERSION
+                ),
+            )))
+        }
+        _ => Err(Error::Parse(Diagnostic::error(
+            path,
+            format!(
+                "spec_version should be a whole number from 1, not {}",
+                item.to_string().trim()
+            ),
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_spec(data: &str) -> Result<()> {
+        check(Path::new("build.toml"), data)
+    }
+
+    #[test]
+    fn check_accepts_current_and_unversioned_specs() {
+        assert!(check_spec("[module]\nname = \"a\"\n").is_ok());
+        let current = format!("spec_version = {}\n", VERSION);
+        assert!(check_spec(&current).is_ok());
+    }
+
+    #[test]
+    fn check_rejects_newer_and_malformed_versions() {
+        let newer = format!("spec_version = {}\n", VERSION + 1);
+        assert!(matches!(check_spec(&newer), Err(Error::Parse(_))));
+        assert!(matches!(
+            check_spec("spec_version = \"1\"\n"),
+            Err(Error::Parse(_))
+        ));
+        assert!(matches!(
+            check_spec("spec_version = 0\n"),
+            Err(Error::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn check_leaves_invalid_toml_to_the_parser() {
+        assert!(check_spec("[module\n").is_ok());
+    }
+}
@@ -1,139 +1,383 @@
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
+use crate::lint;
 use crate::ninja;
+use crate::scan::{HeaderDeps, Scan};
 use crate::spec;
+use crate::target::{Symbols, Target};
+use crate::upgrade;
 use rayon::prelude::*;
-use std::io::{Error, ErrorKind, Result};
-use std::os::unix::fs::DirEntryExt2;
-use std::path::{Path, PathBuf};
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
+use std::time::SystemTime;
+
+/// `path` as ninja build files name it. Ninja has no way to name a path that
+/// is not UTF-8, so those are an error.
+pub fn ninja_path(path: &Path) -> Result<String> {
+    match path.to_str() {
+        Some(x) => Ok(x.to_owned()),
+        None => Err(Diagnostic::error(
+            path,
+            "not valid UTF-8, which paths in a ninja build file must be",
+        )
+        .into()),
+    }
+}
 
 /// Given a list of source files, return a mapping of source file -> object
-/// file.
+/// file, with objects placed in the output directory of `target`. Every
+/// source must exist, and those that do not are all reported.
 pub fn object_source_map(
-    base_path: &PathBuf,
-    src: &Vec<String>,
+    base_path: &Path,
+    src: &[&String],
+    target: &Target,
 ) -> Result<Vec<(PathBuf, PathBuf)>> {
     let mut objs = Vec::new();
+    let mut missing = Vec::new();
     for src in src {
         let out = match src.strip_suffix(".c") {
             Some(prefix) => prefix.to_owned() + ".o",
             None => {
-                return Err(Error::new(
-                    ErrorKind::Other,
+                return Err(Diagnostic::error(
+                    base_path,
                     format!("{}: expected c source file", src),
-                ));
+                )
+                .into());
             }
         };
 
-        let mut in_path = base_path.clone();
+        let mut in_path = base_path.to_path_buf();
         in_path.pop();
         in_path.push(src);
+        if !in_path.exists() {
+            missing.push(
+                Diagnostic::error(
+                    base_path,
+                    format!("source {} does not exist", src),
+                )
+                .into(),
+            );
+            continue;
+        }
 
-        let mut out_path = PathBuf::new();
-        out_path.push("bld");
-        out_path.push(base_path);
-        out_path.pop();
+        let mut out_path = target.output_dir(base_path);
         out_path.push(&out);
         objs.push((in_path, out_path));
     }
+    Error::collect(missing)?;
     Ok(objs)
 }
 
-/// Create a vector of build statements from a source-object map.
+/// Create a vector of build statements from a source-object map. Each object
+/// is produced by three separate edges: compile, ctfconvert and strip, so that
+/// a failure in a later stage does not force a recompile and ninja can
+/// schedule the stages independently. Depending on how `target` treats
+/// symbols the last edge may only copy the object, or split its debug
+/// information out into the debug tree. Without CTF data there is only the
+/// compile edge, which produces the object itself. Header dependencies of the
+/// compile edges are only determined if there is a `scan`, with the flags
+/// `cflags` gives for each source.
 pub fn object_build_statements(
-    cflags: Vec<&'static str>,
+    cflags: &(dyn Fn(&Path) -> Vec<String> + Sync),
     obj_src_map: &[(PathBuf, PathBuf)],
-) -> Vec<ninja::BuildStatement> {
+    scan: Option<&Scan>,
+    target: &Target,
+) -> Result<Vec<ninja::BuildStatement>> {
+    let ctf = target.ctf.enabled;
+    let last = match target.symbols {
+        Symbols::Strip => ninja::Rules::Strip,
+        Symbols::Keep => ninja::Rules::Copy,
+        Symbols::Split => ninja::Rules::Split,
+    };
     // we launch a gcc -H search per object file which is not cheap, so do this
     // over a parallel iterator. On my dev machine with 64 cores this takes
     // the time needed to construct build.ninja from ~30 seconds to ~4 seconds.
-    obj_src_map
+    // Every source is scanned even if one fails, so that all of the failures
+    // are reported at once. Collecting keeps the order of `obj_src_map`, not
+    // that the scans finish in, and headers come sorted, so the statements
+    // are the same from one run to the next however the threads are
+    // scheduled.
+    let results: Vec<Result<Vec<ninja::BuildStatement>>> = obj_src_map
         .par_iter()
-        .map(|(src, obj)| ninja::BuildStatement {
-            input: src.to_str().unwrap().to_owned(),
-            output: obj.to_str().unwrap().to_owned(),
-            rule: ninja::Rules::ModCompile.to_string(),
-            implicit_deps: header_deps(&cflags, src.as_path())
-                .unwrap()
-                .iter()
-                .map(|x| x.to_str().unwrap().to_owned())
-                .collect::<Vec<String>>(),
-            ..Default::default()
+        .map(|(src, obj)| {
+            let compiled = match ctf {
+                true => stage_path(obj, "raw")?,
+                false => ninja_path(obj)?,
+            };
+            let converted = stage_path(obj, "ctf")?;
+            let debug = match target.symbols {
+                Symbols::Split => Some(target.debug_file(obj)?),
+                _ => None,
+            };
+            let compile = ninja::BuildStatement {
+                inputs: vec![ninja_path(src)?],
+                output: compiled.clone(),
+                rule: ninja::Rules::ModCompile.to_string(),
+                implicit_deps: match scan {
+                    Some(scan) => scan
+                        .headers(&cflags(src), src.as_path())?
+                        .iter()
+                        .map(|x| ninja_path(x))
+                        .collect::<Result<Vec<String>>>()?,
+                    None => Vec::new(),
+                },
+                ..Default::default()
+            };
+            if !ctf {
+                return Ok(vec![compile]);
+            }
+            Ok(vec![
+                compile,
+                ninja::BuildStatement {
+                    inputs: vec![compiled],
+                    output: converted.clone(),
+                    rule: ninja::Rules::CtfConvert.to_string(),
+                    ..Default::default()
+                },
+                ninja::BuildStatement {
+                    inputs: vec![converted],
+                    output: ninja_path(obj)?,
+                    implicit_outputs: debug.clone().into_iter().collect(),
+                    rule: last.to_string(),
+                    variables: debug
+                        .into_iter()
+                        .map(|x| ninja::Variable::new("debug", &x))
+                        .collect(),
+                    ..Default::default()
+                },
+            ])
         })
-        .collect()
+        .collect();
+    let mut stmts = Vec::new();
+    let mut errors = Vec::new();
+    for result in results {
+        match result {
+            Ok(x) => stmts.extend(x),
+            Err(e) => errors.push(e),
+        }
+    }
+    Error::collect(errors)?;
+    Ok(stmts)
 }
 
-/// Find all the build files at the given path. This will search the path
-/// recursively for any file named `build.toml`.
-pub fn find_build_files(path: &Path) -> Result<Vec<PathBuf>> {
-    let mut result = Vec::new();
-    find_build_files_rec(path, &mut result)?;
-    Ok(result)
+/// The path of an intermediate stage of building the object `obj`, e.g.
+/// `foo.o` -> `foo.raw.o`.
+fn stage_path(obj: &Path, stage: &str) -> Result<String> {
+    ninja_path(&obj.with_extension(format!("{}.o", stage)))
 }
 
-/// Search the file system recursively for all build files.
-fn find_build_files_rec(path: &Path, result: &mut Vec<PathBuf>) -> Result<()> {
-    for entry in std::fs::read_dir(path)? {
-        let e = entry?;
-        let ft = e.file_type()?;
-        if ft.is_symlink() {
-            continue;
-        } else if ft.is_dir() {
-            find_build_files_rec(&e.path(), result)?;
-        } else if e.file_name_ref() == "build.toml" {
-            result.push(e.path());
+/// The one of `candidates` closest to `name`, if any is close enough that
+/// `name` is likely a misspelling of it: similar by the measure clap uses for
+/// arguments, or a single typo away, which that measure misses in short names.
+pub fn did_you_mean<'a>(
+    name: &str,
+    candidates: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    candidates
+        .into_iter()
+        .map(|x| (strsim::jaro(name, x), x))
+        .filter(|(similarity, x)| {
+            *similarity > 0.7 || strsim::damerau_levenshtein(name, x) == 1
+        })
+        .max_by(|x, y| x.0.total_cmp(&y.0))
+        .map(|(_, x)| x)
+}
+
+/// Write `data` to the file at `path`. The data is written to a temporary
+/// file that is then renamed into place, so an interrupted write never leaves
+/// a truncated file behind. If the file already holds `data` it is not
+/// written at all, preserving its modification time.
+pub fn write_if_changed(path: &Path, data: &[u8]) -> Result<()> {
+    if let Ok(existing) = std::fs::read(path) {
+        if existing == data {
+            return Ok(());
         }
     }
 
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".tmp.{}", std::process::id()));
+    let tmp = path.with_file_name(name);
+    std::fs::write(&tmp, data)?;
+    if let Err(e) = std::fs::rename(&tmp, path) {
+        let _ = std::fs::remove_file(&tmp);
+        return Err(e.into());
+    }
     Ok(())
 }
 
+/// The modification time of `path`, if it exists.
+pub fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+/// Whether `output` is missing or older than any of `inputs`.
+pub fn is_stale(output: &Path, inputs: &[PathBuf]) -> Result<bool> {
+    let modified = match std::fs::metadata(output) {
+        Ok(meta) => meta.modified()?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(true),
+        Err(e) => return Err(e.into()),
+    };
+    for input in inputs {
+        if std::fs::metadata(input)?.modified()? > modified {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
 /// Read the given file into a build spec.
 pub fn read_spec(path: &Path) -> Result<spec::Spec> {
     let data = std::fs::read_to_string(path)?;
+    upgrade::check(path, &data)?;
+    // Unknown fields are looked for first, as the parser stops at the first
+    // one it finds, and cannot say what it might be a misspelling of.
+    let unknown = lint::unknown_fields(path, &data);
+    Error::collect(unknown.into_iter().map(Error::Parse).collect())?;
     match toml::from_str(&data) {
         Ok(spec) => Ok(spec),
-        Err(e) => Err(Error::new(
-            ErrorKind::Other,
-            format!("{}: {}", path.display(), e),
-        )),
+        Err(e) => Err(Error::Parse(Diagnostic::toml(path, &data, &e))),
     }
 }
 
-/// given a c file, use gcc to find all the headers it depends on
+/// Given a c file, use the compiler `cc` to find all the headers it depends on,
+/// or only those in the workspace or under `roots`, as `headers` says. Headers
+/// are named relative to the workspace if they are in it, without `.` or `..`,
+/// once each, in order.
 pub fn header_deps(
-    compiler_flags: &Vec<&'static str>,
+    cc: &str,
+    compiler_flags: &[String],
     path: &Path,
+    headers: HeaderDeps,
+    roots: &[PathBuf],
 ) -> Result<Vec<PathBuf>> {
-    let mut args = vec!["-H", "-fsyntax-only"];
-    args.extend(compiler_flags);
-    args.push(path.to_str().unwrap());
-
-    let result = Command::new("gcc-10")
-        .args(args)
+    tracing::trace!("scanning {} for headers", path.display());
+    // What the compiler says is only shown if scanning fails, in a message
+    // of eos's own, so it is kept free of the colors the kernel flags ask
+    // for.
+    let result = Command::new(cc)
+        .args(["-H", "-fsyntax-only"])
+        .args(compiler_flags)
+        .arg("-fdiagnostics-color=never")
+        .arg(path)
         .output()
-        .expect("failed to execute gcc-10");
+        .map_err(|e| {
+            Error::Toolchain(format!("failed to run {}: {}", cc, e))
+        })?;
 
+    // for whatever reason gcc puts the output of this command on success ....
+    // on stderr - brilliant.
+    let out = String::from_utf8_lossy(&result.stderr);
+    // A source the compiler cannot make sense of is a mistake in the tree,
+    // reported with what the compiler said about it, less the headers.
     if !result.status.success() {
-        return Err(Error::new(
-            ErrorKind::Other,
+        let output: Vec<&str> =
+            out.lines().filter(|x| !x.starts_with('.')).collect();
+        return Err(Diagnostic::error(
+            path,
             format!(
-                "using gcc to determine header deps failed: {}",
-                std::str::from_utf8(&result.stderr).unwrap(),
+                "scanning for headers with {} failed:\n{}",
+                cc,
+                output.join("\n").trim_end()
             ),
-        ));
+        )
+        .into());
     }
 
-    // for whatever reason gcc puts the output of this command on success ....
-    // on stderr - brilliant.
-    let out = std::str::from_utf8(&result.stderr).unwrap();
-
-    let mut deps = Vec::new();
+    // eos works from the root of the workspace, so headers in it are named
+    // relative to it, or by an absolute path under it. The compiler lists a
+    // header each time it is included, as the include path found it.
+    let workspace = std::env::current_dir()?;
+    let mut deps = BTreeSet::new();
     for line in out.lines() {
         if !line.starts_with('.') {
             continue;
         }
-        deps.push(Path::new(line.trim_start_matches('.')).to_owned())
+        let dep =
+            normalize(Path::new(line.trim_start_matches('.').trim_start()));
+        let dep = match dep.strip_prefix(&workspace) {
+            Ok(x) => x.to_owned(),
+            Err(_) => dep,
+        };
+        let local = (!dep.is_absolute() && !dep.starts_with(".."))
+            || roots.iter().any(|x| dep.starts_with(normalize(x)));
+        if local || headers == HeaderDeps::All {
+            deps.insert(dep);
+        }
+    }
+
+    Ok(deps.into_iter().collect())
+}
+
+/// `path` without `.` components, or `..` components that follow a directory
+/// they can be resolved against. Symbolic links are not followed, so this is
+/// only the same file if none of the directories removed is a link.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => match result.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    result.pop();
+                }
+                Some(Component::RootDir) => {}
+                _ => result.push(component),
+            },
+            _ => result.push(component),
+        }
     }
+    result
+}
 
-    Ok(deps)
+/// `path`, which is relative to `dir`, joined to `dir` and normalized, so that
+/// a path like `..` leaves `dir` rather than naming a directory under it.
+pub fn rebase(dir: &Path, path: &Path) -> PathBuf {
+    let result = normalize(&dir.join(path));
+    if result.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_removes_dot_and_resolvable_dot_dot() {
+        assert_eq!(
+            normalize(Path::new("./usr/src/../src/./uts")),
+            Path::new("usr/src/uts")
+        );
+        assert_eq!(normalize(Path::new("/a/b/../../..")), Path::new("/"));
+    }
+
+    #[test]
+    fn normalize_keeps_leading_dot_dot() {
+        assert_eq!(normalize(Path::new("../a/../b")), Path::new("../b"));
+        assert_eq!(normalize(Path::new("a/../..")), Path::new(".."));
+    }
+
+    #[test]
+    fn rebase_resolves_against_dir() {
+        let dir = Path::new("usr/src/uts");
+        assert_eq!(rebase(dir, Path::new("..")), Path::new("usr/src"));
+        assert_eq!(rebase(dir, Path::new("/a")), Path::new("/a"));
+        assert_eq!(rebase(Path::new(""), Path::new(".")), Path::new("."));
+    }
+
+    #[test]
+    fn did_you_mean_finds_close_names() {
+        let names = ["sources", "dependencies", "src"];
+        assert_eq!(did_you_mean("dependancies", names), Some("dependencies"));
+        assert_eq!(did_you_mean("srx", names), Some("src"));
+    }
+
+    #[test]
+    fn did_you_mean_ignores_distant_names() {
+        assert_eq!(did_you_mean("conf", ["dependencies", "src"]), None);
+        assert_eq!(did_you_mean("src", []), None);
+    }
 }
@@ -0,0 +1,241 @@
+use crate::diagnostic::Diagnostic;
+use crate::error::{Error, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use std::collections::BTreeSet;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// The name of the file of patterns for the paths under the source tree the
+/// search for build specs skips, at the root of the tree.
+pub const IGNORE_FILE: &str = ".eosignore";
+
+/// How the source tree is searched for build specs.
+#[derive(Debug)]
+pub struct Walk {
+    /// Whether a directory that cannot be read fails the search.
+    pub strict: bool,
+    /// Whether symbolic links are followed, instead of skipped.
+    pub follow_symlinks: bool,
+    /// The source tree, which ignore patterns with a `/` are relative to.
+    pub root: PathBuf,
+    /// Directories skipped wherever they are, e.g. the output directory.
+    pub skip: Vec<PathBuf>,
+    /// How many directories down the search goes, if there is a limit.
+    pub max_depth: Option<usize>,
+    /// The patterns of the ignore file.
+    pub ignore: Vec<Pattern>,
+}
+
+/// A line of an ignore file, e.g. `uts/*/vendor/`.
+#[derive(Debug)]
+pub struct Pattern {
+    /// The pattern, without a leading or trailing `/`. `*` matches any part
+    /// of a name, `?` any one character of one and `**` any number of
+    /// directories.
+    glob: GlobMatcher,
+    /// Whether the pattern is matched against the path relative to the
+    /// source tree, as it has a `/` before its end, or else against names.
+    anchored: bool,
+    /// Whether it only matches directories, as it ends with a `/`.
+    dir_only: bool,
+}
+
+impl Walk {
+    /// Read the patterns of the ignore file at `path`, if there is one. Blank
+    /// lines and those starting with `#` are left out.
+    pub fn read_ignore(&mut self, path: &Path) -> Result<()> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(unreadable_error(path, e)),
+        };
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let dir_only = line.ends_with('/');
+            let line = line.trim_end_matches('/');
+            let glob = GlobBuilder::new(line.trim_start_matches('/'))
+                .literal_separator(true)
+                .build()
+                .map_err(|e| Diagnostic::error(path, e.to_string()))?;
+            self.ignore.push(Pattern {
+                glob: glob.compile_matcher(),
+                anchored: line.contains('/'),
+                dir_only,
+            });
+        }
+        Ok(())
+    }
+
+    /// Whether the search skips `path`, a directory if `dir`.
+    fn skips(&self, path: &Path, dir: bool) -> bool {
+        let name = Path::new(path.file_name().unwrap_or_default());
+        if dir && name == ".git" {
+            return true;
+        }
+        if self.skip.is_empty() && self.ignore.is_empty() {
+            return false;
+        }
+        let Ok(path) = std::path::absolute(path) else {
+            return false;
+        };
+        let path = crate::util::normalize(&path);
+        if dir && self.skip.contains(&path) {
+            return true;
+        }
+        let relative = path.strip_prefix(&self.root).ok();
+        self.ignore.iter().any(|x| {
+            if x.dir_only && !dir {
+                return false;
+            }
+            match (x.anchored, relative) {
+                (true, Some(relative)) => x.glob.is_match(relative),
+                (true, None) => false,
+                (false, _) => x.glob.is_match(name),
+            }
+        })
+    }
+}
+
+/// Find all the build files at the given path. This will search the path
+/// recursively for any file named `build.toml`. The result is sorted so it does
+/// not depend on directory iteration order. Entries under the path that cannot
+/// be read are skipped with a warning, unless the walk is strict.
+pub fn find_build_files(path: &Path, walk: &Walk) -> Result<Vec<PathBuf>> {
+    let mut search = Search {
+        walk,
+        skipped: 0,
+        visited: BTreeSet::new(),
+        result: Vec::new(),
+    };
+    let entries =
+        std::fs::read_dir(path).map_err(|e| unreadable_error(path, e))?;
+    if walk.follow_symlinks {
+        search.first_visit(path)?;
+    }
+    search.dir(path, entries, 0)?;
+    if search.skipped > 0 {
+        tracing::warn!(
+            "skipped {} unreadable entr{} under {}; --strict-walk fails instead",
+            search.skipped,
+            if search.skipped == 1 { "y" } else { "ies" },
+            path.display()
+        );
+    }
+    search.result.sort();
+    Ok(search.result)
+}
+
+/// A search of the file system for build files.
+struct Search<'a> {
+    walk: &'a Walk,
+    /// How many entries could not be read.
+    skipped: usize,
+    /// The device and inode numbers of the directories searched, when
+    /// following symbolic links, which may lead back to one of them.
+    visited: BTreeSet<(u64, u64)>,
+    /// The build files found.
+    result: Vec<PathBuf>,
+}
+
+impl Search<'_> {
+    /// Search the `entries` of the directory at `path`, `depth` directories
+    /// down, and the directories under it, for build files.
+    fn dir(
+        &mut self,
+        path: &Path,
+        entries: std::fs::ReadDir,
+        depth: usize,
+    ) -> Result<()> {
+        for entry in entries {
+            let e = match entry {
+                Ok(x) => x,
+                Err(e) => {
+                    self.unreadable(path, e)?;
+                    continue;
+                }
+            };
+            let path = e.path();
+            let mut ft = match e.file_type() {
+                Ok(x) => x,
+                Err(e) => {
+                    self.unreadable(&path, e)?;
+                    continue;
+                }
+            };
+            if ft.is_symlink() {
+                if !self.walk.follow_symlinks {
+                    continue;
+                }
+                match std::fs::metadata(&path) {
+                    Ok(x) => ft = x.file_type(),
+                    Err(e) => {
+                        tracing::debug!(
+                            "skipping {}, which does not lead anywhere: {}",
+                            path.display(),
+                            e
+                        );
+                        continue;
+                    }
+                }
+            }
+            if ft.is_dir() {
+                if self.walk.skips(&path, true) {
+                    continue;
+                }
+                if self.walk.max_depth.is_some_and(|x| depth >= x) {
+                    tracing::debug!(
+                        "skipping {}, which is deeper than walk.max_depth",
+                        path.display()
+                    );
+                    continue;
+                }
+                if self.walk.follow_symlinks && !self.first_visit(&path)? {
+                    tracing::debug!(
+                        "skipping {}, which has already been searched",
+                        path.display()
+                    );
+                    continue;
+                }
+                match std::fs::read_dir(&path) {
+                    Ok(x) => self.dir(&path, x, depth + 1)?,
+                    Err(e) => self.unreadable(&path, e)?,
+                }
+            } else if e.file_name() == "build.toml"
+                && !self.walk.skips(&path, false)
+            {
+                self.result.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the directory at `path` has not been searched before, under
+    /// another name.
+    fn first_visit(&mut self, path: &Path) -> Result<bool> {
+        let metadata =
+            std::fs::metadata(path).map_err(|e| unreadable_error(path, e))?;
+        Ok(self.visited.insert((metadata.dev(), metadata.ino())))
+    }
+
+    /// Skip `path`, which cannot be read because of `e`, unless the walk is
+    /// strict.
+    fn unreadable(&mut self, path: &Path, e: std::io::Error) -> Result<()> {
+        if self.walk.strict {
+            return Err(unreadable_error(path, e));
+        }
+        tracing::warn!("cannot read {}: {}", path.display(), e);
+        self.skipped += 1;
+        Ok(())
+    }
+}
+
+/// The error `e` reading `path` fails a search for build files with.
+fn unreadable_error(path: &Path, e: std::io::Error) -> Error {
+    Error::Io(std::io::Error::new(
+        e.kind(),
+        format!("{}: {}", path.display(), e),
+    ))
+}
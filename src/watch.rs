@@ -0,0 +1,155 @@
+use crate::spec::Spec;
+use crate::util;
+use notify::Watcher as _;
+use notify::{
+    Event, EventKind, RecommendedWatcher, RecursiveMode, WatcherKind,
+};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, warn};
+
+/// Waits for files in the source tree, or the configuration, to change. The
+/// system says when they
+/// do where notify has a way of asking it, e.g. inotify; elsewhere the tree
+/// is checked every so often instead.
+pub struct Watcher {
+    /// The watcher and the events it sends, unless checking every so often.
+    events: Option<Events>,
+    /// The root of the tree.
+    root: PathBuf,
+    /// The configuration file, which is outside the tree.
+    config: PathBuf,
+    /// Directories changes in are of no interest, e.g. the output directory.
+    skip: Vec<PathBuf>,
+    /// How long to wait for more changes after one, or between checks.
+    interval: Duration,
+}
+
+impl Watcher {
+    /// Watch the tree at `root`, apart from the directories in `skip`, and
+    /// the file `config`. Both paths are absolute.
+    pub fn new(
+        root: &Path,
+        skip: Vec<PathBuf>,
+        config: &Path,
+        interval: Duration,
+    ) -> Watcher {
+        let events = match events(root, config) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!(
+                    "cannot watch {}, checking it for changes every {}ms \
+                     instead: {}",
+                    root.display(),
+                    interval.as_millis(),
+                    e
+                );
+                None
+            }
+        };
+        Watcher {
+            events,
+            root: root.to_path_buf(),
+            config: config.to_path_buf(),
+            skip,
+            interval,
+        }
+    }
+
+    /// Wait until something in the tree may have changed. Once it has, the
+    /// changes that come in within the interval are waited for as well, so
+    /// that e.g. a checkout changing many files at once is seen as one.
+    pub fn wait(&self) {
+        let Some((_, events)) = &self.events else {
+            std::thread::sleep(self.interval);
+            return;
+        };
+        loop {
+            match events.recv() {
+                Ok(Ok(x))
+                    if matches!(x.kind, EventKind::Access(_))
+                        || x.paths.iter().all(|x| self.skips(x)) => {}
+                Ok(_) => break,
+                Err(_) => {
+                    std::thread::sleep(self.interval);
+                    return;
+                }
+            }
+        }
+        std::thread::sleep(self.interval);
+        while events.try_recv().is_ok() {}
+    }
+
+    /// Whether changes to `path` are of no interest.
+    fn skips(&self, path: &Path) -> bool {
+        (!path.starts_with(&self.root) && path != self.config)
+            || self.skip.iter().any(|x| path.starts_with(x))
+            || path.components().any(|x| x.as_os_str() == ".git")
+    }
+}
+
+/// A watcher, which has to be kept for as long as it should watch, and the
+/// events it sends.
+type Events = (RecommendedWatcher, Receiver<notify::Result<Event>>);
+
+/// Ask the system to say when files in the tree at `root`, or the file
+/// `config`, change, if notify has a way of asking it rather than checking
+/// every file itself. The directory `config` is in is watched, so that it is
+/// seen being created.
+fn events(root: &Path, config: &Path) -> notify::Result<Option<Events>> {
+    if RecommendedWatcher::kind() == WatcherKind::PollWatcher {
+        return Ok(None);
+    }
+    let (sender, receiver) = channel();
+    let mut watcher = notify::recommended_watcher(sender)?;
+    watcher.watch(root, RecursiveMode::Recursive)?;
+    if let Some(dir) = config.parent().filter(|x| !x.starts_with(root)) {
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    }
+    debug!("watching {} for changes", root.display());
+    Ok(Some((watcher, receiver)))
+}
+
+/// The modification times of a set of files. Whether a change to the tree
+/// matters is told by comparing snapshots, which is cheap enough for the
+/// number of files involved.
+#[derive(PartialEq, Default)]
+pub struct Snapshot(BTreeMap<PathBuf, Option<SystemTime>>);
+
+impl Snapshot {
+    /// Take a snapshot of `paths`, plus the sources of `specs` if `sources`
+    /// is set. Files that do not exist are recorded as such.
+    pub fn new(
+        paths: &[PathBuf],
+        specs: &[(PathBuf, Spec)],
+        sources: bool,
+    ) -> Snapshot {
+        let mut files: Vec<PathBuf> = paths.to_vec();
+        if sources {
+            for (path, spec) in specs {
+                let src = spec.sources(None);
+                let inputs = spec
+                    .generate
+                    .iter()
+                    .flat_map(|x| &x.inputs)
+                    .chain(spec.build.iter().flat_map(|x| &x.inputs));
+                files.extend(
+                    src.into_iter()
+                        .chain(inputs)
+                        .map(|x| path.with_file_name(x)),
+                );
+            }
+        }
+        Snapshot(
+            files
+                .into_iter()
+                .map(|x| {
+                    let modified = util::modified(&x);
+                    (x, modified)
+                })
+                .collect(),
+        )
+    }
+}
@@ -0,0 +1,84 @@
+use crate::arch::Arch;
+use crate::backend::Backend;
+use crate::error::{Error, Result};
+use crate::ninja::{self, BuildStatement};
+use std::io::Write;
+
+/// Where a file comes from in the generated build and what needs it: the
+/// build statement producing it, with the build spec, rule and inputs of
+/// that statement, and the statements using it.
+pub struct Why<'a> {
+    /// The build generated for each architecture.
+    pub ninja_specs: &'a [(Arch, ninja::Spec)],
+    /// The file to explain, an output or a source.
+    pub path: String,
+}
+
+impl Why<'_> {
+    /// The file to explain, as it is named in build statements.
+    fn path(&self) -> &str {
+        self.path.strip_prefix("./").unwrap_or(&self.path)
+    }
+}
+
+impl Backend for Why<'_> {
+    fn emit(&self, w: &mut dyn Write) -> Result<()> {
+        let path = self.path();
+        let mut found = false;
+        for (arch, spec) in self.ninja_specs {
+            let producer = spec
+                .statements
+                .iter()
+                .find(|x| x.outputs().any(|x| x == path));
+            let users: Vec<&BuildStatement> = spec
+                .statements
+                .iter()
+                .filter(|x| {
+                    x.inputs
+                        .iter()
+                        .chain(&x.implicit_deps)
+                        .chain(&x.order_only_deps)
+                        .any(|x| x == path)
+                })
+                .collect();
+            if producer.is_none() && users.is_empty() {
+                continue;
+            }
+            found = true;
+
+            writeln!(w, "{} [{}]", path, arch)?;
+            match producer {
+                Some(stmt) => {
+                    writeln!(w, "  built from {}", stmt.origin_display())?;
+                    writeln!(w, "  rule       {}", stmt.rule)?;
+                    for input in &stmt.inputs {
+                        writeln!(w, "  input      {}", input)?;
+                    }
+                    for input in &stmt.implicit_deps {
+                        writeln!(w, "  implicit   {}", input)?;
+                    }
+                    for input in &stmt.order_only_deps {
+                        writeln!(w, "  order-only {}", input)?;
+                    }
+                }
+                None => writeln!(w, "  source, not built")?,
+            }
+            for stmt in users {
+                writeln!(
+                    w,
+                    "  needed by  {} ({}, {})",
+                    stmt.output,
+                    stmt.rule,
+                    stmt.origin_display()
+                )?;
+            }
+        }
+        if !found {
+            return Err(Error::Usage(format!(
+                "{}: not built or used by any build statement",
+                path
+            )));
+        }
+        Ok(())
+    }
+}